@@ -0,0 +1,358 @@
+//! # analysis, the module that statically analyzes how a program uses the stack
+//!
+//! Every instruction has a fixed stack effect, so the depth of the stack at every
+//! instruction in a procedure is known statically, relative to the depth of the
+//! stack when the procedure was called. The only exceptions are loops whose bodies
+//! do not leave the stack as deep as they found it, and recursive procedures.
+//!
+//! Using this, the assembler can find the largest number of cells a program will
+//! ever have on its stack, and use that to size the stack with `stack_size auto`.
+
+use crate::{
+    asm::{Instruct, StackEffect},
+    Warning,
+};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+/// The Usage struct describes how a procedure uses the stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// The net stack effect of the procedure
+    pub effect: StackEffect,
+
+    /// The largest number of cells the procedure ever has on the stack,
+    /// counted from the depth of the stack when the procedure was called
+    pub max_depth: usize,
+}
+
+/// The Unknown enum describes why the stack usage of a procedure cannot be
+/// determined statically. Each variant holds the name of the procedure at fault.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Unknown {
+    /// The procedure calls itself, so its stack depth depends on the recursion depth
+    Recursive(String),
+
+    /// A loop in the procedure changes the depth of the stack on every iteration
+    UnbalancedLoop(String),
+
+    /// The branches of a conditional in the procedure leave the stack at different depths
+    UnbalancedBranch(String),
+
+    /// The procedure calls a procedure through its identifier with `call_ind`
+    IndirectCall(String),
+}
+
+impl fmt::Display for Unknown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Recursive(name) => write!(f, "procedure '{}' is recursive", name),
+            Self::UnbalancedLoop(name) => {
+                write!(f, "a loop in procedure '{}' is not stack balanced", name)
+            }
+            Self::UnbalancedBranch(name) => {
+                write!(
+                    f,
+                    "a conditional in procedure '{}' is not stack balanced",
+                    name
+                )
+            }
+            Self::IndirectCall(name) => {
+                write!(f, "procedure '{}' calls a procedure with call_ind", name)
+            }
+        }
+    }
+}
+
+/// The loops and conditionals that are open while walking over a procedure.
+/// Each block records whether the code at its start can be reached, because
+/// the code after a `break` or `continue` cannot be reached until the block ends.
+enum Block {
+    /// A loop, and the depth of the stack after it popped its test value
+    Loop(isize, bool),
+
+    /// The condition of a `while` loop, and the depth of the stack at its start
+    While(isize, bool),
+
+    /// The body of a `while` loop, and the depth of the stack at the loop's start
+    Do(isize, bool),
+
+    /// A conditional, and the depth of the stack after it popped its test value
+    If(isize, bool),
+
+    /// The else branch of a conditional, and the depth of the stack at the
+    /// end of the then branch, if the end of the then branch can be reached
+    Else(Option<isize>, bool),
+}
+
+/// The Analysis struct computes the stack usage of procedures, where each
+/// procedure's body is a list of instructions that uses `Instruct::Call` for
+/// every call. The usage of each procedure is only computed once.
+///
+/// When a procedure is declared with a signature, such as `proc less (2 -> 1)`,
+/// its callers use the signature instead of the procedure's body. This allows
+/// recursive procedures and procedures with unbalanced loops to be called
+/// without losing track of the stack.
+pub(crate) struct Analysis<'a> {
+    /// The bodies of the procedures, by name
+    bodies: &'a BTreeMap<String, Vec<Instruct>>,
+
+    /// The declared signatures of the procedures, by name
+    signatures: &'a BTreeMap<String, StackEffect>,
+
+    /// The stack effects of the procedure bodies analyzed so far
+    effects: BTreeMap<&'a str, Result<StackEffect, Unknown>>,
+
+    /// The usage of the procedures analyzed so far
+    usages: BTreeMap<&'a str, Result<Usage, Unknown>>,
+
+    /// The procedures whose stack effects are currently being analyzed
+    active_effects: BTreeSet<&'a str>,
+
+    /// The procedures whose usage is currently being analyzed
+    active_usages: BTreeSet<&'a str>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Create an analysis of a set of procedure bodies
+    pub fn new(
+        bodies: &'a BTreeMap<String, Vec<Instruct>>,
+        signatures: &'a BTreeMap<String, StackEffect>,
+    ) -> Self {
+        Self {
+            bodies,
+            signatures,
+            effects: BTreeMap::new(),
+            usages: BTreeMap::new(),
+            active_effects: BTreeSet::new(),
+            active_usages: BTreeSet::new(),
+        }
+    }
+
+    /// Get warnings for the loops and conditionals that are not stack balanced,
+    /// out of the procedures analyzed so far. Procedures with signatures are not
+    /// included, because their callers do not depend on their bodies.
+    pub fn warnings(&self) -> Vec<Warning> {
+        let effects = self
+            .effects
+            .iter()
+            .map(|(name, r)| (name, r.as_ref().err()));
+        let usages = self.usages.iter().map(|(name, r)| (name, r.as_ref().err()));
+        effects
+            .chain(usages)
+            .filter(|(name, _)| !self.signatures.contains_key(**name))
+            .filter_map(|(name, unknown)| match unknown {
+                Some(Unknown::UnbalancedLoop(n)) if n == name => {
+                    Some(Warning::UnbalancedLoop(n.clone()))
+                }
+                Some(Unknown::UnbalancedBranch(n)) if n == name => {
+                    Some(Warning::UnbalancedBranch(n.clone()))
+                }
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Get the stack effect of calling a procedure. If the procedure
+    /// has a signature, the signature is used instead of its body.
+    pub fn effect(&mut self, name: &'a str) -> Result<StackEffect, Unknown> {
+        match self.signatures.get(name) {
+            Some(signature) => Ok(*signature),
+            None => self.body_effect(name),
+        }
+    }
+
+    /// Get the stack effect of a procedure's body
+    pub fn body_effect(&mut self, name: &'a str) -> Result<StackEffect, Unknown> {
+        if let Some(result) = self.effects.get(name) {
+            return result.clone();
+        }
+
+        if !self.active_effects.insert(name) {
+            return Err(Unknown::Recursive(name.to_string()));
+        }
+
+        let result = self.walk(name, false).map(|usage| usage.effect);
+
+        self.active_effects.remove(name);
+        self.effects.insert(name, result.clone());
+        result
+    }
+
+    /// Get the stack usage of a procedure
+    pub fn usage(&mut self, name: &'a str) -> Result<Usage, Unknown> {
+        if let Some(result) = self.usages.get(name) {
+            return result.clone();
+        }
+
+        if !self.active_usages.insert(name) {
+            return Err(Unknown::Recursive(name.to_string()));
+        }
+
+        let result = self.walk(name, true);
+
+        self.active_usages.remove(name);
+        self.usages.insert(name, result.clone());
+        result
+    }
+
+    /// Walk over a procedure's body, tracking the depth of the stack.
+    /// If `track_max` is false, the maximum depth of the stack is not computed,
+    /// so the procedures this procedure calls only need to have known effects.
+    fn walk(&mut self, name: &'a str, track_max: bool) -> Result<Usage, Unknown> {
+        let body = match self.bodies.get(name) {
+            Some(body) => body,
+            // Undefined procedures are reported when lowering
+            None => {
+                return Ok(Usage {
+                    effect: StackEffect::new(0, 0),
+                    max_depth: 0,
+                })
+            }
+        };
+
+        // The depth of the stack relative to the procedure's entry
+        let mut depth: isize = 0;
+        let mut min: isize = 0;
+        let mut max: isize = 0;
+        // The open loops and conditionals
+        let mut blocks = Vec::new();
+        // Whether the current instruction can be reached. Code that cannot
+        // be reached does not change the depth of the stack.
+        let mut reachable = true;
+
+        for instruct in body {
+            if reachable {
+                let effect = match instruct {
+                    Instruct::Call(callee) => {
+                        if track_max {
+                            let usage = self.usage(callee)?;
+                            max = max.max(depth + usage.max_depth as isize);
+                        }
+                        self.effect(callee)?
+                    }
+                    Instruct::CallIndirect => return Err(Unknown::IndirectCall(name.to_string())),
+                    other => other.stack_effect().unwrap_or(StackEffect::new(0, 0)),
+                };
+
+                depth -= effect.pops as isize;
+                min = min.min(depth);
+                depth += effect.pushes as isize;
+                max = max.max(depth);
+            }
+
+            let unbalanced_loop = || Unknown::UnbalancedLoop(name.to_string());
+            let unbalanced_branch = || Unknown::UnbalancedBranch(name.to_string());
+
+            match instruct {
+                Instruct::WhileNotZero => blocks.push(Block::Loop(depth, reachable)),
+                Instruct::EndWhile => match blocks.pop() {
+                    // At the end of the body, the next test value must be
+                    // on top of the stack that the loop started with
+                    Some(Block::Loop(start, live)) if !reachable || depth == start + 1 => {
+                        depth = start;
+                        reachable = live;
+                    }
+                    _ => return Err(unbalanced_loop()),
+                },
+                Instruct::While => blocks.push(Block::While(depth, reachable)),
+                Instruct::Do => match blocks.pop() {
+                    // The condition must push exactly one test value, which `do` popped
+                    Some(Block::While(start, live)) if !reachable || depth == start => {
+                        blocks.push(Block::Do(start, live))
+                    }
+                    _ => return Err(unbalanced_loop()),
+                },
+                Instruct::EndDo => match blocks.pop() {
+                    Some(Block::Do(start, live)) if !reachable || depth == start => {
+                        depth = start;
+                        reachable = live;
+                    }
+                    _ => return Err(unbalanced_loop()),
+                },
+                // A `break` must leave the stack as the loop's exit expects it. A `continue`
+                // must leave the next test value on top of it, unless the loop is a `while`
+                // loop, which computes its own test value.
+                Instruct::Break(n) | Instruct::Continue(n) => {
+                    let (start, test) = blocks
+                        .iter()
+                        .rev()
+                        .filter_map(|block| match block {
+                            Block::Loop(start, _) => Some((*start, 1)),
+                            Block::While(start, _) | Block::Do(start, _) => Some((*start, 0)),
+                            _ => None,
+                        })
+                        .nth(*n)
+                        .ok_or_else(unbalanced_loop)?;
+                    let expected = match instruct {
+                        Instruct::Break(_) => start,
+                        _ => start + test,
+                    };
+                    if reachable && depth != expected {
+                        return Err(unbalanced_loop());
+                    }
+                    reachable = false;
+                }
+                Instruct::If => blocks.push(Block::If(depth, reachable)),
+                Instruct::Else => match blocks.pop() {
+                    // The else branch starts at the same depth as the then branch
+                    Some(Block::If(start, live)) => {
+                        blocks.push(Block::Else(Some(depth).filter(|_| reachable), live));
+                        depth = start;
+                        reachable = live;
+                    }
+                    _ => return Err(unbalanced_branch()),
+                },
+                Instruct::EndIf => match blocks.pop() {
+                    // Without an else branch, the then branch must not change the depth
+                    Some(Block::If(start, live)) if !reachable || depth == start => {
+                        depth = start;
+                        reachable = live;
+                    }
+                    // If only one branch reaches the end, the depth is that branch's depth
+                    Some(Block::Else(then_depth, live)) => match (reachable, then_depth) {
+                        (true, Some(then_depth)) if depth != then_depth => {
+                            return Err(unbalanced_branch())
+                        }
+                        (true, _) => {}
+                        (false, Some(then_depth)) => {
+                            depth = then_depth;
+                            reachable = live;
+                        }
+                        (false, None) => {}
+                    },
+                    _ => return Err(unbalanced_branch()),
+                },
+                _ => {}
+            }
+        }
+
+        match blocks.last() {
+            None => {}
+            Some(Block::If(..)) | Some(Block::Else(..)) => {
+                return Err(Unknown::UnbalancedBranch(name.to_string()))
+            }
+            Some(_) => return Err(Unknown::UnbalancedLoop(name.to_string())),
+        }
+
+        Ok(Usage {
+            effect: StackEffect::new((-min) as usize, (depth - min) as usize),
+            max_depth: max as usize,
+        })
+    }
+}
+
+/// Get the stack effect of a list of instructions that does not call any procedures
+pub(crate) fn code_effect(code: &[Instruct]) -> Result<StackEffect, Unknown> {
+    let mut bodies = BTreeMap::new();
+    bodies.insert(String::new(), code.to_vec());
+    let signatures = BTreeMap::new();
+    let effect = Analysis::new(&bodies, &signatures).body_effect("");
+    effect
+}
@@ -2,6 +2,7 @@
 use alloc::{
     collections::BTreeMap,
     string::{String, ToString},
+    vec::Vec,
 };
 
 use core::fmt;
@@ -11,6 +12,27 @@ use spin::Mutex;
 /// does not specify one.
 pub const DEFAULT_STACK_SIZE: usize = 256;
 
+/// This is the number of cells reserved for `alloc` if the assembly file
+/// uses `stack_size auto` and the caller does not specify a heap size.
+pub const DEFAULT_HEAP_SIZE: usize = 256;
+
+/// This is the maximum number of instructions a lowered program
+/// may contain if the caller does not specify a limit. Because
+/// procedures are inlined, a small assembly file can lower to
+/// an enormous program, so the assembler stops at this limit.
+pub const DEFAULT_CODE_SIZE_LIMIT: usize = 1 << 20;
+
+/// This is the threshold used to decide whether a procedure is inlined
+/// or outlined if the caller does not specify one. A procedure is outlined
+/// when its size multiplied by the number of times it is called exceeds
+/// this threshold.
+pub const DEFAULT_OUTLINE_THRESHOLD: usize = 1024;
+
+/// This is the largest size that can be written in an assembly file, and the
+/// largest number of cells that all of the registers together can occupy.
+/// Targets address memory with 32 bit integers, so larger sizes cannot be used.
+pub const MAX_SIZE: usize = i32::MAX as usize;
+
 /// This specifies the number of predefined registers.
 /// This is VERY important to get right, if this is too small,
 /// user defined registers will overwrite the Accumulator,
@@ -52,17 +74,14 @@ impl Register {
     /// The register MUST be previously defined.
     pub fn named(name: impl fmt::Display) -> Option<Self> {
         let registers = NAMED_REGISTERS.lock();
-        if let Some(r) = registers.get(&name.to_string()) {
-            Some(r.clone())
-        } else {
-            None
-        }
+        registers.get(&name.to_string()).cloned()
     }
 
     /// Define a Register with a given name and size. This will
     /// create a Register in the NAMED_REGISTERS map with the
     /// value of REGISTER_POINTER as the Register's address.
-    pub fn define(name: impl fmt::Display, size: usize) -> Self {
+    /// `None` is returned if the registers would occupy more than `MAX_SIZE` cells.
+    pub fn define(name: impl fmt::Display, size: usize) -> Option<Self> {
         // The pointer to where this register will be stored
         let mut rptr = REGISTER_POINTER.lock();
         // The map of defined registers
@@ -77,13 +96,23 @@ impl Register {
 
         // Increment the register pointer so that the next
         // defined register will not overwrite this register
-        *rptr += size;
+        *rptr = rptr.checked_add(size).filter(|end| *end <= MAX_SIZE)?;
 
         // Insert this register into the map
         registers.insert(name.to_string(), result.clone());
 
         // Return this register
-        result
+        Some(result)
+    }
+
+    /// Get a register that occupies part of this register, such as a field of a struct
+    /// or an element of an array. The new register starts `offset` cells into this register.
+    pub fn part(&self, name: impl fmt::Display, offset: usize, size: usize) -> Self {
+        Self::Named {
+            name: name.to_string(),
+            size,
+            addr: self.get_addr() + offset,
+        }
     }
 
     /// Get the address where this register is stored. This is
@@ -92,7 +121,7 @@ impl Register {
         match self {
             Self::Named { addr, .. } => *addr,
             Self::Accumulator => 0,
-            Self::StackPointer => 1
+            Self::StackPointer => 1,
         }
     }
 
@@ -166,6 +195,30 @@ pub enum Instruct {
     /// This is equivalent to `*(double*)address = value`
     DerefStore,
 
+    /// The `deref_ld_n` instruction takes a number of cells as an argument. This instruction
+    /// pops an address off the stack and pushes the cells starting at the address.
+    ///
+    /// This is like `ld`, but for a register that is only known by its address.
+    DerefLoadN(usize),
+
+    /// The `deref_st_n` instruction takes a number of cells as an argument. This instruction
+    /// pops an address off the stack, and then pops the cells and stores them starting at
+    /// the address.
+    ///
+    /// This is like `st`, but for a register that is only known by its address.
+    DerefStoreN(usize),
+
+    /// The `memcpy` instruction takes no arguments. This instruction pops a destination
+    /// address, a source address, and a length off the stack, and copies the cells
+    /// starting at the source to the cells starting at the destination.
+    /// The two blocks of cells may overlap.
+    MemCopy,
+
+    /// The `memset` instruction takes no arguments. This instruction pops a destination
+    /// address, a value, and a length off the stack, and stores the value in every cell
+    /// starting at the destination.
+    MemSet,
+
     /// The `ld` instruction takes a register as an argument. This instruction
     /// loads the value stored in the register and pushes it onto the stack.
     Load(Register),
@@ -176,6 +229,19 @@ pub enum Instruct {
     /// This is equivalent to `register = value`
     Store(Register),
 
+    /// The `ld_idx` instruction takes a register as an argument. This instruction pops
+    /// an index off of the stack, and pushes the cell at that index in the register.
+    ///
+    /// This is equivalent to `register[index]`
+    LoadIndex(Register),
+
+    /// The `st_idx` instruction takes a register as an argument. This instruction pops
+    /// an index off of the stack, and then pops a value and stores it at that index
+    /// in the register.
+    ///
+    /// This is equivalent to `register[index] = value`
+    StoreIndex(Register),
+
     /// The `push` instruction takes either a literal as an argument. This literal
     /// can either be a character or a double precision float. This instruction pushes
     /// the literal onto the stack.
@@ -201,6 +267,25 @@ pub enum Instruct {
     /// the stack.
     Duplicate,
 
+    /// The `swap` instruction swaps the top two items on the stack
+    Swap,
+
+    /// The `over` instruction pushes a copy of the second item on the stack
+    Over,
+
+    /// The `rot` instruction moves the third item on the stack to the top,
+    /// so `a b c` becomes `b c a`
+    Rotate,
+
+    /// The `pick` instruction takes a number N as an argument, and pushes a copy of the
+    /// item N cells below the top of the stack. `pick 0` is equivalent to `dup`, and
+    /// `pick 1` is equivalent to `over`.
+    Pick(usize),
+
+    /// The `drop` instruction takes a number N as an argument, and pops N cells off of
+    /// the stack. Like `pop`, the last cell dropped is left in the ACC register.
+    Drop(usize),
+
     /// The `add` instruction pops two cells off the stack and pushes their sum
     Add,
 
@@ -213,6 +298,84 @@ pub enum Instruct {
     /// The `div` instruction pops two cells off the stack and pushes the first divided by the second
     Divide,
 
+    /// The `mod` instruction pops two cells off the stack and pushes the remainder of the
+    /// first divided by the second. The result has the sign of the first cell, like C's `fmod`.
+    /// If the second cell is zero or the first is infinite, the result is NaN.
+    /// If the second cell is infinite and the first is finite, the result is the first cell.
+    Modulo,
+
+    /// The `neg` instruction pops a cell off the stack and pushes its negation
+    Negate,
+
+    /// The `floor` instruction pops a cell off the stack and pushes the largest integer
+    /// not greater than it. Infinities and NaN are pushed unchanged.
+    Floor,
+
+    /// The `ceil` instruction pops a cell off the stack and pushes the smallest integer
+    /// not less than it. Infinities and NaN are pushed unchanged.
+    Ceil,
+
+    /// The `trunc` instruction pops a cell off the stack and pushes it rounded toward zero.
+    /// Infinities and NaN are pushed unchanged.
+    Truncate,
+
+    /// The `abs` instruction pops a cell off the stack and pushes its absolute value
+    Absolute,
+
+    /// The `pow` instruction pops two cells off the stack and pushes the first raised to
+    /// the power of the second, like C's `pow`. A negative first cell raised to a power that
+    /// is not an integer is NaN, and anything raised to the power of zero is 1, even NaN.
+    Power,
+
+    /// The `sqrt` instruction pops a cell off the stack and pushes its square root.
+    /// The square root of a negative number is NaN.
+    SquareRoot,
+
+    /// The `band` instruction pops two cells off the stack, converts them to integers,
+    /// and pushes their bitwise and.
+    ///
+    /// Every bitwise and logical instruction converts cells to 64 bit signed integers
+    /// by rounding toward zero. NaN is converted to 0, and cells outside of the range
+    /// of a 64 bit integer are converted to the nearest integer in the range. The
+    /// result is pushed as a double precision float, so integers larger than 2^53
+    /// lose precision.
+    BitAnd,
+
+    /// The `bor` instruction pops two cells off the stack, converts them to integers,
+    /// and pushes their bitwise or
+    BitOr,
+
+    /// The `bxor` instruction pops two cells off the stack, converts them to integers,
+    /// and pushes their bitwise exclusive or
+    BitXor,
+
+    /// The `bnot` instruction pops a cell off the stack, converts it to an integer,
+    /// and pushes its bitwise complement
+    BitNot,
+
+    /// The `shl` instruction pops two cells off the stack, converts them to integers,
+    /// and pushes the first shifted left by the second. Only the lowest 6 bits of the
+    /// second cell are used, so the shift is always between 0 and 63 bits.
+    ShiftLeft,
+
+    /// The `shr` instruction pops two cells off the stack, converts them to integers,
+    /// and pushes the first shifted right by the second, keeping its sign. Only the
+    /// lowest 6 bits of the second cell are used, like `shl`.
+    ShiftRight,
+
+    /// The `and` instruction pops two cells off the stack and pushes 1 if both are
+    /// true, and 0 otherwise. A cell is true if its integer conversion is not zero,
+    /// which is the same test used by `loop` and `if`.
+    And,
+
+    /// The `or` instruction pops two cells off the stack and pushes 1 if either is true,
+    /// and 0 otherwise
+    Or,
+
+    /// The `not` instruction pops a cell off the stack and pushes 1 if it is false,
+    /// and 0 otherwise
+    Not,
+
     /// The `outc` instruction pops a cell off the stack and prints `cell % 256` as a character to STDOUT
     OutputChar,
 
@@ -229,11 +392,224 @@ pub enum Instruct {
     /// If the first popped value is less than the second, -1 is pushed.
     /// If the first popped value is equal to the second, 0 is pushed.
     /// If the first popped value is greater than to the second, 1 is pushed.
+    /// If either value is NaN, NaN is pushed.
     Compare,
 
+    /// The `lt` instruction pops two cells off the stack, and pushes 1 if the first
+    /// popped value is less than the second, and 0 otherwise.
+    ///
+    /// Every comparison follows IEEE 754: if either value is NaN, every comparison
+    /// pushes 0 except for `ne`, which pushes 1.
+    Less,
+
+    /// The `le` instruction pops two cells off the stack, and pushes 1 if the first
+    /// popped value is less than or equal to the second, and 0 otherwise
+    LessEqual,
+
+    /// The `gt` instruction pops two cells off the stack, and pushes 1 if the first
+    /// popped value is greater than the second, and 0 otherwise
+    Greater,
+
+    /// The `ge` instruction pops two cells off the stack, and pushes 1 if the first
+    /// popped value is greater than or equal to the second, and 0 otherwise
+    GreaterEqual,
+
+    /// The `eq` instruction pops two cells off the stack, and pushes 1 if they are equal,
+    /// and 0 otherwise
+    Equal,
+
+    /// The `ne` instruction pops two cells off the stack, and pushes 1 if they are not equal,
+    /// and 0 otherwise
+    NotEqual,
+
     /// The `loop` instruction the start of a loop. At the start of each iteration, a test value is popped from the stack. While the value is not zero, the loop continues. Else, the loop jumps to the matching `endloop`
     WhileNotZero,
 
     /// The `endloop` instruction marks the end of a loop
     EndWhile,
+
+    /// The `while` instruction marks the start of a loop whose test value is computed
+    /// by the instructions between `while` and `do`, which run before every iteration
+    While,
+
+    /// The `do` instruction pops the test value of a `while` loop. If the value is not zero,
+    /// the body of the loop is executed. Otherwise, the loop jumps to the matching `endwhile`
+    Do,
+
+    /// The `endwhile` instruction marks the end of a `while` loop, and jumps back to its `while`
+    EndDo,
+
+    /// The `break` instruction jumps past the end of an enclosing loop.
+    /// The argument is the number of loops between the innermost loop and the
+    /// loop being left, so `Break(0)` leaves the innermost loop.
+    Break(usize),
+
+    /// The `continue` instruction jumps to the `endloop` of an enclosing loop, which
+    /// then pops the next test value, or to the `while` of an enclosing `while` loop.
+    /// Like `Break`, the argument is the number of loops between the innermost loop
+    /// and the loop being continued.
+    Continue(usize),
+
+    /// The `if` instruction pops a test value off of the stack. If the value is not zero,
+    /// the instructions up to the matching `else` or `endif` are executed. Otherwise,
+    /// execution jumps to the matching `else` or `endif`.
+    If,
+
+    /// The `else` instruction marks the start of the instructions executed when the
+    /// test value of the matching `if` is zero
+    Else,
+
+    /// The `endif` instruction marks the end of a conditional
+    EndIf,
+
+    /// The `call` instruction calls a procedure that was outlined as a
+    /// subroutine instead of being inlined. The procedure's code is stored
+    /// in the `procs` map of the Program.
+    Call(String),
+
+    /// The `call` instruction calls an extern procedure, which is declared with
+    /// `extern proc NAME (ARGS -> RETS)` and implemented by the host of the target.
+    /// It pops ARGS cells off of the stack as the arguments, the deepest cell being
+    /// the first argument, and pushes the RETS cells the host returns in order.
+    CallExtern {
+        name: String,
+        args: usize,
+        rets: usize,
+    },
+
+    /// The `refer_proc` instruction takes the name of a procedure, and pushes the
+    /// identifier of the procedure onto the stack. A procedure that is referred to
+    /// is always outlined, so its identifier is given by `Program::proc_id`.
+    ReferProc(String),
+
+    /// The `call_ind` instruction pops the identifier of a procedure off of the
+    /// stack, and calls the procedure. The program is stopped if no procedure
+    /// has the identifier.
+    CallIndirect,
+
+    /// The `raw` instruction holds code written in the language of a target, which
+    /// that target emits verbatim. Other targets refuse to assemble a program with
+    /// raw code for a different target. Raw code is assumed to leave the stack as it was.
+    Raw { target: String, code: String },
+}
+
+impl Instruct {
+    /// Get the stack effect of this instruction: the number of cells it pops off
+    /// of the stack, and the number of cells it then pushes onto the stack.
+    ///
+    /// The `loop`, `do`, and `if` instructions pop their test values, and the other control
+    /// instructions have no stack effects of their own.
+    /// The stack effect of a `call` or `call_ind` instruction depends on the procedure
+    /// being called, so `None` is returned for it.
+    pub fn stack_effect(&self) -> Option<StackEffect> {
+        Some(match self {
+            Self::Refer(_) => StackEffect::new(0, 1),
+            Self::DerefLoad => StackEffect::new(1, 1),
+            Self::DerefStore => StackEffect::new(2, 0),
+            Self::DerefLoadN(n) => StackEffect::new(1, *n),
+            Self::DerefStoreN(n) => StackEffect::new(n + 1, 0),
+            Self::MemCopy | Self::MemSet => StackEffect::new(3, 0),
+            Self::Load(r) => StackEffect::new(0, r.get_size()),
+            Self::Store(r) => StackEffect::new(r.get_size(), 0),
+            Self::LoadIndex(_) => StackEffect::new(1, 1),
+            Self::StoreIndex(_) => StackEffect::new(2, 0),
+            Self::Push(_) => StackEffect::new(0, 1),
+            Self::Pop => StackEffect::new(1, 0),
+            Self::Alloc(_) | Self::Free(_) => StackEffect::new(1, 0),
+            Self::Duplicate => StackEffect::new(1, 2),
+            Self::Swap => StackEffect::new(2, 2),
+            Self::Over => StackEffect::new(2, 3),
+            Self::Rotate => StackEffect::new(3, 3),
+            Self::Pick(n) => StackEffect::new(n + 1, n + 2),
+            Self::Drop(n) => StackEffect::new(*n, 0),
+            Self::Add | Self::Subtract | Self::Multiply | Self::Divide | Self::Compare => {
+                StackEffect::new(2, 1)
+            }
+            Self::Modulo | Self::Power => StackEffect::new(2, 1),
+            Self::Less | Self::LessEqual | Self::Greater | Self::GreaterEqual => {
+                StackEffect::new(2, 1)
+            }
+            Self::Equal | Self::NotEqual => StackEffect::new(2, 1),
+            Self::Negate | Self::Floor | Self::Ceil | Self::Truncate => StackEffect::new(1, 1),
+            Self::Absolute | Self::SquareRoot => StackEffect::new(1, 1),
+            Self::BitAnd | Self::BitOr | Self::BitXor => StackEffect::new(2, 1),
+            Self::ShiftLeft | Self::ShiftRight | Self::And | Self::Or => StackEffect::new(2, 1),
+            Self::BitNot | Self::Not => StackEffect::new(1, 1),
+            Self::OutputChar | Self::OutputNumber => StackEffect::new(1, 0),
+            Self::InputChar | Self::InputNumber => StackEffect::new(0, 1),
+            Self::WhileNotZero => StackEffect::new(1, 0),
+            Self::EndWhile | Self::Break(_) | Self::Continue(_) => StackEffect::new(0, 0),
+            Self::While | Self::EndDo => StackEffect::new(0, 0),
+            Self::Do => StackEffect::new(1, 0),
+            Self::If => StackEffect::new(1, 0),
+            Self::Else | Self::EndIf | Self::Raw { .. } => StackEffect::new(0, 0),
+            Self::CallExtern { args, rets, .. } => StackEffect::new(*args, *rets),
+            Self::ReferProc(_) => StackEffect::new(0, 1),
+            Self::Call(_) | Self::CallIndirect => return None,
+        })
+    }
+}
+
+/// The StackEffect struct describes how an instruction or a procedure
+/// changes the stack: it pops `pops` cells, and then pushes `pushes` cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd)]
+pub struct StackEffect {
+    /// The number of cells taken off of the stack
+    pub pops: usize,
+
+    /// The number of cells put onto the stack after popping
+    pub pushes: usize,
+}
+
+impl StackEffect {
+    /// Create a stack effect from the number of cells popped and pushed
+    pub fn new(pops: usize, pushes: usize) -> Self {
+        Self { pops, pushes }
+    }
+
+    /// Get the net change in the depth of the stack
+    pub fn net(&self) -> isize {
+        self.pushes as isize - self.pops as isize
+    }
+}
+
+impl fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({} -> {})", self.pops, self.pushes)
+    }
+}
+
+/// The Program struct is the result of assembling an assembly file, and
+/// is what a Target converts into output code.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Program {
+    /// The address where the stack begins. This is also the number of cells
+    /// used by the predefined and user defined registers.
+    pub initial_stack_ptr: usize,
+
+    /// The number of cells reserved for the stack
+    pub stack_size: usize,
+
+    /// The instructions executed by the entry point
+    pub code: Vec<Instruct>,
+
+    /// The procedures that were outlined as subroutines, by name
+    pub procs: BTreeMap<String, Vec<Instruct>>,
+
+    /// The initial contents of the registers defined with `data`, by address.
+    /// These cells must be stored before the entry point runs.
+    pub data: BTreeMap<usize, Vec<f64>>,
+
+    /// If this is set, `ld_idx` and `st_idx` must check that their indices are
+    /// inside of their registers at runtime, and stop the program if they are not
+    pub bounds_checks: bool,
+}
+
+impl Program {
+    /// Get the identifier of an outlined procedure, which `refer_proc` pushes and
+    /// `call_ind` pops. This is one more than the position of the procedure in `procs`,
+    /// so zero is never the identifier of a procedure.
+    pub fn proc_id(&self, name: &str) -> Option<usize> {
+        self.procs.keys().position(|n| n == name).map(|i| i + 1)
+    }
 }
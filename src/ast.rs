@@ -1,10 +1,14 @@
 use crate::{asm, Error, Result};
 use alloc::{
+    collections::{BTreeMap, BTreeSet},
     string::{String, ToString},
     vec::Vec,
 };
 use core::fmt::Display;
 
+/// The lowered bodies of the procedures that are outlined, by name
+pub(crate) type Subroutines = BTreeMap<String, Vec<asm::Instruct>>;
+
 /// The Ast object stores the entire abstract syntax tree
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct Ast {
@@ -17,27 +21,328 @@ impl Ast {
         Self { procs }
     }
 
-    /// Lower the list of procedures into a list of instructions.
-    /// Procedures are not stored in memory, but are inlined instead.
-    /// All this method does is resolve all `call` instructions.
-    pub fn lower(self) -> Result<Vec<asm::Instruct>> {
-        let mut entry_proc = Procedure::new(Procedure::ENTRY_POINT, Vec::new());
+    /// Get the procedures in the order they were defined
+    pub(crate) fn procs(&self) -> Vec<&Procedure> {
+        self.procs.iter().collect()
+    }
+
+    /// Get the body of every procedure as a list of instructions, without
+    /// inlining anything. Every call is represented by `Instruct::Call`.
+    pub fn bodies(&self) -> BTreeMap<String, Vec<asm::Instruct>> {
+        self.procs
+            .iter()
+            .map(|prc| {
+                let body = prc
+                    .code
+                    .iter()
+                    .filter_map(|expr| match expr {
+                        Exec::Define(_) | Exec::Data(..) => None,
+                        Exec::Call(name) => Some(asm::Instruct::Call(name.clone())),
+                        Exec::Assembly(i) => Some(i.clone()),
+                    })
+                    .collect();
+                (prc.name.clone(), body)
+            })
+            .collect()
+    }
+
+    /// Get the initial contents of every register defined with `data`, by address
+    pub fn data(&self) -> BTreeMap<usize, Vec<f64>> {
+        self.procs
+            .iter()
+            .flat_map(|prc| &prc.code)
+            .filter_map(|expr| match expr {
+                Exec::Data(r, cells) => Some((r.get_addr(), cells.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Get the declared signature of every procedure that has one
+    pub fn signatures(&self) -> BTreeMap<String, asm::StackEffect> {
+        self.procs
+            .iter()
+            .filter_map(|prc| Some((prc.name.clone(), prc.signature?)))
+            .collect()
+    }
+
+    /// Lower the list of procedures into the instructions of the entry point
+    /// and the procedures that are outlined as subroutines. All other procedures
+    /// are inlined at each `call`.
+    ///
+    /// A procedure is outlined if it is annotated with `noinline`, if it is
+    /// recursive, if it is referred to with `refer_proc`, or if its size multiplied
+    /// by the number of times it is called exceeds `outline_threshold`. Procedures
+    /// annotated with `inline` are never outlined. Calls in procedures that are
+    /// never reached from the entry point are not counted.
+    ///
+    /// If the lowered program would contain more than `code_size_limit`
+    /// instructions, lowering stops and an error is returned.
+    pub fn lower(
+        self,
+        code_size_limit: usize,
+        outline_threshold: usize,
+    ) -> Result<(Vec<asm::Instruct>, Subroutines)> {
+        let procs = self
+            .procs
+            .iter()
+            .map(|prc| (prc.name.as_str(), prc))
+            .collect::<BTreeMap<_, _>>();
+
+        if !procs.contains_key(Procedure::ENTRY_POINT) {
+            return Ok((Vec::new(), BTreeMap::new()));
+        }
+
+        // Only count the calls in procedures that can be reached from the entry
+        // point, because the others are never lowered
+        let mut calls = BTreeMap::new();
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![Procedure::ENTRY_POINT];
+        while let Some(name) = pending.pop() {
+            let prc = match procs.get(name) {
+                Some(prc) if reachable.insert(name) => *prc,
+                _ => continue,
+            };
+            for expr in &prc.code {
+                match expr {
+                    Exec::Call(callee) => {
+                        *calls.entry(callee.as_str()).or_insert(0) += 1;
+                        pending.push(callee);
+                    }
+                    Exec::Assembly(asm::Instruct::ReferProc(callee)) => pending.push(callee),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut lowering = Lowering {
+            procs,
+            calls,
+            cache: BTreeMap::new(),
+            outlined: BTreeSet::new(),
+            active: BTreeSet::new(),
+            code_size_limit,
+            outline_threshold,
+        };
+
+        let code = lowering.lower(Procedure::ENTRY_POINT)?;
+        let mut size = code.len();
+        let mut subroutines = BTreeMap::new();
+        for name in &lowering.outlined {
+            let body = lowering.cache[name].clone();
+            size += body.len();
+            subroutines.insert(name.to_string(), body);
+        }
+
+        if size > code_size_limit {
+            return Err(Error::CodeSizeLimitExceeded(code_size_limit));
+        }
+
+        Ok((code, subroutines))
+    }
+}
+
+/// Lowering resolves `call` instructions by inlining or outlining procedures.
+/// The lowered body of every procedure is cached, so each procedure
+/// is only lowered once no matter how many times it is called.
+struct Lowering<'a> {
+    /// The procedures in the program, looked up by name
+    procs: BTreeMap<&'a str, &'a Procedure>,
+
+    /// The number of places each procedure is called from, counting only
+    /// the procedures that are reachable from the entry point
+    calls: BTreeMap<&'a str, usize>,
+
+    /// The lowered bodies of the procedures that have been lowered so far
+    cache: BTreeMap<&'a str, Vec<asm::Instruct>>,
+
+    /// The procedures that are called as subroutines instead of being inlined
+    outlined: BTreeSet<&'a str>,
+
+    /// The procedures that are currently being lowered. If a procedure
+    /// in this set is called again, the procedure is recursive.
+    active: BTreeSet<&'a str>,
+
+    /// The maximum number of instructions a lowered body may contain
+    code_size_limit: usize,
+
+    /// Procedures whose size times their number of calls exceeds this are outlined
+    outline_threshold: usize,
+}
+
+impl<'a> Lowering<'a> {
+    /// Lower the entry point and all of the procedures it calls
+    fn lower(&mut self, name: &'a str) -> Result<Vec<asm::Instruct>> {
+        self.lower_proc(name)?;
+        if self.outlined.contains(name) {
+            Ok(vec![asm::Instruct::Call(name.to_string())])
+        } else {
+            Ok(self.cache[name].clone())
+        }
+    }
+
+    /// Lower a procedure, store its body in the cache,
+    /// and decide whether or not it is outlined
+    fn lower_proc(&mut self, name: &'a str) -> Result<()> {
+        if self.cache.contains_key(name) {
+            return Ok(());
+        }
+
+        let prc = match self.procs.get(name) {
+            Some(prc) => *prc,
+            None => return Err(Error::ProcedureNotDefined(name.to_string())),
+        };
+
+        self.active.insert(name);
+
+        let mut result = Vec::new();
+        for expr in &prc.code {
+            match expr {
+                Exec::Define(_) | Exec::Data(..) => {}
+                Exec::Call(callee) => {
+                    if self.active.contains(callee.as_str()) {
+                        // A recursive call can only be lowered as a subroutine call
+                        if self.procs[callee.as_str()].inline == Inline::Always {
+                            return Err(Error::RecursiveProcedure(callee.clone()));
+                        }
+                        self.outlined.insert(callee);
+                    } else {
+                        self.lower_proc(callee)?;
+                    }
+
+                    if self.outlined.contains(callee.as_str()) {
+                        result.push(asm::Instruct::Call(callee.clone()));
+                    } else {
+                        let body = &self.cache[callee.as_str()];
+                        if result.len() + body.len() > self.code_size_limit {
+                            return Err(Error::CodeSizeLimitExceeded(self.code_size_limit));
+                        }
+                        result.extend(body.iter().cloned());
+                    }
+                }
+                Exec::Assembly(asm::Instruct::ReferProc(callee)) => {
+                    // A procedure that is referred to can be called through its
+                    // identifier, so it must be outlined
+                    let inline = self.procs.get(callee.as_str()).map(|prc| prc.inline);
+                    if inline == Some(Inline::Always) {
+                        return Err(Error::ReferredInlineProcedure(callee.clone()));
+                    }
+                    if !self.active.contains(callee.as_str()) {
+                        self.lower_proc(callee)?;
+                    }
+                    self.outlined.insert(callee);
+                    result.push(asm::Instruct::ReferProc(callee.clone()));
+                }
+                Exec::Assembly(i) => result.push(i.clone()),
+            }
+
+            if result.len() > self.code_size_limit {
+                return Err(Error::CodeSizeLimitExceeded(self.code_size_limit));
+            }
+        }
+
+        let calls = self.calls.get(name).copied().unwrap_or(0);
+        let outline = match prc.inline {
+            Inline::Always => false,
+            Inline::Never => true,
+            Inline::Auto => {
+                calls > 1
+                    && result.len().saturating_mul(calls) > self.outline_threshold
+                    && check_nesting(&result).is_ok()
+            }
+        };
+        if outline {
+            self.outlined.insert(name);
+        }
+
+        self.active.remove(name);
+        self.cache.insert(name, result);
+        Ok(())
+    }
+}
+
+/// The kinds of blocks that must be closed in a list of instructions
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Block {
+    /// A `loop` waiting for its `endloop`
+    Loop,
 
-        for prc in &self.procs {
-            if prc.is_entry_point() {
-                entry_proc = prc.clone();
+    /// A `while` waiting for its `do`
+    While,
+
+    /// A `do` waiting for its `endwhile`
+    Do,
+
+    /// An `if` waiting for its `else` or `endif`
+    If,
+
+    /// An `else` waiting for its `endif`
+    Else,
+}
+
+/// Check that a list of instructions closes every loop and conditional it opens,
+/// that they are properly nested, and that every `break` and `continue` is inside
+/// of the loop it refers to. Only procedures that pass this check
+/// can be outlined automatically.
+pub(crate) fn check_nesting(code: &[asm::Instruct]) -> Result<()> {
+    let mut blocks = Vec::new();
+    for instruct in code {
+        match instruct {
+            asm::Instruct::WhileNotZero => blocks.push(Block::Loop),
+            asm::Instruct::EndWhile => match blocks.pop() {
+                Some(Block::Loop) => {}
+                _ => return Err(Error::UnmatchedLoop),
+            },
+            // A `break` or `continue` must be inside of the loop it refers to
+            asm::Instruct::While => blocks.push(Block::While),
+            asm::Instruct::Do => match blocks.pop() {
+                Some(Block::While) => blocks.push(Block::Do),
+                _ => return Err(Error::UnmatchedLoop),
+            },
+            asm::Instruct::EndDo => match blocks.pop() {
+                Some(Block::Do) => {}
+                _ => return Err(Error::UnmatchedLoop),
+            },
+            asm::Instruct::Break(n) | asm::Instruct::Continue(n)
+                if blocks
+                    .iter()
+                    .filter(|b| !matches!(b, Block::If | Block::Else))
+                    .count()
+                    <= *n =>
+            {
+                return Err(Error::UnmatchedLoop)
             }
+            asm::Instruct::If => blocks.push(Block::If),
+            asm::Instruct::Else => match blocks.pop() {
+                Some(Block::If) => blocks.push(Block::Else),
+                _ => return Err(Error::UnmatchedIf),
+            },
+            asm::Instruct::EndIf => match blocks.pop() {
+                Some(Block::If) | Some(Block::Else) => {}
+                _ => return Err(Error::UnmatchedIf),
+            },
+            _ => {}
         }
+    }
 
-        entry_proc.lower(&self.procs)
+    match blocks.last() {
+        None => Ok(()),
+        Some(Block::If) | Some(Block::Else) => Err(Error::UnmatchedIf),
+        Some(_) => Err(Error::UnmatchedLoop),
     }
 }
 
 /// This represents an instruction in an assembly file
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub(crate) enum Exec {
-    /// This is not used by the user, but is used internally for simplicity
-    Nop,
+    /// A register definition. Registers are allocated when they are parsed,
+    /// so this does nothing when lowered, but it is kept for the linter.
+    Define(asm::Register),
+
+    /// A register definition with initial contents. The register is filled
+    /// with the cells before the entry point runs, so this also does nothing
+    /// when lowered.
+    Data(asm::Register, Vec<f64>),
 
     /// A procedure call
     Call(String),
@@ -58,61 +363,88 @@ impl Exec {
     }
 }
 
+/// Whether a procedure should be inlined or outlined
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub(crate) enum Inline {
+    /// Let the assembler decide (no annotation)
+    Auto,
+
+    /// Always inline the procedure (the `inline` annotation)
+    Always,
+
+    /// Never inline the procedure (the `noinline` annotation)
+    Never,
+}
+
 /// The Procedure struct is only
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub(crate) struct Procedure {
     /// The name of the procedure
     name: String,
 
+    /// Whether the procedure should be inlined
+    inline: Inline,
+
+    /// The declared stack effect of the procedure, if it has one
+    signature: Option<asm::StackEffect>,
+
     /// The list of instructions the procedure will execute when called
     code: Vec<Exec>,
+
+    /// Whether the procedure was defined in a module of the standard library
+    stdlib: bool,
 }
 
 impl Procedure {
     /// The name of the entry point function
-    const ENTRY_POINT: &'static str = "start";
+    pub(crate) const ENTRY_POINT: &'static str = "start";
 
-    pub fn new(name: impl Display, code: Vec<Exec>) -> Self {
+    pub fn new(
+        name: impl Display,
+        inline: Inline,
+        signature: Option<asm::StackEffect>,
+        code: Vec<Exec>,
+    ) -> Self {
         Self {
             name: name.to_string(),
+            inline,
+            signature,
             code,
+            stdlib: false,
         }
     }
 
-    pub fn get_name(&self) -> String {
-        self.name.clone()
+    /// Mark the procedure as defined in a module of the standard library
+    pub fn set_stdlib(&mut self) {
+        self.stdlib = true;
     }
 
-    pub fn is_entry_point(&self) -> bool {
-        self.get_name() == Self::ENTRY_POINT
+    pub fn is_stdlib(&self) -> bool {
+        self.stdlib
     }
 
-    pub fn is_proc(&self, name: impl Display) -> bool {
-        self.get_name() == name.to_string()
+    pub fn get_name(&self) -> &str {
+        &self.name
     }
 
-    pub fn lower(&self, procs: &[Self]) -> Result<Vec<asm::Instruct>> {
-        let mut result = Vec::new();
-        for expr in &self.code {
-            match expr {
-                Exec::Nop => {}
-                Exec::Call(name) => {
-                    let mut proc_exists = false;
-                    for prc in procs {
-                        if prc.is_proc(&name) {
-                            proc_exists = true;
-                            result.extend(prc.lower(procs)?);
-                            break;
-                        }
-                    }
+    pub fn get_code(&self) -> &[Exec] {
+        &self.code
+    }
 
-                    if !proc_exists {
-                        return Err(Error::ProcedureNotDefined(name.clone()));
-                    }
+    /// Turn the calls to extern procedures into calls to the host. Calls are parsed
+    /// before every extern procedure is declared, so this is done once the whole
+    /// program has been parsed.
+    pub fn call_externs(&mut self, externs: &BTreeMap<String, asm::StackEffect>) {
+        for exec in &mut self.code {
+            if let Exec::Call(name) = exec {
+                if let Some(effect) = externs.get(name) {
+                    *exec = Exec::asm(asm::Instruct::CallExtern {
+                        name: name.clone(),
+                        args: effect.pops,
+                        rets: effect.pushes,
+                    });
                 }
-                Exec::Assembly(i) => result.push(i.clone()),
             }
         }
-        Ok(result)
     }
 }
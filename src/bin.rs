@@ -1,10 +1,107 @@
-use clap::{clap_app, crate_version, AppSettings};
-use lasm::{assemble, target::C};
+use clap::{clap_app, crate_version, AppSettings, ArgMatches};
+use lasm::{
+    assemble_file, check_file,
+    lint::{Level, Lint},
+    target::C,
+    Loader, Options, Warning,
+};
 use std::{
     fs::{read_to_string, write},
+    iter::once,
+    path::{Path, PathBuf},
     process::exit,
 };
 
+/// Loads included files from the filesystem. A path is resolved relative to
+/// the including file first, and then relative to each search path in order.
+struct FileLoader {
+    /// The directories given with the `-I` flag
+    paths: Vec<PathBuf>,
+}
+
+impl FileLoader {
+    fn new(matches: &ArgMatches) -> Self {
+        Self {
+            paths: matches
+                .values_of("include")
+                .into_iter()
+                .flatten()
+                .map(PathBuf::from)
+                .collect(),
+        }
+    }
+
+    /// Get the name used to identify the file at a path
+    fn name(path: &Path) -> Option<String> {
+        Some(path.canonicalize().ok()?.display().to_string())
+    }
+}
+
+impl Loader for FileLoader {
+    fn load(&self, from: &str, path: &str) -> Option<(String, String)> {
+        let dir = Path::new(from)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        once(dir).chain(self.paths.iter().cloned()).find_map(|dir| {
+            let name = Self::name(&dir.join(path))?;
+            let contents = read_to_string(&name).ok()?;
+            Some((name, contents))
+        })
+    }
+}
+
+/// Print warnings along with the lints they belong to
+fn print_warnings(warnings: Vec<Warning>) {
+    for warning in warnings {
+        println!("warning: {} [{}]", warning, warning.lint());
+    }
+}
+
+/// Set the levels of the lints given with the `-A`, `-W`, and `-D` flags
+fn set_lint_levels(matches: &ArgMatches, options: &mut Options) {
+    for (arg, level) in &[
+        ("allow", Level::Allow),
+        ("warn", Level::Warn),
+        ("deny", Level::Deny),
+    ] {
+        for name in matches.values_of(arg).into_iter().flatten() {
+            match (name, Lint::named(name)) {
+                ("warnings", _) if *level == Level::Deny => options.lints.deny_warnings(),
+                (_, Some(lint)) => options.lints.set(lint, *level),
+                (_, None) => {
+                    println!("unknown lint: '{}'", name);
+                    exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Set the constants and features given with the `--define` and `--feature` flags
+fn set_defines(matches: &ArgMatches, options: &mut Options) {
+    for define in matches.values_of("define").into_iter().flatten() {
+        let (name, value) = match define.find('=') {
+            Some(i) => (&define[..i], define[i + 1..].parse().ok()),
+            None => (define, Some(1.0)),
+        };
+        match value {
+            Some(value) => options.defines.insert(name.to_string(), value),
+            None => {
+                println!("invalid define: '{}'", define);
+                exit(1);
+            }
+        };
+    }
+    options.features.extend(
+        matches
+            .values_of("feature")
+            .into_iter()
+            .flatten()
+            .map(String::from),
+    );
+}
+
 fn main() {
     let matches = clap_app!(lasm =>
         (version: crate_version!())
@@ -12,26 +109,113 @@ fn main() {
         (about: "Compiles lasm assembly")
         (@arg input: +takes_value +required "Path to free file to compile")
         (@arg output: +takes_value "Path to output file")
+        (@arg code_limit: -l --limit +takes_value "Maximum number of instructions in the assembled program")
+        (@arg outline_threshold: --outline +takes_value "Outline procedures whose size times call count exceeds this")
+        (@arg heap_size: --heap +takes_value "Number of cells to reserve for alloc with `stack_size auto`")
+        (@arg bounds_checks: --("bounds-checks") "Check the indices of ld_idx and st_idx at runtime")
+        (@arg include: -I --include +takes_value +multiple number_of_values(1) "Directory to search for included files")
+        (@arg define: -D --define +takes_value +multiple number_of_values(1) "Constant to define, as NAME=VALUE, or NAME to define it as 1")
+        (@arg feature: -F --feature +takes_value +multiple number_of_values(1) "Feature to enable for #if feature(NAME)")
+        (@subcommand check =>
+            (about: "Checks lasm assembly for errors and warnings without compiling it")
+            (@arg input: +takes_value +required "Path to file to check")
+            (@arg allow: -A --allow +takes_value +multiple number_of_values(1) "Lint to allow")
+            (@arg warn: -W --warn +takes_value +multiple number_of_values(1) "Lint to report as a warning")
+            (@arg deny: -D --deny +takes_value +multiple number_of_values(1) "Lint to report as an error, or `warnings` to deny all warnings")
+            (@arg include: -I --include +takes_value +multiple number_of_values(1) "Directory to search for included files")
+            (@arg define: -d --define +takes_value +multiple number_of_values(1) "Constant to define, as NAME=VALUE, or NAME to define it as 1")
+            (@arg feature: -F --feature +takes_value +multiple number_of_values(1) "Feature to enable for #if feature(NAME)")
+        )
     )
     .setting(AppSettings::ArgRequiredElseHelp)
+    .setting(AppSettings::SubcommandsNegateReqs)
     .get_matches();
 
-    let output_file = match matches.value_of("output") {
-        Some(file) => file,
-        None => "out.c",
-    };
+    let mut options = Options::default();
+
+    if let Some(matches) = matches.subcommand_matches("check") {
+        set_lint_levels(matches, &mut options);
+        set_defines(matches, &mut options);
+
+        if let Some(file) = matches.value_of("input") {
+            match read_to_string(file) {
+                Ok(contents) => match check_file(
+                    C,
+                    &FileLoader::name(Path::new(file)).unwrap_or_else(|| file.to_string()),
+                    contents,
+                    &FileLoader::new(matches),
+                    &options,
+                ) {
+                    Ok(warnings) => print_warnings(warnings),
+                    Err(e) => {
+                        println!("{}", e);
+                        exit(1);
+                    }
+                },
+                Err(_) => {
+                    println!("could not read file: '{}'", file);
+                    exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    let output_file = matches.value_of("output").unwrap_or("out.c");
+
+    if let Some(limit) = matches.value_of("code_limit") {
+        match limit.parse() {
+            Ok(n) => options.code_size_limit = n,
+            Err(_) => {
+                println!("invalid code size limit: '{}'", limit);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(threshold) = matches.value_of("outline_threshold") {
+        match threshold.parse() {
+            Ok(n) => options.outline_threshold = n,
+            Err(_) => {
+                println!("invalid outline threshold: '{}'", threshold);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(size) = matches.value_of("heap_size") {
+        match size.parse() {
+            Ok(n) => options.heap_size = n,
+            Err(_) => {
+                println!("invalid heap size: '{}'", size);
+                exit(1);
+            }
+        }
+    }
+
+    options.bounds_checks = matches.is_present("bounds_checks");
+    set_defines(&matches, &mut options);
 
     if let Some(file) = matches.value_of("input") {
         if let Ok(contents) = read_to_string(file) {
-            let output_contents = match assemble(C, contents) {
-                Ok(c) => c,
+            let output_contents = match assemble_file(
+                C,
+                &FileLoader::name(Path::new(file)).unwrap_or_else(|| file.to_string()),
+                contents,
+                &FileLoader::new(&matches),
+                &options,
+            ) {
+                Ok((c, warnings)) => {
+                    print_warnings(warnings);
+                    c
+                }
                 Err(e) => {
                     println!("{}", e);
                     exit(1);
                 }
             };
 
-            if write(&output_file, &output_contents).is_ok() {
+            if write(output_file, &output_contents).is_ok() {
                 println!("Successfully compiled program to {}", output_file);
             }
         }
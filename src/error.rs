@@ -7,7 +7,9 @@
 //! Additionally, because this assembler also functions as a library, keeping error handling
 //! elegant is crucial. As such, I've tried to make error handling as simple as possible.
 
+use crate::{analysis::Unknown, asm::StackEffect, lint::Lint};
 use alloc::{
+    boxed::Box,
     string::{String, ToString},
     vec::Vec,
 };
@@ -71,9 +73,94 @@ pub enum Error {
     /// This is returned when the `proc` keyword is not followed by a procedure name
     NoProcedureName(String),
 
-    /// This is returned when an integer is expected but not found
+    /// This is returned when an integer is expected but not found, when a size is
+    /// negative or larger than `asm::MAX_SIZE`, or when the registers do not fit in memory
     InvalidSize(String),
 
+    /// This is returned when a procedure's signature is not of the form `(N -> M)`
+    InvalidSignature(String),
+
+    /// This is returned when a struct declaration is not of the form `struct NAME { FIELD, ... }`
+    InvalidStruct(String),
+
+    /// This is returned when a register is defined with a struct that has not been declared
+    StructNotDefined(String),
+
+    /// This is returned when a constant index, such as `buf[9]`, is not
+    /// a whole number inside of the register
+    IndexOutOfBounds(String),
+
+    /// This is returned when a `data` directive is not followed by a
+    /// list of strings and expressions
+    InvalidData(String),
+
+    /// This is returned when a `const` directive is not of the form `const NAME = EXPRESSION`
+    InvalidConstant(String),
+
+    /// This is returned when a procedure's parameters or results are not of the form `(a, b)`
+    InvalidParameters(String),
+
+    /// This is returned when a `break` or `continue` is not inside of a loop
+    BreakOutsideLoop(String),
+
+    /// This is returned when a `break` or `continue` names a label that
+    /// no enclosing loop has
+    UnknownLabel(String),
+
+    /// This is returned when a macro declaration is not of the form
+    /// `macro NAME PARAM ...`, followed by a body and `endmacro`, or when
+    /// the body uses a parameter that the macro does not have
+    InvalidMacro(String),
+
+    /// This is returned when a macro is not given enough arguments, or when
+    /// its body is not valid after the arguments are substituted
+    InvalidMacroExpansion(String),
+
+    /// This is returned when a macro uses itself, directly or through other macros
+    RecursiveMacro(String),
+
+    /// This is returned when the condition of an `#if` is not valid
+    InvalidCondition(String),
+
+    /// This is returned when an `#if` does not have a matching `#endif`,
+    /// or when an `#else` or `#endif` does not have a matching `#if`
+    UnmatchedDirective(String),
+
+    /// This is returned when an `include` directive is not followed by a string
+    InvalidInclude(String),
+
+    /// This is returned when the file named by an `include` directive cannot be found
+    IncludeNotFound(String),
+
+    /// This is returned when a file includes itself, directly or through other files.
+    /// The files in the cycle are listed in the order they are included
+    IncludeCycle(Vec<String>),
+
+    /// This is returned when an error is found in an included file
+    InFile(String, Box<Error>),
+
+    /// This is returned when two procedures are defined with the same name
+    DuplicateProcedure(String),
+
+    /// This is returned when an extern procedure declaration is not of the form
+    /// `extern proc NAME (ARGS -> RETS)`, or when it declares a procedure again
+    /// with a different stack effect. Targets also return this for an extern procedure
+    /// whose name they can't give to a host function, such as a keyword.
+    InvalidExtern(String),
+
+    /// This is returned when a `raw` instruction is not followed by a target name
+    /// and code between triple quotes
+    InvalidRaw(String),
+
+    /// This is returned when a program with raw code for one target is assembled
+    /// for a different target
+    RawTargetMismatch {
+        /// the target the raw code was written for
+        raw: String,
+        /// the target the program is being assembled for
+        target: String,
+    },
+
     /// This is returned when an unknown parser error is returned
     Unknown(String),
 
@@ -82,6 +169,46 @@ pub enum Error {
 
     /// This is returned when there are an odd number of `loop` and `endloop` keywords
     UnmatchedLoop,
+
+    /// This is returned when an `if`, `else`, or `endif` keyword is unmatched
+    /// or improperly nested
+    UnmatchedIf,
+
+    /// This is returned when a procedure annotated with `inline` calls itself,
+    /// directly or through other procedures. Recursion can only be lowered
+    /// by outlining the procedure
+    RecursiveProcedure(String),
+
+    /// This is returned when a procedure annotated with `inline` is referred to
+    /// with `refer_proc`. Only outlined procedures have identifiers
+    ReferredInlineProcedure(String),
+
+    /// This is returned when the lowered program would contain more
+    /// instructions than the code size limit allows
+    CodeSizeLimitExceeded(usize),
+
+    /// This is returned when `stack_size auto` is used, but the stack usage
+    /// of the program cannot be determined statically
+    UnknownStackSize(Unknown),
+
+    /// This is returned for each call of a procedure whose body does not match
+    /// its declared signature, or once if the procedure is never called
+    SignatureMismatch {
+        /// the name of the procedure
+        name: String,
+        /// the signature the procedure was declared with
+        declared: StackEffect,
+        /// the stack effect of the procedure's body
+        actual: StackEffect,
+        /// the procedure with the call, if the procedure is called
+        caller: Option<String>,
+    },
+
+    /// This is returned when more than one error is found at once
+    Multiple(Vec<Error>),
+
+    /// This is returned when warnings are found for lints that are denied
+    DeniedWarnings(Vec<Warning>),
 }
 
 impl Error {
@@ -97,6 +224,23 @@ impl Error {
     pub const INVALID_SIZE: &'static str = "invalid size value";
     pub const NO_PROC_NAME: &'static str = "procedure requires name";
     pub const NO_PROC_FOUND: &'static str = "no procedure found";
+    pub const INVALID_SIGNATURE: &'static str = "invalid signature";
+    pub const INVALID_STRUCT: &'static str = "invalid struct";
+    pub const STRUCT_NOT_DEFINED: &'static str = "struct not defined";
+    pub const INDEX_OUT_OF_BOUNDS: &'static str = "index out of bounds";
+    pub const INVALID_DATA: &'static str = "invalid data";
+    pub const INVALID_CONSTANT: &'static str = "invalid constant";
+    pub const INVALID_PARAMETERS: &'static str = "invalid parameter list";
+    pub const BREAK_OUTSIDE_LOOP: &'static str = "not inside of a loop";
+    pub const UNKNOWN_LABEL: &'static str = "no enclosing loop has label";
+    pub const INVALID_INCLUDE: &'static str = "invalid include";
+    pub const INVALID_MACRO: &'static str = "invalid macro";
+    pub const INVALID_CONDITION: &'static str = "invalid condition";
+    pub const UNMATCHED_DIRECTIVE: &'static str = "unmatched #if, #else, or #endif";
+    pub const INVALID_MACRO_EXPANSION: &'static str = "invalid expansion of macro";
+    pub const RECURSIVE_MACRO: &'static str = "recursive macro";
+    pub const INVALID_RAW: &'static str = "invalid raw code";
+    pub const INVALID_EXTERN: &'static str = "invalid extern procedure";
 }
 
 impl fmt::Display for Error {
@@ -117,16 +261,224 @@ impl fmt::Display for Error {
                 Self::InvalidProcedure(s) => format!("{}: '{}'", Self::INVALID_PROCEDURE, s),
                 Self::NoProcedureName(s) => format!("{}: '{}'", Self::NO_PROC_NAME, s),
                 Self::InvalidSize(s) => format!("{}: '{}'", Self::INVALID_SIZE, s),
+                Self::InvalidSignature(s) => format!("{}: '{}'", Self::INVALID_SIGNATURE, s),
+                Self::InvalidStruct(s) => format!("{}: '{}'", Self::INVALID_STRUCT, s),
+                Self::StructNotDefined(s) => format!("{}: '{}'", Self::STRUCT_NOT_DEFINED, s),
+                Self::IndexOutOfBounds(s) => format!("{}: '{}'", Self::INDEX_OUT_OF_BOUNDS, s),
+                Self::InvalidData(s) => format!("{}: '{}'", Self::INVALID_DATA, s),
+                Self::InvalidConstant(s) => format!("{}: '{}'", Self::INVALID_CONSTANT, s),
+                Self::InvalidParameters(s) => format!("{}: '{}'", Self::INVALID_PARAMETERS, s),
+                Self::BreakOutsideLoop(s) => format!("'{}' is {}", s, Self::BREAK_OUTSIDE_LOOP),
+                Self::UnknownLabel(s) => format!("{}: '{}'", Self::UNKNOWN_LABEL, s),
+                Self::InvalidMacro(s) => format!("{}: '{}'", Self::INVALID_MACRO, s),
+                Self::InvalidMacroExpansion(s) => {
+                    format!("{}: '{}'", Self::INVALID_MACRO_EXPANSION, s)
+                }
+                Self::RecursiveMacro(s) => format!("{}: '{}'", Self::RECURSIVE_MACRO, s),
+                Self::InvalidCondition(s) => format!("{}: '{}'", Self::INVALID_CONDITION, s),
+                Self::UnmatchedDirective(s) => format!("{}: '{}'", Self::UNMATCHED_DIRECTIVE, s),
+                Self::InvalidInclude(s) => format!("{}: '{}'", Self::INVALID_INCLUDE, s),
+                Self::IncludeNotFound(s) => format!("included file not found: '{}'", s),
+                Self::IncludeCycle(files) => format!(
+                    "include cycle: {}",
+                    files
+                        .iter()
+                        .map(|file| format!("'{}'", file))
+                        .collect::<Vec<String>>()
+                        .join(" -> ")
+                ),
+                Self::InFile(file, e) => format!("{}\n  in file '{}'", e, file),
+                Self::DuplicateProcedure(s) => format!("procedure defined more than once: '{}'", s),
+                Self::InvalidExtern(s) => format!("{}: '{}'", Self::INVALID_EXTERN, s),
+                Self::InvalidRaw(s) => format!("{}: '{}'", Self::INVALID_RAW, s),
+                Self::RawTargetMismatch { raw, target } => format!(
+                    "raw code for target '{}' cannot be assembled for target '{}'",
+                    raw, target
+                ),
                 Self::Unknown(_) => "unknown error".to_string(),
                 Self::NoProcedureFound => Self::NO_PROC_FOUND.to_string(),
                 Self::UnmatchedLoop => "unmatched loop".to_string(),
+                Self::UnmatchedIf => "unmatched conditional".to_string(),
+                Self::RecursiveProcedure(s) =>
+                    format!("recursive procedure marked inline: '{}'", s),
+                Self::ReferredInlineProcedure(s) => {
+                    format!("procedure marked inline is referred to: '{}'", s)
+                }
+                Self::CodeSizeLimitExceeded(n) => {
+                    format!(
+                        "code size limit exceeded: program is larger than {} instructions",
+                        n
+                    )
+                }
+                Self::UnknownStackSize(u) => format!("cannot determine stack size: {}", u),
+                Self::SignatureMismatch {
+                    name,
+                    declared,
+                    actual,
+                    caller,
+                } => {
+                    let message = format!(
+                        "procedure '{}' is declared as {}, but its body has the stack effect {}",
+                        name, declared, actual
+                    );
+                    match caller {
+                        Some(caller) => {
+                            format!("{}\n  called from procedure '{}'", message, caller)
+                        }
+                        None => message,
+                    }
+                }
+                Self::Multiple(errors) => errors
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Self::DeniedWarnings(warnings) => warnings
+                    .iter()
+                    .map(|w| format!("{} [{}]", w, w.lint()))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
             }
         )
     }
 }
 
+/// The Warning type is used for problems found while assembling
+/// that do not stop an assembly file from being assembled
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Warning {
+    /// This is returned when a loop in a procedure does not leave the stack
+    /// at the same depth on every iteration
+    UnbalancedLoop(String),
+
+    /// This is returned when the two branches of a conditional in a procedure
+    /// do not leave the stack at the same depth
+    UnbalancedBranch(String),
+
+    /// This is returned when the stack size is smaller than the
+    /// largest number of cells the program will have on its stack
+    StackSizeTooSmall {
+        /// the stack size of the program
+        declared: usize,
+        /// the number of cells the program needs
+        required: usize,
+    },
+
+    /// This is returned when the entry point pops cells off of an empty stack
+    StackUnderflow(usize),
+
+    /// This is returned when a procedure is never called from the entry point
+    UnreachableProcedure(String),
+
+    /// This is returned when a register is defined, but never read
+    UnusedRegister(String),
+
+    /// This is returned when a register is read before anything is stored in it
+    UninitializedRegister(String),
+
+    /// This is returned when a register is used with `alloc`, but never with `free`
+    UnfreedAlloc(String),
+
+    /// This is returned when a procedure contains a `pop` immediately after a `push`
+    PushPop(String),
+
+    /// This is returned when a procedure contains a loop whose body cannot
+    /// change the loop's test value
+    ConstantLoop(String),
+}
+
+impl Warning {
+    /// Get the lint that this warning belongs to
+    pub fn lint(&self) -> Lint {
+        match self {
+            Self::UnbalancedLoop(_) => Lint::UnbalancedLoop,
+            Self::UnbalancedBranch(_) => Lint::UnbalancedBranch,
+            Self::StackSizeTooSmall { .. } => Lint::StackSize,
+            Self::StackUnderflow(_) => Lint::StackUnderflow,
+            Self::UnreachableProcedure(_) => Lint::UnreachableProcedure,
+            Self::UnusedRegister(_) => Lint::UnusedRegister,
+            Self::UninitializedRegister(_) => Lint::UninitializedRegister,
+            Self::UnfreedAlloc(_) => Lint::UnfreedAlloc,
+            Self::PushPop(_) => Lint::PushPop,
+            Self::ConstantLoop(_) => Lint::ConstantLoop,
+        }
+    }
+
+    /// Get the name of the procedure this warning was found in, if it belongs to one
+    pub fn procedure(&self) -> Option<&str> {
+        match self {
+            Self::UnbalancedLoop(s)
+            | Self::UnbalancedBranch(s)
+            | Self::UnreachableProcedure(s)
+            | Self::PushPop(s)
+            | Self::ConstantLoop(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> core::result::Result<(), fmt::Error> {
+        match self {
+            Self::UnbalancedLoop(s) => {
+                write!(f, "a loop in procedure '{}' is not stack balanced", s)
+            }
+            Self::UnbalancedBranch(s) => {
+                write!(
+                    f,
+                    "a conditional in procedure '{}' is not stack balanced",
+                    s
+                )
+            }
+            Self::StackSizeTooSmall { declared, required } => write!(
+                f,
+                "stack size is too small: {} cells declared, but {} cells are used",
+                declared, required
+            ),
+            Self::StackUnderflow(n) => {
+                write!(
+                    f,
+                    "stack underflow: the entry point pops {} more cells than it pushes",
+                    n
+                )
+            }
+            Self::UnreachableProcedure(s) => write!(f, "procedure '{}' is never called", s),
+            Self::UnusedRegister(s) => write!(f, "register '{}' is never read", s),
+            Self::UninitializedRegister(s) => {
+                write!(
+                    f,
+                    "register '{}' is read before anything is stored in it",
+                    s
+                )
+            }
+            Self::UnfreedAlloc(s) => {
+                write!(f, "memory allocated in register '{}' is never freed", s)
+            }
+            Self::PushPop(s) => write!(
+                f,
+                "a value is pushed and immediately popped in procedure '{}'",
+                s
+            ),
+            Self::ConstantLoop(s) => {
+                write!(
+                    f,
+                    "a loop in procedure '{}' cannot change its test value",
+                    s
+                )
+            }
+        }
+    }
+}
+
 impl<'a> From<VerboseError<&'a str>> for Error {
     fn from(mut e: VerboseError<&'a str>) -> Self {
+        // An index out of bounds is reported instead of the operand it is in,
+        // because the operand would be valid with a different index
+        let index = VerboseErrorKind::Context(Self::INDEX_OUT_OF_BOUNDS);
+        if let Some((input, _)) = e.errors.iter().find(|(_, kind)| *kind == index) {
+            return Self::IndexOutOfBounds(first(input));
+        }
+
         let mut result = Self::Unknown(String::new());
         e.errors.reverse();
         for (input, err_kind) in &e.errors {
@@ -143,6 +495,23 @@ impl<'a> From<VerboseError<&'a str>> for Error {
                     Self::INVALID_IDENTIFIER => Self::InvalidIdentifer(e),
                     Self::INVALID_PROCEDURE => Self::InvalidProcedure(e),
                     Self::INVALID_SIZE => Self::InvalidSize(e),
+                    Self::INVALID_SIGNATURE => Self::InvalidSignature(e),
+                    Self::INVALID_STRUCT => Self::InvalidStruct(e),
+                    Self::STRUCT_NOT_DEFINED => Self::StructNotDefined(e),
+                    Self::INDEX_OUT_OF_BOUNDS => Self::IndexOutOfBounds(e),
+                    Self::INVALID_DATA => Self::InvalidData(e),
+                    Self::INVALID_CONSTANT => Self::InvalidConstant(e),
+                    Self::INVALID_PARAMETERS => Self::InvalidParameters(e),
+                    Self::BREAK_OUTSIDE_LOOP => Self::BreakOutsideLoop(e),
+                    Self::UNKNOWN_LABEL => Self::UnknownLabel(e),
+                    Self::INVALID_INCLUDE => Self::InvalidInclude(e),
+                    Self::INVALID_MACRO => Self::InvalidMacro(e),
+                    Self::INVALID_CONDITION => Self::InvalidCondition(e),
+                    Self::UNMATCHED_DIRECTIVE => Self::UnmatchedDirective(e),
+                    Self::INVALID_MACRO_EXPANSION => Self::InvalidMacroExpansion(e),
+                    Self::RECURSIVE_MACRO => Self::RecursiveMacro(e),
+                    Self::INVALID_RAW => Self::InvalidRaw(e),
+                    Self::INVALID_EXTERN => Self::InvalidExtern(e),
                     Self::NO_PROC_NAME => Self::NoProcedureName(e),
                     Self::NO_PROC_FOUND => Self::NoProcedureFound,
                     other => Self::Unknown(format!("{:?}", other)),
@@ -0,0 +1,61 @@
+//! # include, the module responsible for finding the files a program includes
+//!
+//! lasm itself does not use a filesystem, so that it can be used without
+//! the standard library. Instead, the files a program includes with the
+//! `include` directive are found by a `Loader`. The binary implements
+//! `Loader` using the filesystem, and a map from file names to contents
+//! can be used as a loader for programs that live in memory.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// This trait should be implemented for a struct that finds the
+/// files included by an assembly file.
+pub trait Loader {
+    /// Find the file that `path` refers to when it is included from the file named
+    /// `from`, and return the name and contents of the file. The name is used to
+    /// identify the file, so every path that refers to the same file must give the
+    /// same name. If the file cannot be found, `None` is returned.
+    fn load(&self, from: &str, path: &str) -> Option<(String, String)>;
+}
+
+/// A map from file names to their contents is a loader. Paths are resolved
+/// relative to the directory of the including file, and then relative to the root.
+impl Loader for BTreeMap<String, String> {
+    fn load(&self, from: &str, path: &str) -> Option<(String, String)> {
+        [relative(from, path), normalize(path)]
+            .iter()
+            .find_map(|name| Some((name.clone(), self.get(name)?.clone())))
+    }
+}
+
+/// Get the name of the file that `path` refers to from the file named `from`
+pub fn relative(from: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return normalize(path);
+    }
+    match from.rfind('/') {
+        Some(i) => normalize(&format!("{}/{}", &from[..i], path)),
+        None => normalize(path),
+    }
+}
+
+/// Remove the `.` and `..` parts of a path separated by `/`
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "." => {}
+            ".." => match parts.last() {
+                // The parent of the root directory is the root directory
+                Some(&"") => {}
+                Some(&"..") | None => parts.push(".."),
+                Some(_) => {
+                    parts.pop();
+                }
+            },
+            "" if !parts.is_empty() => {}
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
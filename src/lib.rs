@@ -26,6 +26,293 @@
 //! assembly is parsed, the procedures are each defined before they are checked
 //! for semantic errors. So, procedures can be defined in any order.
 //!
+//! Procedures are either inlined at each `call`, or outlined as a subroutine
+//! in the output code. The assembler outlines a procedure when its size multiplied
+//! by the number of times it is called exceeds `Options::outline_threshold`, when
+//! the procedure is recursive, or when it is referred to with `refer_proc`. This choice
+//! can be overridden by annotating the procedure.
+//!
+//! ```rust,ignore,no_run
+//! // always inlined
+//! inline proc nl
+//!     push 10 outc
+//! endproc
+//!
+//! // always outlined
+//! noinline proc print
+//!     dup loop outc dup endloop pop
+//! endproc
+//! ```
+//!
+//! Registers and parameters are not saved when a procedure is called, so every call
+//! of a recursive procedure shares them. A recursive call overwrites the registers and
+//! parameters of the call that made it, so values that are still needed after the
+//! recursive call should be kept on the stack instead.
+//!
+//! A procedure can also be declared with a signature: the number of cells it pops
+//! and the number of cells it pushes. The assembler checks the signature against the
+//! stack effect of the procedure's body, and reports an error at every call of the
+//! procedure if they do not match. Callers are analyzed using the signature instead of
+//! the body.
+//!
+//! ```rust,ignore,no_run
+//! proc less (2 -> 1)
+//!     cmp push 1 add call not
+//! endproc
+//! ```
+//!
+//! A procedure can name its parameters and results. Each name is a register that can
+//! only be used inside of the procedure. When the procedure is called, its arguments are
+//! stored in its parameters, with the first parameter getting the deepest argument. When
+//! the procedure returns, its results are pushed in order.
+//!
+//! ```rust,ignore,no_run
+//! // `push 3 push 1 call sub_pair` pushes 2
+//! proc sub_pair (a, b) -> (r)
+//!     ld b ld a sub st r
+//! endproc
+//! ```
+//!
+//! A procedure can be called through a pointer. `refer_proc NAME` pushes the identifier
+//! of the procedure NAME, and `call_ind` pops an identifier and calls its procedure, which
+//! stops the program if no procedure has the identifier. A procedure that is referred to
+//! is always outlined, so it cannot be annotated with `inline`. The stack effect of
+//! `call_ind` is not known, so procedures that use it should be declared with signatures.
+//!
+//! ```rust,ignore,no_run
+//! proc double (1 -> 1)
+//!     push 2 mul
+//! endproc
+//!
+//! proc apply (2 -> 1)
+//!     call_ind
+//! endproc
+//!
+//! // prints 10
+//! proc start
+//!     push 5 refer_proc double call apply outn
+//! endproc
+//! ```
+//!
+//! Because inlining can make the output very large, the number of instructions
+//! in the assembled program is capped by `Options::code_size_limit`.
+//!
+//! ### constants
+//!
+//! Constants are defined with `const NAME = EXPRESSION` before or between procedures,
+//! and can be used after they are defined. Anywhere a literal or a size is expected,
+//! such as in `push`, `define`, or `stack_size`, an expression can be used instead.
+//! Expressions are evaluated when the assembly code is parsed, and can use numbers,
+//! characters, constants, `sizeof(REGISTER)`, parentheses, and the operators
+//! `+`, `-`, `*`, `/`, and `%`.
+//!
+//! ```rust,ignore,no_run
+//! const BUF_LEN = 16
+//! stack_size BUF_LEN * 4 + 64
+//!
+//! proc start
+//!     define buf, BUF_LEN
+//!     push sizeof(buf) - 1
+//!     push 'a' + 1 outc
+//! endproc
+//! ```
+//!
+//! ### macros
+//!
+//! A macro is a snippet of code that is copied into a procedure everywhere it is used,
+//! instead of being called. Macros are declared with `macro NAME PARAM ...` before or
+//! between procedures, with the parameters on the same line as the name, and end with
+//! `endmacro`. A macro is used by writing its name followed by an argument for each
+//! parameter on the same line, and every `$PARAM` in its body is replaced with the
+//! argument. A `$` that is not followed by the name of a parameter is left as it is.
+//! Because the arguments are substituted before the body is parsed, they can be
+//! registers, literals, or the names of procedures.
+//!
+//! ```rust,ignore,no_run
+//! macro incr r
+//!     ld $r push 1 add st $r
+//! endmacro
+//!
+//! proc start
+//!     define counter, 1
+//!     push 0 st counter
+//!     incr counter
+//! endproc
+//! ```
+//!
+//! ### structs
+//!
+//! A struct declares the layout of a register with several cells. Structs are declared
+//! with `struct NAME { FIELD, ... }` before or between procedures. Each field occupies
+//! a single cell, unless it is declared with a struct, as in `pos: Vec2`, or with a
+//! size, as in `name: 16`. A register is defined with a struct using `define NAME: STRUCT`,
+//! and its fields can be used anywhere a register is expected.
+//!
+//! ```rust,ignore,no_run
+//! struct Vec2 { x, y }
+//! struct Body { pos: Vec2, vel: Vec2, mass }
+//!
+//! proc start
+//!     define b: Body
+//!     push 1 st b.pos.x
+//!     ld b.vel.y outn
+//!     refer b.mass
+//! endproc
+//! ```
+//!
+//! ### indexing
+//!
+//! A single cell of a register can be used anywhere a register is expected by
+//! following the register with a constant index, as in `buf[3]`. The index can be any
+//! expression, and the assembler returns `Error::IndexOutOfBounds` if it is not a whole
+//! number inside of the register. To use an index computed at runtime, `ld_idx` and
+//! `st_idx` pop the index off of the stack. These indices are only checked when the
+//! program is assembled with bounds checks.
+//!
+//! ```rust,ignore,no_run
+//! proc start
+//!     define buf, 4
+//!     push 1 st buf[0]
+//!     push 2 push 3 st_idx buf
+//!     push 3 ld_idx buf outn
+//! endproc
+//! ```
+//!
+//! ### data
+//!
+//! A register can be defined with initial contents using `data NAME ITEMS`, where ITEMS
+//! is a comma separated list of strings and expressions. Each string is stored as its
+//! characters followed by a zero, and each expression is stored as a single cell. The
+//! register is as large as its contents, and is filled before the entry point runs.
+//!
+//! ```rust,ignore,no_run
+//! proc start
+//!     data greeting "Hello world!\n"
+//!     data table 1, 2, 3
+//!     refer greeting call cprint
+//! endproc
+//! ```
+//!
+//! ### includes
+//!
+//! A program can be split across several files with `include "PATH"`, which can be
+//! written before and between procedures. The procedures, constants, and structs of
+//! the included file are added to the program. Paths are resolved relative to the
+//! including file, and then relative to the directories given with the `-I` flag.
+//! Each file is only included once, so two files can include the same file, but a
+//! file cannot include itself. Procedure names must be unique across every file.
+//!
+//! ```rust,ignore,no_run
+//! include "util.lasm"
+//!
+//! proc start
+//!     push 5 call print_line
+//! endproc
+//! ```
+//!
+//! When lasm is used as a library, included files are found with a `Loader`,
+//! which is passed to `assemble_file`. Warnings in the procedures of included
+//! files are reported like the warnings in the file being assembled.
+//!
+//! ### conditional compilation
+//!
+//! Code between `#if CONDITION` and `#endif` is only assembled if the condition holds,
+//! and code between an optional `#else` and the `#endif` is only assembled if it does
+//! not. Conditionals can be written around procedures and directives, or around
+//! instructions inside of a procedure, and the code that is not assembled is not parsed.
+//!
+//! | Condition | Holds when |
+//! |-----------|------------|
+//! | `target == "NAME"` | The program is assembled for the target named NAME, such as `"c"` |
+//! | `target != "NAME"` | The program is not assembled for the target named NAME |
+//! | `feature(NAME)` | The feature NAME is enabled with `-F NAME` or `Options::features` |
+//! | `defined(NAME)` | NAME is a constant |
+//! | `EXPRESSION` | The expression is not zero. Names that are not constants are zero |
+//! | `EXPRESSION OP EXPRESSION` | The comparison holds, where OP is `==`, `!=`, `<`, `<=`, `>`, or `>=` |
+//! | `!CONDITION` | The condition does not hold |
+//!
+//! Conditions can be combined with `&&` and `||`. The condition is the rest of the
+//! line after `#if`, so the code that is assembled starts on the next line. Constants
+//! can be defined from the command line with `-D NAME=VALUE`, or with `Options::defines`,
+//! before the program is parsed. `lasm check` uses `-D` to deny lints, so it takes
+//! `-d NAME=VALUE` instead.
+//!
+//! ```rust,ignore,no_run
+//! proc start
+//! #if DEBUG
+//!     push "starting" call print call nl
+//! #endif
+//! #if target == "c"
+//!     call c_shim
+//! #endif
+//! endproc
+//! ```
+//!
+//! ### standard library
+//!
+//! lasm comes with a standard library of common procedures, which is included with
+//! `include std:MODULE`. The modules are `std:io`, `std:cmp`, `std:string`, and
+//! `std:mem`. The procedures in each module are listed in the `stdlib` module.
+//! Warnings in the procedures of the standard library are not reported.
+//!
+//! ```rust,ignore,no_run
+//! include std:io
+//!
+//! proc start
+//!     push "Hello world!" call print call nl
+//! endproc
+//! ```
+//!
+//! ### extern procedures
+//!
+//! A procedure implemented by the host of a target, such as a math library or a
+//! runtime, is declared with `extern proc NAME (ARGS -> RETS)`, anywhere in the program.
+//! `call NAME` pops ARGS cells as the arguments, the deepest cell being the first
+//! argument, and pushes the RETS cells that the host returns. The stack effect is
+//! checked like the signature of any other procedure.
+//!
+//! The C target declares a host function for each extern procedure, which takes the
+//! arguments as `double`s. It returns `double` for a single result, and `void` otherwise.
+//! More than one result is written to a `double rets[]` array passed after the arguments,
+//! in the order they are pushed. The output code must be linked with the host functions.
+//! An extern procedure can't be named after a C keyword or a function of the runtime,
+//! such as `add` or `main`, or start with `lasm_`. It also can't be named after something
+//! declared by the C standard headers, such as `abs` or `rand`, except for the functions
+//! of `math.h` that take and return `double`s, such as `hypot`, if it has the same
+//! number of arguments and one result.
+//!
+//! ```rust,ignore,no_run
+//! extern proc hypot (2 -> 1)
+//!
+//! proc start
+//!     push 3 push 4 call hypot outn // prints 5
+//! endproc
+//! ```
+//!
+//! ### raw code
+//!
+//! Code that lasm cannot express, such as a call to a library of the host language,
+//! can be written in the language of a target with `raw TARGET """CODE"""` inside of a
+//! procedure. The target named TARGET emits CODE verbatim, and every other target
+//! refuses to assemble the program. Comments inside of CODE are kept, and the stack
+//! analysis assumes that CODE leaves the stack as it found it. Raw code is usually
+//! written inside of an `#if target == "NAME"`, so that a program can still be assembled
+//! for other targets.
+//!
+//! ```rust,ignore,no_run
+//! proc start
+//!     push 2 sqrt
+//! #if target == "c"
+//!     raw c """
+//!     pop_cell(tape, ACC);
+//!     printf("%.3f\n", tape[ACC]);
+//!     """
+//! #else
+//!     outn push 10 outc
+//! #endif
+//! endproc
+//! ```
+//!
 //! ### portability
 //!
 //! The final, and best feature is portability. lasm is _extremely_ compact:
@@ -38,17 +325,29 @@
 //!
 //! | Stack Instruction | Description |
 //! |-------------------|-------------|
-//! | `push LITERAL` | Push the LITERAL argument onto the stack. The LITERAL argument MUST be a character or float |
+//! | `push LITERAL` | Push the LITERAL argument onto the stack. The LITERAL argument MUST be a character, a float, or an expression |
+//! | `push "STRING"` | Push a zero, and then the characters of STRING in reverse, so that the first character is on top of the stack |
 //! | `pop` | Pop a value off of the stack and into the ACC register |
 //! | `ld REGISTER` | Push the value stored in REGISTER onto the stack. The REGISTER being loaded MUST be defined before being loaded |
 //! | `st REGISTER` | Pop a value off of the stack into REGISTER. The REGISTER being stored to MUST be declared before being stored |
 //! | `dup` | Duplicate the top item on the stack |
+//! | `swap` | Swap the top two items on the stack |
+//! | `over` | Push a copy of the second item on the stack |
+//! | `rot` | Move the third item on the stack to the top |
+//! | `pick N` | Push a copy of the item N cells below the top of the stack. `pick 0` is `dup`, and `pick 1` is `over` |
+//! | `drop N` | Pop N cells off of the stack. The last cell dropped is left in the ACC register |
 //!
 //! | Pointer Instruction | Description |
 //! |---------------------|-------------|
 //! | `refer REGISTER` | Push a pointer to REGISTER onto the stack |
 //! | `deref_ld` | Pop a pointer off of the stack, and push the value stored at where the pointer points. This will only push a single cell onto the stack, not more than one cell |
 //! | `deref_st` | Pop a pointer and a cell off of the stack, and store the cell at the pointer |
+//! | `deref_ld_n N` | Pop a pointer off of the stack, and push the N cells starting at where the pointer points |
+//! | `deref_st_n N` | Pop a pointer and N cells off of the stack, and store the cells starting at the pointer. The top cell is stored last, like `st` |
+//! | `memcpy` | Pop a destination pointer, a source pointer, and a LENGTH off of the stack, and copy LENGTH cells from the source to the destination |
+//! | `memset` | Pop a destination pointer, a value, and a LENGTH off of the stack, and store the value in LENGTH cells starting at the destination |
+//! | `ld_idx REGISTER` | Pop an INDEX off of the stack, and push the cell at INDEX in REGISTER |
+//! | `st_idx REGISTER` | Pop an INDEX and a cell off of the stack, and store the cell at INDEX in REGISTER |
 //! | `alloc REGISTER` | Pop a SIZE value off of the stack, and store the address to SIZE free cells in REGISTER |
 //! | `free REGISTER` | Pop a SIZE value off of the stack, and free the memory stored at the pointer stored in REGISTER |
 //!
@@ -59,19 +358,88 @@
 //! | `sub` | Pop two cells off of the stack, and push the first minus the second |
 //! | `div` | Pop two cells off of the stack, and push their product |
 //! | `mul` | Pop two cells off of the stack, and push the first divided by the second |
-//! | `cmp` | Pop two cells off of the stack, and push -1 if the first is less than the second, 0 if they are equal, and 1 otherwise |
+//! | `cmp` | Pop two cells off of the stack, and push -1 if the first is less than the second, 0 if they are equal, 1 if the first is greater, and NaN if either is NaN |
+//! | `mod` | Pop two cells off of the stack, and push the remainder of the first divided by the second. The remainder has the sign of the first cell. If the second cell is zero, or the first is infinite, the remainder is NaN |
+//! | `pow` | Pop two cells off of the stack, and push the first raised to the power of the second. A negative number raised to a power that is not an integer is NaN |
+//! | `neg` | Pop a cell off of the stack, and push its negation |
+//! | `abs` | Pop a cell off of the stack, and push its absolute value |
+//! | `floor` | Pop a cell off of the stack, and push the largest integer not greater than it |
+//! | `ceil` | Pop a cell off of the stack, and push the smallest integer not less than it |
+//! | `trunc` | Pop a cell off of the stack, and push it rounded toward zero |
+//! | `sqrt` | Pop a cell off of the stack, and push its square root. The square root of a negative number is NaN |
+//!
+//! `floor`, `ceil`, and `trunc` push infinities and NaN unchanged. The C target implements
+//! these instructions with `<math.h>`, so its output must be linked with the math library.
+//!
+//! | Comparison Instruction | Description |
+//! |------------------------|-------------|
+//! | `lt` | Pop two cells off of the stack, and push 1 if the first is less than the second, and 0 otherwise |
+//! | `le` | Pop two cells off of the stack, and push 1 if the first is less than or equal to the second, and 0 otherwise |
+//! | `gt` | Pop two cells off of the stack, and push 1 if the first is greater than the second, and 0 otherwise |
+//! | `ge` | Pop two cells off of the stack, and push 1 if the first is greater than or equal to the second, and 0 otherwise |
+//! | `eq` | Pop two cells off of the stack, and push 1 if they are equal, and 0 otherwise |
+//! | `ne` | Pop two cells off of the stack, and push 1 if they are not equal, and 0 otherwise |
+//!
+//! If either cell is NaN, every comparison pushes 0, except for `ne`, which pushes 1.
+//!
+//! | Bitwise Instruction | Description |
+//! |---------------------|-------------|
+//! | `band` | Pop two cells off of the stack, and push their bitwise and |
+//! | `bor` | Pop two cells off of the stack, and push their bitwise or |
+//! | `bxor` | Pop two cells off of the stack, and push their bitwise exclusive or |
+//! | `bnot` | Pop a cell off of the stack, and push its bitwise complement |
+//! | `shl` | Pop two cells off of the stack, and push the first shifted left by the second |
+//! | `shr` | Pop two cells off of the stack, and push the first shifted right by the second, keeping its sign |
+//! | `and` | Pop two cells off of the stack, and push 1 if both are true, and 0 otherwise |
+//! | `or` | Pop two cells off of the stack, and push 1 if either is true, and 0 otherwise |
+//! | `not` | Pop a cell off of the stack, and push 1 if it is false, and 0 otherwise |
+//!
+//! The bitwise and logical instructions convert cells to 64 bit integers by rounding
+//! toward zero. NaN is converted to 0, and cells too large or small for a 64 bit integer
+//! are converted to the largest or smallest 64 bit integer. Shifts only use the lowest
+//! 6 bits of the shift amount. A cell is true if its integer conversion is not zero,
+//! which is also how `loop` and `if` test their values.
 //!
 //! | IO Instruction | Description |
 //! |----------------|-------------|
 //! | `outc` | Pop a cell off of the stack and print it as a character |
-//! | `outn` | Pop a cell off of the stack and print it as a float |
+//! | `outn` | Pop a cell off of the stack and print it as a float, in the form of C's `%G` format: with six significant digits, and `INF`, `-INF` or `NAN` for infinities and NaN |
 //! | `inc` | Get a character from STDIN and push it into the stack |
 //! | `inn` | Get a float from STDIN and push it into the stack |
 //!
 //! | Control Instruction | Description |
 //! |---------------------|-------------|
 //! | `loop` | Marks the start of a loop. At the start of each iteration, a test value is popped from the stack. While the value is not zero, the loop continues. Else, the loop jumps to the matching `endloop` |
+//! | `loop LABEL` | Identical to `loop`, but gives the loop a LABEL that `break` and `continue` can refer to |
 //! | `endloop` | Marks the end of a loop |
+//! | `while` | Marks the start of a loop whose test value is computed by the instructions between `while` and `do`, which run before every iteration. `while LABEL` gives the loop a LABEL |
+//! | `do` | Pop the test value of a `while` loop. If the value is not zero, execute the loop's body. Else, jump past the matching `endwhile` |
+//! | `endwhile` | Marks the end of a `while` loop, and jumps back to its `while` |
+//! | `break` | Jump past the end of the innermost loop. `break LABEL` leaves the loop with that LABEL, and every loop inside of it |
+//! | `continue` | Jump to the `endloop` of the innermost loop, which pops the next test value, or to the `while` of a `while` loop. `continue LABEL` continues the loop with that LABEL |
+//! | `if` | Pop a test value off of the stack. If the value is not zero, execute the instructions up to the matching `else` or `endif`. Else, jump to the matching `else` or `endif` |
+//! | `else` | Marks the start of the instructions executed when the test value of the matching `if` is zero |
+//! | `endif` | Marks the end of a conditional |
+//! | `refer_proc NAME` | Push the identifier of the procedure NAME onto the stack |
+//! | `call_ind` | Pop the identifier of a procedure off of the stack, and call the procedure |
+//!
+//! ### stack analysis
+//!
+//! Every instruction has a fixed stack effect, so the assembler checks that every
+//! `loop` leaves the stack as deep as it found it, and computes the largest number
+//! of cells the program will have on its stack. A warning is given if the stack size
+//! is smaller than that. Loops that are not stack balanced, such as loops that
+//! consume a string from the stack, are reported as warnings too. A `break` must
+//! leave the stack as deep as it was after the loop popped its test value, and a
+//! `continue` must leave the next test value on top of that. The condition of a
+//! `while` loop must push exactly one test value.
+//!
+//! ### linting
+//!
+//! The `lint` module finds likely bugs that are not errors, such as registers that are
+//! never read or procedures that are never called. Each lint can be allowed, warned
+//! about, or denied with `Options::lints`, or with `lasm check -A LINT -W LINT -D LINT`.
+//! `lasm check -D warnings` turns every warning into an error.
 //!
 //! # examples
 //!
@@ -80,10 +448,10 @@
 //! As a result, examples are pretty simple.
 //!
 //! ## fibonacci
-//! 
+//!
 //! This simply implements fibonacci by doing arithmetic on three variables `a`, `b`, and `c`.
 //! To simplify outputing the numbers, a few helper procedures are defined.
-//! 
+//!
 //! ```rust,ignore,no_run
 //! // comments are C-style
 //! // The `stack_size` flag can ONLY be used at the top of the file.
@@ -93,6 +461,10 @@
 //! // pushes, allocs, etc. require a bit of memory on the stack.
 //!
 //! // If this flag is not present, 256 cells are used by default.
+//! // With `stack_size auto`, the assembler computes the largest number
+//! // of cells the program ever has on its stack, and uses that instead.
+//! // If the program uses `alloc`, `Options::heap_size` cells (256 by
+//! // default) are added for the heap, which shares memory with the stack.
 //! stack_size 1024
 //!
 //! // The start procedure is the entry point
@@ -167,15 +539,15 @@
 //! ```
 //!
 //! # implementation
-//! 
+//!
 //! lasm's implementation is very simple: there are very few instructions to implement
 //! when targeting a new programming language. Additionally, lasm's structure is very
 //! simple to implement in low level languages.
-//! 
+//!
 //! There are a few **very** important notes for lasm's implementation
 //! 1. lasm's memory is implemented using an array of double precision floats, or 64 bit floats
 //! 2. lasm tracks allocs and frees for each individual cell of the memory array. This is most
-//! simply done using an array of booleans with identical length to the data tape
+//!    simply done using an array of booleans with identical length to the data tape
 //! 3. allocating more than the available amount of memory is undefined behavior (if possible, this should cause the program to exit)
 //! 4. the implementation should _always_ mark memory reserved for registers as allocated (so that alloc may not return a pointer to register memory)
 //! 5. memory reserved for registers always lies **immediately** before the stack
@@ -191,61 +563,281 @@ extern crate alloc;
 extern crate lazy_static;
 
 pub mod asm;
-pub use asm::{Instruct, Register};
+pub use asm::{Instruct, Program, Register, StackEffect};
+pub mod analysis;
+use analysis::{Analysis, Usage};
 pub(crate) mod ast;
 pub mod target;
 pub use target::Target;
 pub mod error;
-pub use error::{Error, Result};
+pub use error::{Error, Result, Warning};
+pub mod include;
+pub mod lint;
+pub mod stdlib;
+pub use include::Loader;
 pub(crate) mod parser;
-pub(crate) use parser::program;
+pub(crate) use parser::{program, strip_comments};
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+
+/// Options controls how an assembly file is assembled. The default
+/// options are used by the `assemble` function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// The maximum number of instructions the lowered program may contain.
+    /// Exceeding this limit returns `Error::CodeSizeLimitExceeded`.
+    pub code_size_limit: usize,
 
-use alloc::{string::String, vec::Vec, collections::BTreeMap};
+    /// A procedure without an `inline` or `noinline` annotation is outlined
+    /// as a subroutine when its size multiplied by the number of times it is
+    /// called exceeds this threshold.
+    pub outline_threshold: usize,
+
+    /// The levels of the lints. Warnings for allowed lints are discarded, and
+    /// warnings for denied lints return `Error::DeniedWarnings`.
+    pub lints: lint::Config,
+
+    /// The number of cells reserved for `alloc` when the stack size is computed
+    /// with `stack_size auto`. The heap is allocated from the same memory as the
+    /// stack, and its size cannot be computed, so this many cells are added to the
+    /// computed stack size if the program uses `alloc`.
+    pub heap_size: usize,
+
+    /// If this is set, `ld_idx` and `st_idx` check that their indices are inside
+    /// of their registers at runtime, and stop the program if they are not.
+    pub bounds_checks: bool,
+
+    /// Constants that are defined before the program is parsed, such as the
+    /// ones given with `-D NAME=VALUE` on the command line.
+    pub defines: BTreeMap<String, f64>,
+
+    /// The features enabled for the program, which are tested with `#if feature(NAME)`.
+    pub features: BTreeSet<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            code_size_limit: asm::DEFAULT_CODE_SIZE_LIMIT,
+            outline_threshold: asm::DEFAULT_OUTLINE_THRESHOLD,
+            heap_size: asm::DEFAULT_HEAP_SIZE,
+            lints: lint::Config::default(),
+            bounds_checks: false,
+            defines: BTreeMap::new(),
+            features: BTreeSet::new(),
+        }
+    }
+}
 
 /// assemble takes an assembly target, and the assembly code an object that implements display
-/// 
-/// This function will 
+///
+/// This function will
 /// 1. parse the assembly code and convert it into an abstract syntax tree
 /// 2. convert the abstract syntax tree into a list of executable assembly instructions
 /// 3. transform the list of assembly instructions into output code using the assembly target
 pub fn assemble(target: impl Target, asm_code: impl core::fmt::Display) -> Result<String> {
-    use asm::{REGISTER_POINTER, NAMED_REGISTERS, PREDEFINED_REGISTERS};
+    let (code, _) = assemble_with(target, asm_code, &Options::default())?;
+    Ok(code)
+}
+
+/// assemble_with is identical to `assemble`, but uses the given options
+/// instead of the default options. Along with the output code, the warnings
+/// found while assembling are returned.
+pub fn assemble_with(
+    target: impl Target,
+    asm_code: impl core::fmt::Display,
+    options: &Options,
+) -> Result<(String, Vec<Warning>)> {
+    assemble_file(target, "", asm_code, &BTreeMap::new(), options)
+}
 
-    let (code, stack_size) = compile(asm_code)?;
-    let initial_stack_ptr = *REGISTER_POINTER.lock();
+/// assemble_file is identical to `assemble_with`, but the assembly code can include
+/// other files. `name` is the name of the file the assembly code was read from,
+/// and the files it includes are found using the loader.
+pub fn assemble_file(
+    target: impl Target,
+    name: &str,
+    asm_code: impl core::fmt::Display,
+    loader: &dyn Loader,
+    options: &Options,
+) -> Result<(String, Vec<Warning>)> {
+    use asm::{NAMED_REGISTERS, PREDEFINED_REGISTERS, REGISTER_POINTER};
+
+    let result = compile(target.name(), name, asm_code, loader, options);
 
     // Reset the global variables used for assembling an input file
     *REGISTER_POINTER.lock() = PREDEFINED_REGISTERS;
     *NAMED_REGISTERS.lock() = BTreeMap::new();
-    
+
     // Assemble using the targets assembly method
-    Ok(target.assemble(initial_stack_ptr, stack_size, code))
+    let (program, warnings) = result?;
+    Ok((target.assemble(program)?, warnings))
 }
 
+/// check assembles the assembly code without producing output code, and returns
+/// the warnings found while assembling. The code is checked as it would be
+/// assembled for the C target.
+pub fn check(asm_code: impl core::fmt::Display, options: &Options) -> Result<Vec<Warning>> {
+    check_file(target::C, "", asm_code, &BTreeMap::new(), options)
+}
 
-fn compile(s: impl core::fmt::Display) -> Result<(Vec<Instruct>, usize)> {
-    let (ast, stack_size) = program(
-        &comment::c::strip(s)
-            .unwrap()
-            .split_whitespace()
-            .collect::<Vec<&str>>()
-            .join(" "),
-    )?;
+/// check_file is identical to `check`, but the code is checked as it would be assembled
+/// for the given target, and can include other files in the same way as `assemble_file`.
+pub fn check_file(
+    target: impl Target,
+    name: &str,
+    asm_code: impl core::fmt::Display,
+    loader: &dyn Loader,
+    options: &Options,
+) -> Result<Vec<Warning>> {
+    use asm::{NAMED_REGISTERS, PREDEFINED_REGISTERS, REGISTER_POINTER};
 
-    let code = ast.lower()?;
+    let result = compile(target.name(), name, asm_code, loader, options);
 
-    let mut loop_count = 0;
-    for instruct in &code {
-        match instruct {
-            Instruct::WhileNotZero => loop_count += 1,
-            Instruct::EndWhile => loop_count -= 1,
-            _ => {}
+    // Reset the global variables used for assembling an input file
+    *REGISTER_POINTER.lock() = PREDEFINED_REGISTERS;
+    *NAMED_REGISTERS.lock() = BTreeMap::new();
+
+    let (_, warnings) = result?;
+    Ok(warnings)
+}
+
+fn compile(
+    target: &str,
+    name: &str,
+    s: impl core::fmt::Display,
+    loader: &dyn Loader,
+    options: &Options,
+) -> Result<(Program, Vec<Warning>)> {
+    let source = strip_comments(s);
+    let (ast, stack_size) = program(&source, name, loader, target, options)?;
+    let initial_stack_ptr = *asm::REGISTER_POINTER.lock();
+
+    let bodies = ast.bodies();
+    let signatures = ast.signatures();
+    let data = ast.data();
+    let lints = lint::lint(&ast);
+    let stdlib = ast
+        .procs()
+        .into_iter()
+        .filter(|prc| prc.is_stdlib())
+        .map(|prc| String::from(prc.get_name()))
+        .collect::<BTreeSet<_>>();
+    let (code, procs) = ast.lower(options.code_size_limit, options.outline_threshold)?;
+
+    ast::check_nesting(&code)?;
+    for body in procs.values() {
+        ast::check_nesting(body)?;
+    }
+
+    let mut warnings = Vec::new();
+    let mut analysis = Analysis::new(&bodies, &signatures);
+
+    // Check that every procedure's body matches its declared signature, and report
+    // the mismatch at each call. A body whose stack effect cannot be determined is trusted.
+    let mut mismatches = Vec::new();
+    for (name, declared) in &signatures {
+        if let Ok(actual) = analysis.body_effect(name) {
+            if actual.net() != declared.net() || actual.pops > declared.pops {
+                let mismatch = |caller| Error::SignatureMismatch {
+                    name: name.clone(),
+                    declared: *declared,
+                    actual,
+                    caller,
+                };
+                let call = Instruct::Call(name.clone());
+                let calls = bodies
+                    .iter()
+                    .flat_map(|(caller, body)| {
+                        body.iter().filter(|i| **i == call).map(move |_| caller)
+                    })
+                    .map(|caller| mismatch(Some(caller.clone())))
+                    .collect::<Vec<Error>>();
+                if calls.is_empty() {
+                    mismatches.push(mismatch(None));
+                } else {
+                    mismatches.extend(calls);
+                }
+            }
         }
     }
+    match mismatches.len() {
+        0 => {}
+        1 => return Err(mismatches.remove(0)),
+        _ => return Err(Error::Multiple(mismatches)),
+    }
 
-    if loop_count != 0 {
-        Err(Error::UnmatchedLoop)
+    let usage = if bodies.contains_key(ast::Procedure::ENTRY_POINT) {
+        analysis.usage(ast::Procedure::ENTRY_POINT)
     } else {
-        Ok((code, stack_size))
+        Ok(Usage {
+            effect: StackEffect::new(0, 0),
+            max_depth: 0,
+        })
+    };
+
+    warnings.extend(analysis.warnings());
+
+    if let Ok(usage) = &usage {
+        if usage.effect.pops > 0 {
+            warnings.push(Warning::StackUnderflow(usage.effect.pops));
+        }
     }
+
+    let stack_size = match (stack_size, usage) {
+        (Some(declared), Ok(usage)) => {
+            if declared < usage.max_depth {
+                warnings.push(Warning::StackSizeTooSmall {
+                    declared,
+                    required: usage.max_depth,
+                });
+            }
+            declared
+        }
+        (Some(declared), Err(_)) => declared,
+        (None, Ok(usage)) => {
+            // The heap is allocated from the same memory as the stack,
+            // so leave room for it if the program uses `alloc`
+            let uses_heap = code
+                .iter()
+                .chain(procs.values().flatten())
+                .any(|i| matches!(i, Instruct::Alloc(_)));
+            if uses_heap {
+                usage.max_depth + options.heap_size
+            } else {
+                usage.max_depth
+            }
+        }
+        (None, Err(unknown)) => return Err(Error::UnknownStackSize(unknown)),
+    };
+
+    warnings.extend(lints);
+    // The standard library is checked on its own, so the warnings in its procedures
+    // are not reported for every program that includes it
+    warnings.retain(|w| w.procedure().map_or(true, |name| !stdlib.contains(name)));
+    warnings.retain(|w| options.lints.level(w.lint()) != lint::Level::Allow);
+    let denied = warnings
+        .iter()
+        .filter(|w| options.lints.level(w.lint()) == lint::Level::Deny)
+        .cloned()
+        .collect::<Vec<Warning>>();
+    if !denied.is_empty() {
+        return Err(Error::DeniedWarnings(denied));
+    }
+
+    Ok((
+        Program {
+            initial_stack_ptr,
+            stack_size,
+            code,
+            procs,
+            data,
+            bounds_checks: options.bounds_checks,
+        },
+        warnings,
+    ))
 }
@@ -0,0 +1,454 @@
+//! # lint, the module that finds likely bugs in assembly code
+//!
+//! None of the problems found by the linter stop a program from being assembled,
+//! but they are the most common bugs in generated assembly code. Each kind of
+//! warning is a Lint, which can be allowed, reported as a warning, or denied.
+//! A denied lint stops the program from being assembled.
+//!
+//! | Lint | Description |
+//! |------|-------------|
+//! | `unbalanced-loop` | A loop does not leave the stack as deep as it found it |
+//! | `unbalanced-branch` | The branches of a conditional leave the stack at different depths |
+//! | `stack-size` | The stack size is smaller than the program's stack usage |
+//! | `stack-underflow` | The entry point pops cells off of an empty stack |
+//! | `unreachable-procedure` | A procedure is never called from `start` |
+//! | `unused-register` | A register is defined, but never read |
+//! | `uninitialized-register` | A register is read before anything is stored in it |
+//! | `unfreed-alloc` | A register is used with `alloc`, but never with `free` |
+//! | `push-pop` | A `pop` immediately follows a `push` |
+//! | `constant-loop` | A loop's body cannot change the loop's test value |
+
+use crate::{
+    analysis,
+    asm::{Instruct, Register},
+    ast::{Ast, Exec, Procedure},
+    Warning,
+};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+/// The Lint enum represents a kind of warning
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lint {
+    /// A loop does not leave the stack as deep as it found it
+    UnbalancedLoop,
+
+    /// The branches of a conditional leave the stack at different depths
+    UnbalancedBranch,
+
+    /// The stack size is smaller than the program's stack usage
+    StackSize,
+
+    /// The entry point pops cells off of an empty stack
+    StackUnderflow,
+
+    /// A procedure is never called from `start`
+    UnreachableProcedure,
+
+    /// A register is defined, but never read
+    UnusedRegister,
+
+    /// A register is read before anything is stored in it
+    UninitializedRegister,
+
+    /// A register is used with `alloc`, but never with `free`
+    UnfreedAlloc,
+
+    /// A `pop` immediately follows a `push`
+    PushPop,
+
+    /// A loop's body cannot change the loop's test value
+    ConstantLoop,
+}
+
+impl Lint {
+    /// Every lint
+    pub const ALL: [Self; 10] = [
+        Self::UnbalancedLoop,
+        Self::UnbalancedBranch,
+        Self::StackSize,
+        Self::StackUnderflow,
+        Self::UnreachableProcedure,
+        Self::UnusedRegister,
+        Self::UninitializedRegister,
+        Self::UnfreedAlloc,
+        Self::PushPop,
+        Self::ConstantLoop,
+    ];
+
+    /// Get the name of the lint, as it is written on the command line
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::UnbalancedLoop => "unbalanced-loop",
+            Self::UnbalancedBranch => "unbalanced-branch",
+            Self::StackSize => "stack-size",
+            Self::StackUnderflow => "stack-underflow",
+            Self::UnreachableProcedure => "unreachable-procedure",
+            Self::UnusedRegister => "unused-register",
+            Self::UninitializedRegister => "uninitialized-register",
+            Self::UnfreedAlloc => "unfreed-alloc",
+            Self::PushPop => "push-pop",
+            Self::ConstantLoop => "constant-loop",
+        }
+    }
+
+    /// Get a lint by its name
+    pub fn named(name: impl fmt::Display) -> Option<Self> {
+        let name = name.to_string();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|lint| lint.get_name() == name)
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// The Level enum determines how a lint is reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// The lint is not reported
+    Allow,
+
+    /// The lint is reported as a warning
+    Warn,
+
+    /// The lint is reported as an error
+    Deny,
+}
+
+/// The Config struct stores the level of every lint
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    /// The levels of the lints that do not use the default level, `Warn`
+    levels: BTreeMap<Lint, Level>,
+
+    /// If this is set, every lint at the `Warn` level is denied instead.
+    /// This is what `-D warnings` sets.
+    deny_warnings: bool,
+}
+
+impl Config {
+    /// Set the level of a lint
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    /// Deny every lint that would otherwise be reported as a warning
+    pub fn deny_warnings(&mut self) {
+        self.deny_warnings = true;
+    }
+
+    /// Get the level of a lint
+    pub fn level(&self, lint: Lint) -> Level {
+        match self.levels.get(&lint).copied().unwrap_or(Level::Warn) {
+            Level::Warn if self.deny_warnings => Level::Deny,
+            level => level,
+        }
+    }
+}
+
+/// Get the name of a register as it is written in assembly code
+fn register_name(r: &Register) -> String {
+    match r {
+        Register::Accumulator => String::from("ACC"),
+        Register::StackPointer => String::from("SPR"),
+        Register::Named { name, .. } => name.clone(),
+    }
+}
+
+/// Get the cells a register occupies
+fn cells(r: &Register) -> core::ops::Range<usize> {
+    r.get_addr()..r.get_addr() + r.get_size()
+}
+
+/// Get the register an instruction reads from, if any
+fn reads(i: &Instruct) -> Option<&Register> {
+    match i {
+        Instruct::Load(r) | Instruct::LoadIndex(r) | Instruct::Free(r) | Instruct::Refer(r) => {
+            Some(r)
+        }
+        _ => None,
+    }
+}
+
+/// Get the register an instruction writes to, if any
+fn writes(i: &Instruct) -> Option<&Register> {
+    match i {
+        Instruct::Store(r) | Instruct::StoreIndex(r) | Instruct::Alloc(r) => Some(r),
+        _ => None,
+    }
+}
+
+/// Can this instruction change anything other than the stack?
+fn has_side_effects(i: &Instruct) -> bool {
+    matches!(
+        i,
+        Instruct::Store(_)
+            | Instruct::StoreIndex(_)
+            | Instruct::DerefStore
+            | Instruct::DerefStoreN(_)
+            | Instruct::MemCopy
+            | Instruct::MemSet
+            | Instruct::Alloc(_)
+            | Instruct::Free(_)
+            | Instruct::InputChar
+            | Instruct::InputNumber
+            | Instruct::Call(_)
+            | Instruct::CallExtern { .. }
+            | Instruct::CallIndirect
+            | Instruct::Raw { .. }
+    )
+}
+
+/// Can the body of a loop leave the loop with a `break` or a `continue`?
+fn can_leave(body: &[Instruct]) -> bool {
+    let mut depth = 0;
+    body.iter().any(|i| match i {
+        Instruct::WhileNotZero | Instruct::While => {
+            depth += 1;
+            false
+        }
+        Instruct::EndWhile | Instruct::EndDo => {
+            depth -= 1;
+            false
+        }
+        Instruct::Break(n) => *n >= depth,
+        Instruct::Continue(n) => *n > depth,
+        _ => false,
+    })
+}
+
+/// The Linter struct finds the lint warnings in an abstract syntax tree
+struct Linter<'a> {
+    /// The procedures in the program, looked up by name
+    procs: BTreeMap<&'a str, &'a Procedure>,
+
+    /// The procedures reachable from the entry point, in the order they are first called
+    reachable: Vec<&'a Procedure>,
+
+    /// The warnings found so far
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Linter<'a> {
+    /// Find every procedure reachable from a procedure
+    fn reach(&mut self, name: &str) {
+        if let Some(prc) = self.procs.get(name).copied() {
+            if self.reachable.iter().any(|p| p.get_name() == name) {
+                return;
+            }
+            self.reachable.push(prc);
+            for expr in prc.get_code() {
+                if let Exec::Call(callee) | Exec::Assembly(Instruct::ReferProc(callee)) = expr {
+                    self.reach(callee);
+                }
+            }
+        }
+    }
+
+    /// Walk the program in the order it executes, following each call the
+    /// first time it is made, and find the registers read before they are written
+    fn uninitialized(
+        &mut self,
+        prc: &'a Procedure,
+        visited: &mut BTreeSet<&'a str>,
+        written: &mut BTreeSet<usize>,
+        reported: &mut BTreeSet<String>,
+    ) {
+        for expr in prc.get_code() {
+            match expr {
+                // A procedure that is referred to may be called with `call_ind` from then on
+                Exec::Call(callee) | Exec::Assembly(Instruct::ReferProc(callee)) => {
+                    if let Some(callee) = self.procs.get(callee.as_str()).copied() {
+                        if visited.insert(callee.get_name()) {
+                            self.uninitialized(callee, visited, written, reported);
+                        }
+                    }
+                }
+                // Taking a register's address means it may be written through the pointer
+                Exec::Assembly(Instruct::Refer(r)) => written.extend(cells(r)),
+                Exec::Assembly(i) => {
+                    if let Some(r @ Register::Named { .. }) = reads(i) {
+                        // The cell an index reads is not known, so any written cell may be it
+                        let initialized = match i {
+                            Instruct::LoadIndex(_) => cells(r).any(|cell| written.contains(&cell)),
+                            _ => cells(r).all(|cell| written.contains(&cell)),
+                        };
+                        if !initialized && reported.insert(register_name(r)) {
+                            self.warnings
+                                .push(Warning::UninitializedRegister(register_name(r)));
+                        }
+                    }
+                    if let Some(r) = writes(i) {
+                        written.extend(cells(r));
+                    }
+                }
+                Exec::Define(_) | Exec::Data(..) => {}
+            }
+        }
+    }
+
+    /// Find the loops in a procedure whose bodies cannot change their test value.
+    /// Such a body does not touch the stack below it, does not change memory,
+    /// and does not read input, so it computes the same test value every time.
+    /// The test value of a `while` loop is computed by its condition instead, which
+    /// must not touch the stack below it either. A loop that can be left with
+    /// `break` or `continue` is not reported.
+    fn constant_loops(&mut self, prc: &Procedure) {
+        let code = prc
+            .get_code()
+            .iter()
+            .filter_map(|expr| match expr {
+                Exec::Assembly(i) => Some(i.clone()),
+                Exec::Call(name) => Some(Instruct::Call(name.clone())),
+                Exec::Define(_) | Exec::Data(..) => None,
+            })
+            .collect::<Vec<_>>();
+
+        // The open loops, as the start of each loop and the `do` of a `while` loop
+        let mut starts = Vec::new();
+        for (i, instruct) in code.iter().enumerate() {
+            let (condition, body) = match instruct {
+                Instruct::WhileNotZero | Instruct::While => {
+                    starts.push((i + 1, None));
+                    continue;
+                }
+                Instruct::Do => {
+                    if let Some((_, test)) = starts.last_mut() {
+                        *test = Some(i);
+                    }
+                    continue;
+                }
+                Instruct::EndWhile => match starts.pop() {
+                    Some((start, None)) => (None, &code[start..i]),
+                    _ => continue,
+                },
+                Instruct::EndDo => match starts.pop() {
+                    Some((start, Some(test))) => (Some(&code[start..test]), &code[test + 1..i]),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            if condition
+                .into_iter()
+                .flatten()
+                .chain(body)
+                .any(has_side_effects)
+                || can_leave(body)
+            {
+                continue;
+            }
+            // The test value is pushed by the condition of a `while` loop, and by
+            // the body of any other loop
+            let test = condition.unwrap_or(body);
+            if let Ok(effect) = analysis::code_effect(test) {
+                if effect.pops == 0 && effect.net() == 1 {
+                    self.warnings
+                        .push(Warning::ConstantLoop(prc.get_name().to_string()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Find all of the lint warnings in the program
+    fn lint(mut self, ast: &'a Ast) -> Vec<Warning> {
+        self.reach(Procedure::ENTRY_POINT);
+
+        for prc in ast.procs() {
+            if !self
+                .reachable
+                .iter()
+                .any(|p| p.get_name() == prc.get_name())
+            {
+                self.warnings
+                    .push(Warning::UnreachableProcedure(prc.get_name().to_string()));
+            }
+        }
+
+        if let Some(entry) = self.procs.get(Procedure::ENTRY_POINT).copied() {
+            let mut visited = BTreeSet::new();
+            visited.insert(entry.get_name());
+            // Registers defined with `data` are filled before the entry point runs
+            let mut written = ast
+                .data()
+                .into_iter()
+                .flat_map(|(addr, cells)| addr..addr + cells.len())
+                .collect();
+            self.uninitialized(entry, &mut visited, &mut written, &mut BTreeSet::new());
+        }
+
+        let mut defined = Vec::new();
+        let mut read = BTreeSet::new();
+        let mut allocated = Vec::new();
+        let mut freed = BTreeSet::new();
+        for prc in self.reachable.clone() {
+            let mut last: Option<&Instruct> = None;
+            let mut push_pop = false;
+            for expr in prc.get_code() {
+                match expr {
+                    Exec::Define(r) | Exec::Data(r, _) => defined.push(r),
+                    Exec::Assembly(i) => {
+                        if let Some(r) = reads(i) {
+                            read.extend(cells(r));
+                        }
+                        match i {
+                            Instruct::Alloc(r) => allocated.push(r),
+                            Instruct::Free(r) => {
+                                freed.insert(r.get_addr());
+                            }
+                            Instruct::Pop => {
+                                push_pop |= matches!(last, Some(Instruct::Push(_)));
+                            }
+                            _ => {}
+                        }
+                        last = Some(i);
+                    }
+                    Exec::Call(_) => last = None,
+                }
+            }
+
+            if push_pop {
+                self.warnings
+                    .push(Warning::PushPop(prc.get_name().to_string()));
+            }
+            self.constant_loops(prc);
+        }
+
+        for r in defined {
+            if !cells(r).any(|cell| read.contains(&cell)) {
+                self.warnings
+                    .push(Warning::UnusedRegister(register_name(r)));
+            }
+        }
+
+        let mut reported = BTreeSet::new();
+        for r in allocated {
+            if !freed.contains(&r.get_addr()) && reported.insert(r.get_addr()) {
+                self.warnings.push(Warning::UnfreedAlloc(register_name(r)));
+            }
+        }
+
+        self.warnings
+    }
+}
+
+/// Find the lint warnings in an abstract syntax tree
+pub(crate) fn lint(ast: &Ast) -> Vec<Warning> {
+    Linter {
+        procs: ast.procs().into_iter().map(|p| (p.get_name(), p)).collect(),
+        reachable: Vec::new(),
+        warnings: Vec::new(),
+    }
+    .lint(ast)
+}
@@ -1,132 +1,500 @@
 use crate::{
-    asm::{Instruct, Literal, Register, DEFAULT_STACK_SIZE},
-    ast::{Ast, Exec, Procedure},
-    Error, Result,
+    asm::{
+        Instruct, Literal, Register, StackEffect, DEFAULT_STACK_SIZE, MAX_SIZE, NAMED_REGISTERS,
+    },
+    ast::{Ast, Exec, Inline, Procedure},
+    stdlib, Error, Loader, Options, Result,
 };
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::iter::once;
+use spin::Mutex;
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{anychar, char, space0},
-    combinator::{cut, map_opt},
-    error::{context, VerboseError},
-    multi::{many0, many1},
+    bytes::complete::{tag, take_until, take_while1},
+    character::complete::{
+        anychar, char, line_ending, multispace0, multispace1, none_of, space0, space1,
+    },
+    combinator::{all_consuming, cut, map, map_opt, opt, verify},
+    error::{context, VerboseError, VerboseErrorKind},
+    multi::{many0, separated_list, separated_nonempty_list},
     number::complete::double,
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
 /// The ParseResult type is used to make interfacing with nom's IResult type simpler
 pub type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
 
-/// Parses a number literal as an unsigned size value
-fn size(input: &str) -> ParseResult<usize> {
-    let (input, num) = double(input)?;
-    Ok((input, num as usize))
+/// Parses an expression as an unsigned size value. The value must be a whole
+/// number that is not negative and not larger than `MAX_SIZE`.
+fn size(input: &str) -> ParseResult<'_, usize> {
+    map_opt(expression, |value| {
+        let value = value.get();
+        if value >= 0.0 && value.fract() == 0.0 && value <= MAX_SIZE as f64 {
+            Some(value as usize)
+        } else {
+            None
+        }
+    })(input)
+}
+
+/// The error for a register that does not fit in memory. It points at `input`,
+/// which is the size of the register or its name.
+fn too_large(input: &str) -> nom::Err<VerboseError<&str>> {
+    nom::Err::Failure(VerboseError {
+        errors: vec![(input, VerboseErrorKind::Context(Error::INVALID_SIZE))],
+    })
 }
 
 /// Parses a number literal as an actual instance of Literal.
 /// This makes defining the `literal` parser much simpler
-fn num(input: &str) -> ParseResult<Literal> {
+fn num(input: &str) -> ParseResult<'_, Literal> {
     let (input, num) = double(input)?;
     Ok((input, Literal::num(num)))
 }
 
+/// Parses an escaped character, such as `\n`, in a character or string literal
+fn escaped(input: &str) -> ParseResult<'_, char> {
+    let (input, _) = tag("\\")(input)?;
+    let (input, ch) = anychar(input)?;
+    Ok((
+        input,
+        // convert the escaped character into its escaped form
+        match ch {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            // if the character isnt recognized as an escape character,
+            // just pass it along as a non-escaped character
+            other => other,
+        },
+    ))
+}
+
 /// Parses a character literal as an actual instance of Literal.
 /// This makes defining the `literal` parser much simpler
-fn ch(input: &str) -> ParseResult<Literal> {
+fn ch(input: &str) -> ParseResult<'_, Literal> {
     let (input, _) = char('\'')(input)?;
-    let (input, ch) = alt((
-        // This lambda function accounts for escape characters
+    let (input, ch) = alt((escaped, anychar))(input)?;
+    let (input, _) = char('\'')(input)?;
+    Ok((input, Literal::ch(ch)))
+}
+
+/// Parses a string literal as the list of characters it contains
+fn string(input: &str) -> ParseResult<'_, Vec<char>> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char('"')(input)?;
+    let (input, chars) = many0(alt((escaped, none_of("\"\\"))))(input)?;
+    let (input, _) = cut(char('"'))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, chars))
+}
+
+/// Parses a single item of a `data` directive: a string literal, which is stored
+/// as its characters followed by a zero, or an expression, which is stored as one cell
+fn data_item(input: &str) -> ParseResult<'_, Vec<f64>> {
+    alt((
+        map(string, |chars| {
+            chars
+                .into_iter()
+                .map(|ch| Literal::ch(ch).get())
+                .chain(once(0.0))
+                .collect()
+        }),
+        map(expression, |value| vec![value.get()]),
+    ))(input)
+}
+
+/// This parses the operand of an expression: a character or number literal, a constant,
+/// a parenthesized expression, a negated operand, or the size of a register
+fn operand(input: &str) -> ParseResult<'_, Literal> {
+    let (input, _) = multispace0(input)?;
+    let (input, value) = alt((
+        ch,
+        num,
         |input| {
-            let (input, _) = tag("\\")(input)?;
-            let (input, ch) = anychar(input)?;
-            Ok((
-                input,
-                // convert the escaped character into its escaped form
-                match ch {
-                    'n' => '\n',
-                    'r' => '\r',
-                    't' => '\t',
-                    '0' => '\0',
-                    // if the character isnt recognized as an escape character,
-                    // just pass it along as a non-escaped character
-                    other => other,
-                },
-            ))
+            let (input, _) = char('(')(input)?;
+            let (input, value) = expression(input)?;
+            let (input, _) = char(')')(input)?;
+            Ok((input, value))
         },
-        anychar,
+        |input| {
+            let (input, _) = char('-')(input)?;
+            let (input, value) = operand(input)?;
+            Ok((input, Literal::num(-value.get())))
+        },
+        |input| {
+            let (input, _) = tag("sizeof")(input)?;
+            let (input, _) = multispace0(input)?;
+            let (input, _) = char('(')(input)?;
+            let (input, r) = register(input)?;
+            let (input, _) = char(')')(input)?;
+            Ok((input, Literal::num(r.get_size() as f64)))
+        },
+        map_opt(identifier, |name| {
+            CONSTANTS
+                .lock()
+                .get(name)
+                .cloned()
+                .or_else(|| match *IN_CONDITION.lock() {
+                    true => Some(Literal::num(0.0)),
+                    false => None,
+                })
+        }),
     ))(input)?;
-    let (input, _) = char('\'')(input)?;
-    Ok((input, Literal::ch(ch)))
+    let (input, _) = multispace0(input)?;
+    Ok((input, value))
+}
+
+/// This parses a product of operands, using `*`, `/`, and `%`
+fn term(input: &str) -> ParseResult<'_, Literal> {
+    let (input, first) = operand(input)?;
+    let (input, rest) = many0(pair(alt((char('*'), char('/'), char('%'))), operand))(input)?;
+    Ok((
+        input,
+        rest.into_iter().fold(first, |a, (op, b)| {
+            Literal::num(match op {
+                '*' => a.get() * b.get(),
+                '/' => a.get() / b.get(),
+                _ => a.get() % b.get(),
+            })
+        }),
+    ))
+}
+
+/// This parses an expression that is evaluated when the assembly code is parsed.
+/// Expressions are sums of terms, using `+` and `-`. An expression that is just a
+/// character is kept as a character literal. Otherwise, the result is a number.
+fn expression(input: &str) -> ParseResult<'_, Literal> {
+    let (input, first) = term(input)?;
+    let (input, rest) = many0(pair(alt((char('+'), char('-'))), term))(input)?;
+    Ok((
+        input,
+        rest.into_iter().fold(first, |a, (op, b)| {
+            Literal::num(match op {
+                '+' => a.get() + b.get(),
+                _ => a.get() - b.get(),
+            })
+        }),
+    ))
 }
 
-/// This parses either a character or number literal
-fn literal(input: &str) -> ParseResult<Literal> {
-    let (input, _) = space0(input)?;
-    let (input, n) = alt((ch, num))(input)?;
-    let (input, _) = space0(input)?;
+/// This parses either a character or number literal, or an expression
+fn literal(input: &str) -> ParseResult<'_, Literal> {
+    let (input, _) = multispace0(input)?;
+    let (input, n) = expression(input)?;
+    let (input, _) = multispace0(input)?;
     Ok((input, n))
 }
 
 /// This parses an identifier, which is composed of alphanumeric characters and underscores.
 /// identifiers can start with numbers.
-fn identifier(input: &str) -> ParseResult<&str> {
-    let (input, _) = space0(input)?;
+fn identifier(input: &str) -> ParseResult<'_, &str> {
+    let (input, _) = multispace0(input)?;
     let (input, i) = take_while1(|input: char| input.is_alphanumeric() || input == '_')(input)?;
-    let (input, _) = space0(input)?;
+    let (input, _) = multispace0(input)?;
     Ok((input, i))
 }
 
+/// A field name or a constant index following a register, such as `.x` or `[3]`
+#[derive(Clone, Debug)]
+enum Part<'a> {
+    Field(&'a str),
+    Index(Literal),
+}
+
 /// register parses predefined registers and user defined registers using the Register
-/// structure and the identifier parser.
-fn register(input: &str) -> ParseResult<Register> {
-    context(
+/// structure and the identifier parser. A register defined with a struct can be followed
+/// by field names, such as `p.pos.x`, to get the part of the register that holds the field.
+/// A register can also be followed by a constant index, such as `buf[3]`, to get a single cell.
+fn register(input: &str) -> ParseResult<'_, Register> {
+    let (rest, (name, parts)) = context(
         Error::REGISTER_NOT_DEFINED,
-        cut(map_opt(identifier, |name| match name {
-            "ACC" => Some(Register::Accumulator),
-            "SPR" => Some(Register::StackPointer),
-            other => Register::named(other),
-        })),
-    )(input)
+        cut(pair(
+            identifier,
+            many0(alt((
+                map(preceded(char('.'), identifier), Part::Field),
+                map(
+                    delimited(char('['), expression, pair(char(']'), multispace0)),
+                    Part::Index,
+                ),
+            ))),
+        )),
+    )(input)?;
+    let r = match name {
+        "ACC" => Some(Register::Accumulator),
+        "SPR" => Some(Register::StackPointer),
+        other => Register::named(other),
+    };
+    r.ok_or(Error::REGISTER_NOT_DEFINED)
+        .and_then(|r| part(name, r, &parts))
+        .map(|r| (rest, r))
+        .map_err(|context| {
+            nom::Err::Failure(VerboseError {
+                errors: vec![(input, VerboseErrorKind::Context(context))],
+            })
+        })
 }
 
-/// This parser parses lasm's opcodes
-fn opcode(input: &str) -> ParseResult<&str> {
-    let (input, _) = space0(input)?;
-    let (input, op) = alt((
-        tag("refer"),
-        tag("deref_ld"),
-        tag("deref_st"),
-        tag("free"),
-        tag("alloc"),
-        tag("ld"),
-        tag("st"),
-        tag("push"),
-        tag("pop"),
-        tag("dup"),
-        tag("add"),
-        // a second alt must be added because the number of arguments
-        // is too large for a single call to alt
-        alt((
-            tag("inc"),
-            tag("inn"),
-            tag("sub"),
-            tag("mul"),
-            tag("div"),
-            tag("outc"),
-            tag("outn"),
-            tag("cmp"),
-            tag("loop"),
-            tag("call"),
-            tag("define"),
-            tag("endloop"),
-        )),
-    ))(input)?;
-    let (input, _) = space0(input)?;
+/// A field of a struct
+#[derive(Clone, Debug)]
+struct Field {
+    /// The name of the field
+    name: String,
+    /// The number of cells between the start of the struct and the field
+    offset: usize,
+    /// The number of cells the field occupies
+    size: usize,
+    /// The struct the field was declared with, if any
+    layout: Option<String>,
+}
+
+/// Get the part of a register that holds a chain of struct fields and indices, such as
+/// `pos.x` or `[3]`. Fields can only be used on registers defined with a struct, and
+/// indices must be inside of the register. If a part cannot be found, the context
+/// of the error is returned.
+fn part(
+    name: &str,
+    mut r: Register,
+    parts: &[Part],
+) -> core::result::Result<Register, &'static str> {
+    if parts.is_empty() {
+        return Ok(r);
+    }
+
+    let structs = STRUCTS.lock();
+    // A register's struct only applies if the name still refers to the same register
+    let mut layout = REGISTER_STRUCTS
+        .lock()
+        .get(name)
+        .filter(|(addr, _)| *addr == r.get_addr())
+        .map(|(_, layout)| layout.clone());
+    let mut name = name.to_string();
+
+    for part in parts {
+        match part {
+            Part::Field(field) => {
+                let field = layout
+                    .as_ref()
+                    .and_then(|layout| structs.get(layout))
+                    .and_then(|fields| fields.iter().find(|f| f.name == *field))
+                    .ok_or(Error::REGISTER_NOT_DEFINED)?;
+                name = format!("{}.{}", name, field.name);
+                r = r.part(&name, field.offset, field.size);
+                layout = field.layout.clone();
+            }
+            Part::Index(index) => {
+                let index = index.get();
+                if index < 0.0 || index.fract() != 0.0 || index as usize >= r.get_size() {
+                    return Err(Error::INDEX_OUT_OF_BOUNDS);
+                }
+                name = format!("{}[{}]", name, index);
+                r = r.part(&name, index as usize, 1);
+                layout = None;
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// lasm's opcodes
+const OPCODES: &[&str] = &[
+    "refer",
+    "deref_ld",
+    "deref_st",
+    "deref_ld_n",
+    "deref_st_n",
+    "memcpy",
+    "memset",
+    "free",
+    "alloc",
+    "ld",
+    "st",
+    "ld_idx",
+    "st_idx",
+    "push",
+    "pop",
+    "dup",
+    "swap",
+    "over",
+    "rot",
+    "pick",
+    "drop",
+    "add",
+    "inc",
+    "inn",
+    "sub",
+    "mul",
+    "div",
+    "mod",
+    "neg",
+    "floor",
+    "ceil",
+    "trunc",
+    "abs",
+    "pow",
+    "sqrt",
+    "band",
+    "bor",
+    "bxor",
+    "bnot",
+    "shl",
+    "shr",
+    "and",
+    "or",
+    "not",
+    "outc",
+    "outn",
+    "cmp",
+    "lt",
+    "le",
+    "gt",
+    "ge",
+    "eq",
+    "ne",
+    "loop",
+    "call",
+    "define",
+    "data",
+    "endloop",
+    "while",
+    "do",
+    "endwhile",
+    "break",
+    "continue",
+    "if",
+    "else",
+    "endif",
+    "raw",
+    "refer_proc",
+    "call_ind",
+];
+
+/// The delimiter that surrounds the code of a `raw` instruction
+const RAW_DELIMITER: &str = "\"\"\"";
+
+lazy_static! {
+    /// This tracks the constants defined with `const`, by name
+    static ref CONSTANTS: Mutex<BTreeMap<String, Literal>> = Mutex::new(BTreeMap::new());
+
+    /// This tracks the fields of the structs declared with `struct`, by name
+    static ref STRUCTS: Mutex<BTreeMap<String, Vec<Field>>> = Mutex::new(BTreeMap::new());
+
+    /// This tracks the registers defined with a struct, by name, along with
+    /// their addresses and the names of their structs
+    static ref REGISTER_STRUCTS: Mutex<BTreeMap<String, (usize, String)>> = Mutex::new(BTreeMap::new());
+
+    /// This tracks the labels of the loops that are open while parsing a procedure,
+    /// from the outermost loop to the innermost. Unlabeled loops are `None`.
+    static ref LOOP_LABELS: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+
+    /// This tracks the extern procedures declared with `extern proc`, by name
+    static ref EXTERNS: Mutex<BTreeMap<String, StackEffect>> = Mutex::new(BTreeMap::new());
+
+    /// This tracks the macros declared with `macro`, by name
+    static ref MACROS: Mutex<BTreeMap<String, Macro>> = Mutex::new(BTreeMap::new());
+
+    /// This tracks the macros that are being expanded, from the outermost to the innermost
+    static ref EXPANDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// This is the error in the body of the macro that failed to expand, if there was one
+    static ref EXPANSION_ERROR: Mutex<Option<Error>> = Mutex::new(None);
+
+    /// This is the name of the target the program is assembled for, which is used by `#if`
+    static ref TARGET: Mutex<String> = Mutex::new(String::new());
+
+    /// This tracks the features enabled for the program, which are used by `#if`
+    static ref FEATURES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+    /// This is set while the condition of an `#if` is parsed, where names that are
+    /// not constants are zero
+    static ref IN_CONDITION: Mutex<bool> = Mutex::new(false);
+}
+
+/// A macro declared with `macro NAME PARAM ...`
+#[derive(Clone, Debug)]
+struct Macro {
+    /// The names of the parameters, which are written as `$PARAM` in the body
+    params: Vec<String>,
+    /// The source code of the body, which is parsed after the arguments are substituted
+    body: String,
+}
+
+impl Macro {
+    /// Replace each `$PARAM` in the body with the argument given for it.
+    /// A `$` that is not followed by the name of a parameter is kept as it is.
+    fn expand(&self, args: &[&str]) -> String {
+        let mut result = String::new();
+        let mut rest = self.body.as_str();
+        while let Some(i) = rest.find('$') {
+            result += &rest[..=i];
+            rest = &rest[i + 1..];
+            let len = rest
+                .find(|ch: char| !ch.is_alphanumeric() && ch != '_')
+                .unwrap_or(rest.len());
+            if let Some(param) = self.params.iter().position(|p| *p == rest[..len]) {
+                result.pop();
+                result += args[param];
+                rest = &rest[len..];
+            }
+        }
+        result += rest;
+        result
+    }
+}
+
+/// This parser parses lasm's opcodes. An opcode must be a whole word,
+/// so that identifiers such as `address` are not read as `add`.
+fn opcode(input: &str) -> ParseResult<'_, &str> {
+    let (input, _) = multispace0(input)?;
+    let (input, op) = verify(
+        take_while1(|input: char| input.is_alphanumeric() || input == '_'),
+        |op: &str| OPCODES.contains(&op),
+    )(input)?;
+    let (input, _) = multispace0(input)?;
     Ok((input, op))
 }
 
-fn instruction(input: &str) -> ParseResult<Exec> {
+/// Is a name reserved for an opcode or a keyword?
+fn is_keyword(name: &str) -> bool {
+    OPCODES.contains(&name)
+        || matches!(
+            name,
+            "proc" | "endproc" | "inline" | "noinline" | "macro" | "endmacro" | "extern"
+        )
+}
+
+/// This parses the label of a loop, which is any identifier that is not a keyword or a macro
+fn label(input: &str) -> ParseResult<'_, &str> {
+    verify(identifier, |label: &str| {
+        !is_keyword(label) && !MACROS.lock().contains_key(label)
+    })(input)
+}
+
+/// Get the number of loops between the innermost open loop and the loop
+/// that a `break` or `continue` with the given label refers to. Without
+/// a label, the innermost loop is used.
+fn loop_depth(label: Option<&str>) -> Option<usize> {
+    let labels = LOOP_LABELS.lock();
+    match label {
+        Some(label) => labels
+            .iter()
+            .rev()
+            .position(|l| l.as_deref() == Some(label)),
+        None if labels.is_empty() => None,
+        None => Some(0),
+    }
+}
+
+fn instruction(input: &str) -> ParseResult<'_, Exec> {
+    let start = input;
     let (input, op) = opcode(input)?;
     match op {
         "alloc" => {
@@ -143,6 +511,24 @@ fn instruction(input: &str) -> ParseResult<Exec> {
         }
         "deref_ld" => Ok((input, Exec::asm(Instruct::DerefLoad))),
         "deref_st" => Ok((input, Exec::asm(Instruct::DerefStore))),
+        "deref_ld_n" | "deref_st_n" => {
+            let (input, n) = context(Error::INVALID_SIZE, cut(size))(input)?;
+            let (input, _) = multispace0(input)?;
+            match op {
+                "deref_ld_n" => Ok((input, Exec::asm(Instruct::DerefLoadN(n)))),
+                _ => Ok((input, Exec::asm(Instruct::DerefStoreN(n)))),
+            }
+        }
+        "memcpy" => Ok((input, Exec::asm(Instruct::MemCopy))),
+        "memset" => Ok((input, Exec::asm(Instruct::MemSet))),
+        "ld_idx" => {
+            let (input, reg) = context(Error::INVALID_LOAD_ARG, cut(register))(input)?;
+            Ok((input, Exec::asm(Instruct::LoadIndex(reg))))
+        }
+        "st_idx" => {
+            let (input, reg) = context(Error::INVALID_STORE_ARG, cut(register))(input)?;
+            Ok((input, Exec::asm(Instruct::StoreIndex(reg))))
+        }
         "ld" => {
             let (input, reg) = context(Error::INVALID_LOAD_ARG, cut(register))(input)?;
             Ok((input, Exec::asm(Instruct::Load(reg))))
@@ -157,71 +543,927 @@ fn instruction(input: &str) -> ParseResult<Exec> {
         }
         "pop" => Ok((input, Exec::asm(Instruct::Pop))),
         "dup" => Ok((input, Exec::asm(Instruct::Duplicate))),
+        "swap" => Ok((input, Exec::asm(Instruct::Swap))),
+        "over" => Ok((input, Exec::asm(Instruct::Over))),
+        "rot" => Ok((input, Exec::asm(Instruct::Rotate))),
+        "pick" | "drop" => {
+            let (input, n) = context(Error::INVALID_SIZE, cut(size))(input)?;
+            let (input, _) = multispace0(input)?;
+            match op {
+                "pick" => Ok((input, Exec::asm(Instruct::Pick(n)))),
+                _ => Ok((input, Exec::asm(Instruct::Drop(n)))),
+            }
+        }
         "add" => Ok((input, Exec::asm(Instruct::Add))),
         "sub" => Ok((input, Exec::asm(Instruct::Subtract))),
         "mul" => Ok((input, Exec::asm(Instruct::Multiply))),
         "div" => Ok((input, Exec::asm(Instruct::Divide))),
+        "mod" => Ok((input, Exec::asm(Instruct::Modulo))),
+        "neg" => Ok((input, Exec::asm(Instruct::Negate))),
+        "floor" => Ok((input, Exec::asm(Instruct::Floor))),
+        "ceil" => Ok((input, Exec::asm(Instruct::Ceil))),
+        "trunc" => Ok((input, Exec::asm(Instruct::Truncate))),
+        "abs" => Ok((input, Exec::asm(Instruct::Absolute))),
+        "pow" => Ok((input, Exec::asm(Instruct::Power))),
+        "sqrt" => Ok((input, Exec::asm(Instruct::SquareRoot))),
+        "band" => Ok((input, Exec::asm(Instruct::BitAnd))),
+        "bor" => Ok((input, Exec::asm(Instruct::BitOr))),
+        "bxor" => Ok((input, Exec::asm(Instruct::BitXor))),
+        "bnot" => Ok((input, Exec::asm(Instruct::BitNot))),
+        "shl" => Ok((input, Exec::asm(Instruct::ShiftLeft))),
+        "shr" => Ok((input, Exec::asm(Instruct::ShiftRight))),
+        "and" => Ok((input, Exec::asm(Instruct::And))),
+        "or" => Ok((input, Exec::asm(Instruct::Or))),
+        "not" => Ok((input, Exec::asm(Instruct::Not))),
         "outc" => Ok((input, Exec::asm(Instruct::OutputChar))),
         "outn" => Ok((input, Exec::asm(Instruct::OutputNumber))),
         "inc" => Ok((input, Exec::asm(Instruct::InputChar))),
         "inn" => Ok((input, Exec::asm(Instruct::InputNumber))),
         "cmp" => Ok((input, Exec::asm(Instruct::Compare))),
-        "loop" => Ok((input, Exec::asm(Instruct::WhileNotZero))),
-        "endloop" => Ok((input, Exec::asm(Instruct::EndWhile))),
+        "lt" => Ok((input, Exec::asm(Instruct::Less))),
+        "le" => Ok((input, Exec::asm(Instruct::LessEqual))),
+        "gt" => Ok((input, Exec::asm(Instruct::Greater))),
+        "ge" => Ok((input, Exec::asm(Instruct::GreaterEqual))),
+        "eq" => Ok((input, Exec::asm(Instruct::Equal))),
+        "ne" => Ok((input, Exec::asm(Instruct::NotEqual))),
+        "loop" | "while" => {
+            let (input, l) = opt(label)(input)?;
+            LOOP_LABELS.lock().push(l.map(String::from));
+            match op {
+                "loop" => Ok((input, Exec::asm(Instruct::WhileNotZero))),
+                _ => Ok((input, Exec::asm(Instruct::While))),
+            }
+        }
+        "do" => Ok((input, Exec::asm(Instruct::Do))),
+        "endloop" | "endwhile" => {
+            LOOP_LABELS.lock().pop();
+            match op {
+                "endloop" => Ok((input, Exec::asm(Instruct::EndWhile))),
+                _ => Ok((input, Exec::asm(Instruct::EndDo))),
+            }
+        }
+        "break" | "continue" => {
+            let (rest, l) = opt(label)(input)?;
+            match (op, loop_depth(l)) {
+                ("break", Some(depth)) => Ok((rest, Exec::asm(Instruct::Break(depth)))),
+                (_, Some(depth)) => Ok((rest, Exec::asm(Instruct::Continue(depth)))),
+                // The error points at the label if there is one, and at the instruction if not
+                (_, None) => Err(nom::Err::Failure(VerboseError {
+                    errors: vec![match l {
+                        Some(_) => (input, VerboseErrorKind::Context(Error::UNKNOWN_LABEL)),
+                        None => (start, VerboseErrorKind::Context(Error::BREAK_OUTSIDE_LOOP)),
+                    }],
+                })),
+            }
+        }
+        "if" => Ok((input, Exec::asm(Instruct::If))),
+        "else" => Ok((input, Exec::asm(Instruct::Else))),
+        "endif" => Ok((input, Exec::asm(Instruct::EndIf))),
+        "raw" => {
+            let (input, target) = context(Error::INVALID_RAW, cut(identifier))(input)?;
+            let (input, code) = context(
+                Error::INVALID_RAW,
+                cut(delimited(
+                    tag(RAW_DELIMITER),
+                    take_until(RAW_DELIMITER),
+                    tag(RAW_DELIMITER),
+                )),
+            )(input)?;
+            let (input, _) = multispace0(input)?;
+            Ok((
+                input,
+                Exec::asm(Instruct::Raw {
+                    target: target.to_string(),
+                    code: code.to_string(),
+                }),
+            ))
+        }
         "call" => {
+            // Calls to extern procedures are found once the whole program is parsed
             let (input, i) = cut(identifier)(input)?;
             Ok((input, Exec::call(i)))
         }
+        "refer_proc" => {
+            let (input, i) = context(Error::INVALID_IDENTIFIER, cut(identifier))(input)?;
+            Ok((input, Exec::asm(Instruct::ReferProc(i.to_string()))))
+        }
+        "call_ind" => Ok((input, Exec::asm(Instruct::CallIndirect))),
+        "data" => {
+            let (input, i) = context(Error::INVALID_IDENTIFIER, cut(identifier))(input)?;
+            let (input, items) = context(
+                Error::INVALID_DATA,
+                cut(separated_nonempty_list(char(','), data_item)),
+            )(input)?;
+            let cells = items.concat();
+            let r = Register::define(i, cells.len()).ok_or_else(|| too_large(i))?;
+            Ok((input, Exec::Data(r, cells)))
+        }
         "define" => {
             let (input, i) = context(Error::INVALID_IDENTIFIER, cut(identifier))(input)?;
-            let (input, _) = char(',')(input)?;
-            let (input, _) = space0(input)?;
-            let (input, s) = context(Error::INVALID_SIZE, cut(size))(input)?;
-            let (input, _) = space0(input)?;
-            Register::define(i, s);
-            Ok((input, Exec::Nop))
+            let (input, sep) = alt((char(','), char(':')))(input)?;
+            let (input, _) = multispace0(input)?;
+            if sep == ',' {
+                let (rest, s) = context(Error::INVALID_SIZE, cut(size))(input)?;
+                let (rest, _) = multispace0(rest)?;
+                let r = Register::define(i, s).ok_or_else(|| too_large(input))?;
+                REGISTER_STRUCTS.lock().remove(i);
+                Ok((rest, Exec::Define(r)))
+            } else {
+                let (rest, (layout, s)) =
+                    context(Error::STRUCT_NOT_DEFINED, cut(struct_size))(input)?;
+                let r = Register::define(i, s).ok_or_else(|| too_large(input))?;
+                REGISTER_STRUCTS
+                    .lock()
+                    .insert(i.to_string(), (r.get_addr(), layout.to_string()));
+                Ok((rest, Exec::Define(r)))
+            }
         }
         _ => unreachable!(),
     }
 }
 
-fn procedure(input: &str) -> ParseResult<Procedure> {
-    let (input, _) = space0(input)?;
+/// This parses `push` with a string literal. A zero is pushed, followed by the
+/// string's characters in reverse, so that the first character is on top of the stack.
+fn push_string(input: &str) -> ParseResult<'_, Vec<Exec>> {
+    let (input, _) = verify(opcode, |op: &str| op == "push")(input)?;
+    let (input, chars) = string(input)?;
+    Ok((
+        input,
+        once(Literal::num(0.0))
+            .chain(chars.into_iter().rev().map(Literal::ch))
+            .map(|lit| Exec::asm(Instruct::Push(lit)))
+            .collect(),
+    ))
+}
+
+/// This parses the operator of a comparison in a condition
+fn comparison(input: &str) -> ParseResult<'_, &str> {
+    alt((
+        tag("=="),
+        tag("!="),
+        tag("<="),
+        tag(">="),
+        tag("<"),
+        tag(">"),
+    ))(input)
+}
+
+/// This parses a single condition of an `#if`. A condition is `target == "NAME"`,
+/// `target != "NAME"`, `feature(NAME)`, `defined(NAME)`, a comparison of two expressions,
+/// or an expression, which holds if it is not zero. A name that is not a constant is zero.
+fn condition_atom(input: &str) -> ParseResult<'_, bool> {
+    let (input, _) = multispace0(input)?;
+    alt((
+        map(preceded(char('!'), condition_atom), |holds| !holds),
+        |input| {
+            let (input, _) = verify(identifier, |name: &str| name == "target")(input)?;
+            let (input, op) = alt((tag("=="), tag("!=")))(input)?;
+            let (input, name) = string(input)?;
+            let is_target = *TARGET.lock() == name.into_iter().collect::<String>();
+            Ok((input, is_target == (op == "==")))
+        },
+        |input| {
+            let (input, function) = verify(identifier, |name: &str| {
+                matches!(name, "feature" | "defined")
+            })(input)?;
+            let (input, name) = delimited(char('('), identifier, char(')'))(input)?;
+            let (input, _) = multispace0(input)?;
+            Ok((
+                input,
+                match function {
+                    "feature" => FEATURES.lock().contains(name),
+                    _ => CONSTANTS.lock().contains_key(name),
+                },
+            ))
+        },
+        |input| {
+            let (input, a) = expression(input)?;
+            let (input, op) = opt(pair(comparison, expression))(input)?;
+            let a = a.get();
+            Ok((
+                input,
+                match op.map(|(op, b)| (op, b.get())) {
+                    None => a != 0.0,
+                    Some(("==", b)) => a == b,
+                    Some(("!=", b)) => a != b,
+                    Some(("<=", b)) => a <= b,
+                    Some((">=", b)) => a >= b,
+                    Some(("<", b)) => a < b,
+                    Some((_, b)) => a > b,
+                },
+            ))
+        },
+    ))(input)
+}
+
+/// This parses the condition of an `#if`, which is made of single conditions
+/// joined with `&&` and `||`. `&&` is applied before `||`.
+fn condition(input: &str) -> ParseResult<'_, bool> {
+    let all = |input| {
+        map(separated_nonempty_list(tag("&&"), condition_atom), |c| {
+            c.into_iter().all(|holds| holds)
+        })(input)
+    };
+    map(separated_nonempty_list(tag("||"), all), |c| {
+        c.into_iter().any(|holds| holds)
+    })(input)
+}
+
+/// This parses `#if CONDITION`, and returns whether the condition holds.
+/// The condition is the rest of the line, and all of it must be parsed.
+fn hash_if(input: &str) -> ParseResult<'_, bool> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("#if")(input)?;
+    let (line, rest) = input.split_at(input.find('\n').unwrap_or(input.len()));
+
+    *IN_CONDITION.lock() = true;
+    let result = context(
+        Error::INVALID_CONDITION,
+        cut(all_consuming(terminated(condition, space0))),
+    )(line);
+    *IN_CONDITION.lock() = false;
+    let (_, holds) = result?;
+    Ok((rest, holds))
+}
+
+/// This parses `#else` or `#endif`
+fn hash_tag<'a>(name: &'static str) -> impl Fn(&'a str) -> ParseResult<'a, &'a str> {
+    move |input| delimited(multispace0, tag(name), multispace0)(input)
+}
+
+/// This skips the code in a branch of an `#if` that is not assembled, up to the `#else`
+/// or `#endif` that ends the branch. The code is not parsed, so it can use registers
+/// and constants that are not defined. A `#` in a string, a character, or the code
+/// of a `raw` instruction does not start a directive.
+fn skip_branch(input: &str) -> ParseResult<'_, ()> {
+    let literal = alt((
+        map(
+            delimited(
+                tag(RAW_DELIMITER),
+                take_until(RAW_DELIMITER),
+                tag(RAW_DELIMITER),
+            ),
+            |_| (),
+        ),
+        map(string, |_| ()),
+        map(ch, |_| ()),
+    ));
+
+    let mut depth = 0;
+    let mut rest = input;
+    while let Some(next) = rest.chars().next() {
+        if let Ok((after, _)) = literal(rest) {
+            rest = after;
+            continue;
+        }
+        if rest.starts_with("#if") {
+            depth += 1;
+        } else if rest.starts_with("#else") || rest.starts_with("#endif") {
+            if depth == 0 {
+                return Ok((rest, ()));
+            }
+            if rest.starts_with("#endif") {
+                depth -= 1;
+            }
+        }
+        rest = &rest[next.len_utf8()..];
+    }
+    Err(nom::Err::Failure(VerboseError {
+        errors: vec![(input, VerboseErrorKind::Context(Error::UNMATCHED_DIRECTIVE))],
+    }))
+}
+
+/// This parses an `#if` in the body of a procedure or macro. Only the instructions in
+/// the branch that is chosen are kept, and the other branch is skipped without parsing it.
+fn conditional(input: &str) -> ParseResult<'_, Vec<Exec>> {
+    let (input, holds) = hash_if(input)?;
+    let branch = |assembled: bool| {
+        move |input| match assembled {
+            true => body(input),
+            false => map(skip_branch, |_| Vec::new())(input),
+        }
+    };
+    let (input, code) = cut(branch(holds))(input)?;
+    let (input, other) = opt(preceded(hash_tag("#else"), cut(branch(!holds))))(input)?;
+    let (input, _) = context(Error::UNMATCHED_DIRECTIVE, cut(hash_tag("#endif")))(input)?;
+    Ok((
+        input,
+        if holds {
+            code
+        } else {
+            other.unwrap_or_default()
+        },
+    ))
+}
+
+/// This parses a use of a macro, such as `incr counter`, and expands it into the
+/// instructions of the macro's body. Each argument is a single word, and the
+/// arguments are written on the same line as the name.
+fn macro_call(input: &str) -> ParseResult<'_, Vec<Exec>> {
+    let (input, _) = multispace0(input)?;
+    let start = input;
+    let (input, (name, mac)) = map_opt(
+        take_while1(|ch: char| ch.is_alphanumeric() || ch == '_'),
+        |name| Some((name, MACROS.lock().get(name).cloned()?)),
+    )(input)?;
+
+    let failure = |context| {
+        nom::Err::Failure(VerboseError {
+            errors: vec![(start, VerboseErrorKind::Context(context))],
+        })
+    };
+
+    let mut args = Vec::new();
+    let mut input = input;
+    for _ in &mac.params {
+        // A missing argument must not take the `endproc` or the next line with it
+        let arg: ParseResult<'_, &str> = preceded(
+            space1,
+            verify(take_while1(|ch: char| !ch.is_whitespace()), |arg: &str| {
+                !matches!(arg, "endproc" | "endmacro")
+            }),
+        )(input);
+        let (rest, arg) = arg.map_err(|_| failure(Error::INVALID_MACRO_EXPANSION))?;
+        args.push(arg);
+        input = rest;
+    }
+    let (input, _) = multispace0(input)?;
+    if EXPANDING.lock().iter().any(|m| m == name) {
+        return Err(failure(Error::RECURSIVE_MACRO));
+    }
+
+    let code = mac.expand(&args);
+    EXPANDING.lock().push(name.to_string());
+    let result =
+        match body(&code) {
+            Ok((rest, code)) if rest.trim().is_empty() => Ok(code),
+            Err(nom::Err::Failure(e))
+                if e.errors.iter().any(|(_, kind)| {
+                    *kind == VerboseErrorKind::Context(Error::RECURSIVE_MACRO)
+                }) =>
+            {
+                Err(failure(Error::RECURSIVE_MACRO))
+            }
+            Err(nom::Err::Failure(e)) => {
+                // The error points into the expanded code, which is dropped when this
+                // returns, so it is converted now. A macro used inside this one has
+                // already kept its own error, which is the innermost.
+                if !e.errors.iter().any(|(_, kind)| {
+                    *kind == VerboseErrorKind::Context(Error::INVALID_MACRO_EXPANSION)
+                }) {
+                    *EXPANSION_ERROR.lock() = Some(Error::from(e));
+                }
+                Err(failure(Error::INVALID_MACRO_EXPANSION))
+            }
+            _ => Err(failure(Error::INVALID_MACRO_EXPANSION)),
+        };
+    EXPANDING.lock().pop();
+    Ok((input, result?))
+}
+
+/// This parses the instructions in the body of a procedure or a macro
+fn body(input: &str) -> ParseResult<'_, Vec<Exec>> {
+    map(
+        many0(alt((
+            push_string,
+            conditional,
+            macro_call,
+            map(instruction, |expr| vec![expr]),
+        ))),
+        |code| code.into_iter().flatten().collect(),
+    )(input)
+}
+
+/// This parses the optional `inline` or `noinline` annotation before a procedure
+fn inlining(input: &str) -> ParseResult<'_, Inline> {
+    let (input, _) = multispace0(input)?;
+    let (input, inline) = opt(alt((
+        map(tag("inline"), |_| Inline::Always),
+        map(tag("noinline"), |_| Inline::Never),
+    )))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, inline.unwrap_or(Inline::Auto)))
+}
+
+/// This parses a procedure's signature, such as `(2 -> 1)`. The first number is
+/// the number of cells the procedure pops, and the second is the number it pushes.
+fn signature(input: &str) -> ParseResult<'_, StackEffect> {
+    let (input, _) = char('(')(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, pops) = context(Error::INVALID_SIGNATURE, cut(size))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = context(Error::INVALID_SIGNATURE, cut(tag("->")))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, pushes) = context(Error::INVALID_SIGNATURE, cut(size))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = context(Error::INVALID_SIGNATURE, cut(char(')')))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, StackEffect::new(pops, pushes)))
+}
+
+/// This parses a parenthesized list of register names, such as `(a, b)`
+fn names(input: &str) -> ParseResult<'_, Vec<&str>> {
+    let (input, _) = char('(')(input)?;
+    let (input, names) = separated_list(char(','), identifier)(input)?;
+    let (input, _) = char(')')(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, names))
+}
+
+/// This parses a procedure's named parameters and results, such as `(a, b) -> (r)`.
+/// The results are optional.
+fn parameters(input: &str) -> ParseResult<'_, (Vec<&str>, Vec<&str>)> {
+    let (input, params) = names(input)?;
+    let (input, results) = opt(|input| {
+        let (input, _) = tag("->")(input)?;
+        let (input, _) = multispace0(input)?;
+        context(Error::INVALID_PARAMETERS, cut(names))(input)
+    })(input)?;
+    Ok((input, (params, results.unwrap_or_default())))
+}
+
+/// The registers that were bound to the names of a procedure's parameters and results
+/// before the procedure, or `None` for names that were not bound
+type Shadowed = Vec<(String, Option<Register>)>;
+
+/// Define the registers for a procedure's parameters and results. Any registers
+/// they shadow are returned, so that they can be restored at the end of the procedure.
+/// `None` is returned if the registers do not fit in memory.
+fn define_parameters(
+    params: &[&str],
+    results: &[&str],
+) -> Option<(Vec<Register>, Vec<Register>, Shadowed)> {
+    let mut shadowed = Vec::new();
+    let mut defined = BTreeMap::new();
+    let mut define = |name: &&str| {
+        // A name that is both a parameter and a result is a single register
+        if let Some(register) = defined.get(*name) {
+            return Some(Register::clone(register));
+        }
+        shadowed.push((name.to_string(), Register::named(name)));
+        let register = Register::define(name, 1)?;
+        defined.insert(name.to_string(), register.clone());
+        Some(register)
+    };
+    let params = params.iter().map(&mut define).collect::<Option<_>>()?;
+    let results = results.iter().map(&mut define).collect::<Option<_>>()?;
+    Some((params, results, shadowed))
+}
+
+fn procedure(input: &str) -> ParseResult<'_, Procedure> {
+    // Loop labels are only visible inside of the procedure that defines them
+    LOOP_LABELS.lock().clear();
+    let (input, inline) = inlining(input)?;
     let (input, _) = context(Error::INVALID_PROCEDURE, tag("proc"))(input)?;
     let (input, name) = context(Error::NO_PROC_NAME, identifier)(input)?;
-    let (input, code) = cut(many0(instruction))(input)?;
+    let (input, parameters) = opt(parameters)(input)?;
+    let (params, results) = parameters.unwrap_or_default();
+    let (input, signature) = opt(signature)(input)?;
+
+    // The parameters and results are registers that are only visible inside of the procedure
+    let (params, results, shadowed) =
+        define_parameters(&params, &results).ok_or_else(|| too_large(name))?;
+    let body = cut(body)(input);
+    for (name, register) in shadowed {
+        let mut registers = NAMED_REGISTERS.lock();
+        match register {
+            Some(register) => registers.insert(name, register),
+            None => registers.remove(&name),
+        };
+    }
+    let (input, body) = body?;
     let (input, _) = context(Error::INVALID_PROCEDURE, tag("endproc"))(input)?;
-    let (input, _) = space0(input)?;
-    Ok((input, Procedure::new(name, code)))
+    let (input, _) = multispace0(input)?;
+
+    // The arguments are stored into the parameters from the top of the stack down,
+    // so the first parameter gets the deepest argument. The results are pushed in order.
+    let mut code = Vec::new();
+    for r in params.iter().chain(&results) {
+        if !code.contains(&Exec::Define(r.clone())) {
+            code.push(Exec::Define(r.clone()));
+        }
+    }
+    code.extend(
+        params
+            .iter()
+            .rev()
+            .map(|r| Exec::asm(Instruct::Store(r.clone()))),
+    );
+    code.extend(body);
+    code.extend(results.iter().map(|r| Exec::asm(Instruct::Load(r.clone()))));
+    Ok((input, Procedure::new(name, inline, signature, code)))
 }
 
-pub fn program(mut input: &str) -> Result<(Ast, usize)> {
-    let stack_size;
-    match (|input| -> ParseResult<usize> {
-        let (input, _) = space0(input)?;
-        let (input, _) = tag("stack_size")(input)?;
-        let (input, _) = space0(input)?;
-        let (input, n) = size(input)?;
-        let (input, _) = space0(input)?;
+/// Parses the optional `stack_size` flag at the top of the file.
+/// `None` is returned for `stack_size auto`.
+fn stack_size(input: &str) -> ParseResult<'_, Option<usize>> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("stack_size")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, n) = context(
+        Error::INVALID_SIZE,
+        cut(alt((map(tag("auto"), |_| None), map(size, Some)))),
+    )(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, n))
+}
 
-        Ok((input, n))
+/// Parses the name of a struct, along with the number of cells it occupies
+fn struct_size(input: &str) -> ParseResult<'_, (&str, usize)> {
+    map_opt(identifier, |name| {
+        let structs = STRUCTS.lock();
+        let size = structs.get(name)?.iter().map(|f| f.size).sum();
+        Some((name, size))
     })(input)
-    {
-        Ok((i, s)) => {
-            input = i;
-            stack_size = s;
+}
+
+/// Parses a field in a struct declaration. A field occupies a single cell,
+/// unless it is followed by `: STRUCT` or `: SIZE`.
+fn field_decl(input: &str) -> ParseResult<'_, (&str, Option<&str>, usize)> {
+    let (input, name) = identifier(input)?;
+    let (input, ty) = opt(|input| {
+        let (input, _) = char(':')(input)?;
+        alt((
+            map(struct_size, |(layout, size)| (Some(layout), size)),
+            map(size, |size| (None, size)),
+        ))(input)
+    })(input)?;
+    let (layout, size) = ty.unwrap_or((None, 1));
+    Ok((input, (name, layout, size)))
+}
+
+/// Parses a `struct NAME { FIELD, ... }` declaration, which can be written before
+/// and between procedures. The fields are laid out in order, without any padding.
+fn structure(input: &str) -> ParseResult<'_, ()> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("struct")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, name) = context(Error::INVALID_IDENTIFIER, cut(identifier))(input)?;
+    let (input, _) = context(Error::INVALID_STRUCT, cut(char('{')))(input)?;
+    let (input, decls) = context(
+        Error::INVALID_STRUCT,
+        cut(separated_nonempty_list(char(','), field_decl)),
+    )(input)?;
+    let (input, _) = context(Error::INVALID_STRUCT, cut(char('}')))(input)?;
+    let (input, _) = multispace0(input)?;
+
+    let mut offset = 0;
+    let mut fields = Vec::new();
+    for (name, layout, size) in decls {
+        fields.push(Field {
+            name: name.to_string(),
+            offset,
+            size,
+            layout: layout.map(String::from),
+        });
+        offset = offset
+            .checked_add(size)
+            .filter(|size| *size <= MAX_SIZE)
+            .ok_or_else(|| too_large(name))?;
+    }
+    STRUCTS.lock().insert(name.to_string(), fields);
+    Ok((input, ()))
+}
+
+/// Parses a directive that can be written before and between procedures
+fn directive(input: &str) -> ParseResult<'_, ()> {
+    alt((constant, structure, macro_decl, extern_decl))(input)
+}
+
+/// Parses an `extern proc NAME (ARGS -> RETS)` declaration, which can be written
+/// before and between procedures. An extern procedure is implemented by the host
+/// of the target, and can be declared before or after it is called.
+fn extern_decl(input: &str) -> ParseResult<'_, ()> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("extern")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, (name, effect)) = context(
+        Error::INVALID_EXTERN,
+        cut(verify(
+            preceded(tag("proc"), pair(identifier, signature)),
+            |(name, effect): &(&str, StackEffect)| {
+                // An extern procedure can be declared again with the same stack effect
+                EXTERNS
+                    .lock()
+                    .get(*name)
+                    .map_or(true, |declared| declared == effect)
+            },
+        )),
+    )(input)?;
+    EXTERNS.lock().insert(name.to_string(), effect);
+    Ok((input, ()))
+}
+
+/// Parses a `macro NAME PARAM ...` declaration, which can be written before and between
+/// procedures. The parameters are written on the same line as the name, and the body
+/// continues until `endmacro`.
+fn macro_decl(input: &str) -> ParseResult<'_, ()> {
+    let word = |input| take_while1(|ch: char| ch.is_alphanumeric() || ch == '_')(input);
+
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("macro")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, (name, mac)) = context(
+        Error::INVALID_MACRO,
+        cut(map(
+            pair(
+                verify(word, |name: &str| !is_keyword(name)),
+                pair(
+                    many0(preceded(space1, word)),
+                    preceded(pair(space0, line_ending), take_until("endmacro")),
+                ),
+            ),
+            |(name, (params, body)): (&str, (Vec<&str>, &str))| {
+                let mac = Macro {
+                    params: params.into_iter().map(ToString::to_string).collect(),
+                    body: body.to_string(),
+                };
+                (name, mac)
+            },
+        )),
+    )(input)?;
+    let (input, _) = tag("endmacro")(input)?;
+    let (input, _) = multispace0(input)?;
+    MACROS.lock().insert(name.to_string(), mac);
+    Ok((input, ()))
+}
+
+/// Parses a `const NAME = EXPRESSION` directive, which can be written before
+/// and between procedures. The expression is evaluated and stored under the name.
+fn constant(input: &str) -> ParseResult<'_, ()> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("const")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, name) = context(Error::INVALID_IDENTIFIER, cut(identifier))(input)?;
+    let (input, _) = context(Error::INVALID_CONSTANT, cut(char('=')))(input)?;
+    let (input, value) = context(Error::INVALID_CONSTANT, cut(expression))(input)?;
+    CONSTANTS.lock().insert(name.to_string(), value);
+    Ok((input, ()))
+}
+
+/// Parses an `include "PATH"` or `include std:MODULE` directive, which can be written
+/// before and between procedures. The path of the included file is returned.
+fn include(input: &str) -> ParseResult<'_, String> {
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("include")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, path) = context(
+        Error::INVALID_INCLUDE,
+        cut(alt((
+            map(string, |path| path.into_iter().collect()),
+            map(preceded(tag("std:"), identifier), |name| {
+                format!("std:{}", name)
+            }),
+        ))),
+    )(input)?;
+    Ok((input, path))
+}
+
+/// Convert an error returned by a parser into an Error
+fn error(e: nom::Err<VerboseError<&str>>) -> Error {
+    let error = match e {
+        nom::Err::Error(e) => Error::from(e),
+        nom::Err::Failure(f) => Error::from(f),
+        e => Error::Unknown(format!("{:?}", e)),
+    };
+    // A macro that failed to expand reports the error in its body instead
+    match (error, EXPANSION_ERROR.lock().take()) {
+        (Error::InvalidMacroExpansion(_), Some(inner)) => inner,
+        (error, _) => error,
+    }
+}
+
+/// The files included while parsing a program
+struct Includes<'a> {
+    /// The loader used to find included files
+    loader: &'a dyn Loader,
+    /// The files being parsed, from the root file to the innermost included file
+    stack: Vec<String>,
+    /// The files that have already been included. Each file is only included once.
+    done: BTreeSet<String>,
+}
+
+impl Includes<'_> {
+    /// Parse the directives before a procedure, including the files they name
+    /// and the `#if` blocks they are in
+    fn directives<'a>(
+        &mut self,
+        mut input: &'a str,
+        procs: &mut Vec<Procedure>,
+    ) -> Result<&'a str> {
+        loop {
+            let (rest, _) = many0(directive)(input).map_err(error)?;
+            input = match include(rest) {
+                Ok((rest, path)) => {
+                    self.include(&path, procs)?;
+                    rest
+                }
+                Err(nom::Err::Error(_)) => match self.conditional(rest, procs)? {
+                    Some(rest) => rest,
+                    None => return Ok(rest),
+                },
+                Err(e) => return Err(error(e)),
+            }
         }
-        Err(_) => stack_size = DEFAULT_STACK_SIZE,
     }
 
-    let res = context(Error::NO_PROC_FOUND, many1(procedure))(input);
+    /// Parse an `#if` block around procedures and directives, if there is one
+    fn conditional<'a>(
+        &mut self,
+        input: &'a str,
+        procs: &mut Vec<Procedure>,
+    ) -> Result<Option<&'a str>> {
+        let (input, holds) = match hash_if(input) {
+            Ok(result) => result,
+            Err(nom::Err::Error(_)) => return Ok(None),
+            Err(e) => return Err(error(e)),
+        };
+        let mut branch = |input, assembled| match assembled {
+            true => self.items(input, procs),
+            false => Ok(skip_branch(input).map_err(error)?.0),
+        };
 
-    match res {
-        Ok((_, procs)) => Ok((Ast::new(procs), stack_size)),
-        Err(e) => match e {
-            nom::Err::Error(e) => Err(Error::from(e)),
-            nom::Err::Failure(f) => Err(Error::from(f)),
-            e => Err(Error::Unknown(format!("{:?}", e))),
-        },
+        let mut input = branch(input, holds)?;
+        if let Ok((rest, _)) = hash_tag("#else")(input) {
+            input = branch(rest, !holds)?;
+        }
+        let (input, _) =
+            context(Error::UNMATCHED_DIRECTIVE, cut(hash_tag("#endif")))(input).map_err(error)?;
+        Ok(Some(input))
     }
+
+    /// Parse procedures and directives until there are no more, and return the rest
+    fn items<'a>(&mut self, mut input: &'a str, procs: &mut Vec<Procedure>) -> Result<&'a str> {
+        loop {
+            input = self.directives(input, procs)?;
+            match procedure(input) {
+                Ok((rest, mut prc)) => {
+                    if self
+                        .stack
+                        .last()
+                        .is_some_and(|file| stdlib::module(file).is_some())
+                    {
+                        prc.set_stdlib();
+                    }
+                    procs.push(prc);
+                    input = rest;
+                }
+                Err(nom::Err::Error(_)) => return Ok(input),
+                Err(e) => return Err(error(e)),
+            }
+        }
+    }
+
+    /// Parse the procedures and directives in a file
+    fn file(&mut self, input: &str, procs: &mut Vec<Procedure>) -> Result<()> {
+        let input = self.items(input, procs)?;
+        // An `#else` or `#endif` can only end the branch of an `#if`
+        match alt((hash_tag("#else"), hash_tag("#endif")))(input) {
+            Ok((_, directive)) => Err(Error::UnmatchedDirective(directive.to_string())),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Parse the procedures and directives in an included file
+    fn include(&mut self, path: &str, procs: &mut Vec<Procedure>) -> Result<()> {
+        let from = self.stack.last().map(String::as_str).unwrap_or_default();
+        let (name, contents) = match stdlib::module(path) {
+            Some(contents) => (path.to_string(), contents.to_string()),
+            None => self
+                .loader
+                .load(from, path)
+                .ok_or_else(|| Error::IncludeNotFound(path.to_string()))?,
+        };
+
+        if let Some(i) = self.stack.iter().position(|file| *file == name) {
+            let mut cycle = self.stack[i..].to_vec();
+            cycle.push(name);
+            return Err(Error::IncludeCycle(cycle));
+        }
+        if self.done.contains(&name) {
+            return Ok(());
+        }
+
+        self.stack.push(name.clone());
+        let result = self.file(&strip_comments(contents), procs);
+        self.stack.pop();
+        result.map_err(|e| match e {
+            // A cycle already names every file it passes through
+            Error::IncludeCycle(_) => e,
+            e => Error::InFile(name.clone(), Box::new(e)),
+        })?;
+        self.done.insert(name);
+        Ok(())
+    }
+}
+
+/// Find the code of the first `raw` instruction in a file, and return where the
+/// instruction starts and where its code ends. A `raw` in a comment, a string,
+/// or a character is not an instruction.
+fn raw_code(source: &str) -> Option<(usize, usize)> {
+    let literal = alt((map(string, |_| ()), map(ch, |_| ())));
+    let raw = tuple((
+        tag("raw"),
+        multispace1,
+        identifier,
+        tag(RAW_DELIMITER),
+        take_until(RAW_DELIMITER),
+        tag(RAW_DELIMITER),
+    ));
+
+    let mut i = 0;
+    while let Some(next) = source[i..].chars().next() {
+        let rest = &source[i..];
+        let is_word_start = !source[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|ch| ch.is_alphanumeric() || ch == '_');
+        if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            i += comment.find("*/").map_or(rest.len(), |end| end + 4);
+        } else if let Ok((after, _)) = literal(rest) {
+            i += rest.len() - after.len();
+        } else if let (true, Ok((after, _))) = (is_word_start, raw(rest)) {
+            return Some((i, i + rest.len() - after.len()));
+        } else {
+            i += next.len_utf8();
+        }
+    }
+    None
+}
+
+/// Remove the comments from a file. The code of `raw` instructions is written
+/// in the language of a target, so it is kept exactly as it is.
+pub fn strip_comments(source: impl core::fmt::Display) -> String {
+    let source = source.to_string();
+    let mut result = String::new();
+    let mut rest = source.as_str();
+    while let Some((start, end)) = raw_code(rest) {
+        result += &comment::c::strip(&rest[..start]).unwrap();
+        result += &rest[start..end];
+        rest = &rest[end..];
+    }
+    result + &comment::c::strip(rest).unwrap()
+}
+
+/// Parses a program, along with the files it includes. `name` is the name of the
+/// file the program was read from, and included files are found using the loader.
+/// `target` is the name of the target, and `options` gives the constants and features
+/// defined on the command line. These are used by `#if`.
+pub fn program(
+    input: &str,
+    name: &str,
+    loader: &dyn Loader,
+    target: &str,
+    options: &Options,
+) -> Result<(Ast, Option<usize>)> {
+    // Constants, structs, macros, and extern procedures are only visible
+    // in the program that defines them
+    CONSTANTS.lock().clear();
+    STRUCTS.lock().clear();
+    REGISTER_STRUCTS.lock().clear();
+    MACROS.lock().clear();
+    EXPANDING.lock().clear();
+    *EXPANSION_ERROR.lock() = None;
+    EXTERNS.lock().clear();
+    // A program that failed to parse can leave the labels of its open loops behind
+    LOOP_LABELS.lock().clear();
+
+    *TARGET.lock() = target.to_string();
+    *FEATURES.lock() = options.features.clone();
+    for (name, value) in &options.defines {
+        CONSTANTS.lock().insert(name.clone(), Literal::num(*value));
+    }
+
+    let mut includes = Includes {
+        loader,
+        stack: vec![name.to_string()],
+        done: BTreeSet::new(),
+    };
+    let mut procs = Vec::new();
+
+    let input = includes.directives(input, &mut procs)?;
+    let (input, stack_size) = opt(stack_size)(input).map_err(error)?;
+    includes.file(input, &mut procs)?;
+
+    if procs.is_empty() {
+        return Err(Error::NoProcedureFound);
+    }
+    let mut names = BTreeSet::new();
+    let externs = EXTERNS.lock();
+    if let Some(prc) = procs
+        .iter()
+        .find(|prc| !names.insert(prc.get_name()) || externs.contains_key(prc.get_name()))
+    {
+        return Err(Error::DuplicateProcedure(prc.get_name().to_string()));
+    }
+    for prc in &mut procs {
+        prc.call_externs(&externs);
+    }
+
+    Ok((
+        Ast::new(procs),
+        stack_size.unwrap_or(Some(DEFAULT_STACK_SIZE)),
+    ))
 }
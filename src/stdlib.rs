@@ -0,0 +1,33 @@
+//! # stdlib, the module that provides lasm's standard library
+//!
+//! The standard library is a set of lasm files embedded in the crate, which any
+//! program can include with `include std:MODULE`. The standard library is versioned
+//! with the crate, so a program always uses the standard library of the assembler
+//! that assembles it.
+//!
+//! | Module | Procedures |
+//! |--------|------------|
+//! | `std:io` | `nl`, `print`, `cprint`, `cscan` |
+//! | `std:cmp` | `less`, `less_eq`, `greater`, `greater_eq`, `equal`, `nequal`, `min`, `max`, `and`, `or`, `not`, `bool` |
+//! | `std:string` | `strlen`, `strcpy`, `strcat`, `streq` |
+//! | `std:mem` | `last_ptr`, `last`, `memzero`, `memeq` |
+
+/// The version of the standard library
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The modules of the standard library and their source code, by name
+pub const MODULES: &[(&str, &str)] = &[
+    ("io", include_str!("stdlib/io.lasm")),
+    ("cmp", include_str!("stdlib/cmp.lasm")),
+    ("string", include_str!("stdlib/string.lasm")),
+    ("mem", include_str!("stdlib/mem.lasm")),
+];
+
+/// Get the source code of the module included with a path such as `std:io`
+pub fn module(path: &str) -> Option<&'static str> {
+    let name = path.strip_prefix("std:")?;
+    MODULES
+        .iter()
+        .find(|(module, _)| *module == name)
+        .map(|(_, source)| *source)
+}
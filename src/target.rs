@@ -3,44 +3,628 @@
 //! As stated in the top level documentation of the crate,
 //! the purpose of lasm is to be as portable as possible.
 //! To maximize portability, the `target` module provides the
-//! `Target` trait and a few builtin implementations for 
+//! `Target` trait and a few builtin implementations for
 //! common programming languages.
-//! 
+//!
 //! Using the Instruct enum in the `asm` module, though,
 //! Target can be implemented for other programming languages
 //! by other crates that use this library. Additionally,
 //! uses can write more optimized implementations for languages
 //! that already have one.
 
-use crate::Instruct;
-use alloc::{string::String, vec::Vec};
+use crate::{Error, Instruct, Program, Result};
+use alloc::{collections::BTreeSet, string::String, vec::Vec};
 
 /// This trait should be implemented for a struct that represents
 /// a target language that lasm assembles to.
 pub trait Target {
-    /// This function assembles a list of instructions with a given stack size and initial stack pointer.
+    /// The name of the target, which programs can test with `#if target == "NAME"`.
+    /// By default, this is the name of the type that implements the trait, without
+    /// its module path, so a target named `struct Python;` is named `"Python"`.
+    fn name(&self) -> &str {
+        let name = core::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// This function assembles a program with a given stack size and initial stack pointer.
     ///
     /// The reason the initial stack pointer is necessary is because the output code must know how large
     /// the memory allocated for registers is. Without the initial stack pointer, the output code would
     /// have no clue how much memory registers use.
-    fn assemble(&self, initial_stack_ptr: usize, stack_size: usize, code: Vec<Instruct>) -> String;
+    ///
+    /// Procedures that were outlined are stored in the program's `procs` map, and must be
+    /// assembled as subroutines that are called by `Instruct::Call`. The registers in the
+    /// program's `data` map must be filled with their cells before the entry point runs.
+    /// An `Instruct::CallExtern` must be assembled as a call to a function of the host.
+    /// `Instruct::CallIndirect` calls the outlined procedure whose identifier, given by
+    /// `Program::proc_id`, it pops off of the stack.
+    ///
+    /// The code of an `Instruct::Raw` is emitted verbatim if it was written for this target,
+    /// and `Error::RawTargetMismatch` is returned if it was written for any other target.
+    fn assemble(&self, program: Program) -> Result<String>;
 }
 
 /// C is a target
 pub struct C;
 
+/// The names that a host function can't have in C: the keywords of C, the functions
+/// and constants of the runtime, and the variables in scope where it is called.
+/// Every name that starts with `lasm_` is reserved for the runtime as well.
+const C_RESERVED: &[&str] = &[
+    // keywords, including the types and operators defined by the standard headers
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "bool",
+    "true",
+    "false",
+    "int64_t",
+    "and",
+    "or",
+    "not",
+    "xor",
+    "bitand",
+    "bitor",
+    "compl",
+    // the runtime
+    "INIT_STACK_PTR",
+    "MEMORY_SIZE",
+    "BOUNDS_CHECKS",
+    "ACC",
+    "SPR",
+    "init",
+    "push_cell",
+    "pop_cell",
+    "deref_load",
+    "deref_store",
+    "deref_load_n",
+    "deref_store_n",
+    "store",
+    "load",
+    "add",
+    "sub",
+    "mul",
+    "neg",
+    "to_int",
+    "pop_index",
+    "load_idx",
+    "store_idx",
+    "band",
+    "bor",
+    "bxor",
+    "bnot",
+    "shl",
+    "shr",
+    "dup",
+    "swap",
+    "over",
+    "rot",
+    "pick",
+    "drop",
+    "cmp",
+    "lt",
+    "le",
+    "gt",
+    "ge",
+    "eq",
+    "ne",
+    "outc",
+    "outn",
+    "inc",
+    "inn",
+    "pop_bool",
+    "pop_args",
+    "push_rets",
+    "main",
+    // the variables in scope at a call
+    "tape",
+    "alloc_tape",
+    "args",
+    "rets",
+];
+
+/// The functions of `math.h` that only take and return `double`s, along with the number
+/// of arguments they take. These are already declared by the headers the runtime includes,
+/// so an extern procedure can only be named after one if it has the same prototype.
+const C_MATH_FUNCTIONS: &[(&str, usize)] = &[
+    ("acos", 1),
+    ("asin", 1),
+    ("atan", 1),
+    ("atan2", 2),
+    ("cos", 1),
+    ("sin", 1),
+    ("tan", 1),
+    ("acosh", 1),
+    ("asinh", 1),
+    ("atanh", 1),
+    ("cosh", 1),
+    ("sinh", 1),
+    ("tanh", 1),
+    ("exp", 1),
+    ("exp2", 1),
+    ("expm1", 1),
+    ("log", 1),
+    ("log10", 1),
+    ("log1p", 1),
+    ("log2", 1),
+    ("logb", 1),
+    ("cbrt", 1),
+    ("fabs", 1),
+    ("hypot", 2),
+    ("pow", 2),
+    ("sqrt", 1),
+    ("erf", 1),
+    ("erfc", 1),
+    ("lgamma", 1),
+    ("tgamma", 1),
+    ("ceil", 1),
+    ("floor", 1),
+    ("nearbyint", 1),
+    ("rint", 1),
+    ("round", 1),
+    ("trunc", 1),
+    ("fmod", 2),
+    ("remainder", 2),
+    ("copysign", 2),
+    ("nextafter", 2),
+    ("fdim", 2),
+    ("fmax", 2),
+    ("fmin", 2),
+    ("fma", 3),
+];
+
+/// The other names declared by the headers the runtime includes, which a host function
+/// can't have in C because their declarations would conflict. The `float` and `long double`
+/// versions of the functions in `C_MATH_FUNCTIONS`, such as `sqrtf`, are reserved as well.
+const C_HEADER_NAMES: &[&str] = &[
+    // stdio.h
+    "FILE",
+    "fpos_t",
+    "size_t",
+    "EOF",
+    "BUFSIZ",
+    "NULL",
+    "stdin",
+    "stdout",
+    "stderr",
+    "printf",
+    "fprintf",
+    "sprintf",
+    "snprintf",
+    "dprintf",
+    "scanf",
+    "fscanf",
+    "sscanf",
+    "puts",
+    "fputs",
+    "putchar",
+    "fputc",
+    "putc",
+    "getchar",
+    "fgetc",
+    "getc",
+    "gets",
+    "fgets",
+    "getline",
+    "ungetc",
+    "fopen",
+    "fdopen",
+    "fclose",
+    "freopen",
+    "fflush",
+    "fread",
+    "fwrite",
+    "fseek",
+    "ftell",
+    "rewind",
+    "fgetpos",
+    "fsetpos",
+    "fileno",
+    "clearerr",
+    "feof",
+    "ferror",
+    "perror",
+    "remove",
+    "rename",
+    "tmpfile",
+    "tmpnam",
+    "setbuf",
+    "setvbuf",
+    "popen",
+    "pclose",
+    // stdlib.h
+    "EXIT_SUCCESS",
+    "EXIT_FAILURE",
+    "RAND_MAX",
+    "abs",
+    "labs",
+    "llabs",
+    "div",
+    "ldiv",
+    "lldiv",
+    "atoi",
+    "atol",
+    "atoll",
+    "atof",
+    "strtod",
+    "strtof",
+    "strtold",
+    "strtol",
+    "strtoul",
+    "strtoll",
+    "strtoull",
+    "rand",
+    "srand",
+    "random",
+    "srandom",
+    "drand48",
+    "srand48",
+    "malloc",
+    "calloc",
+    "realloc",
+    "free",
+    "aligned_alloc",
+    "exit",
+    "abort",
+    "atexit",
+    "quick_exit",
+    "at_quick_exit",
+    "_Exit",
+    "getenv",
+    "setenv",
+    "unsetenv",
+    "putenv",
+    "system",
+    "bsearch",
+    "qsort",
+    "mkstemp",
+    "realpath",
+    // math.h
+    "INFINITY",
+    "NAN",
+    "HUGE_VAL",
+    "M_PI",
+    "M_E",
+    "isnan",
+    "isinf",
+    "isfinite",
+    "isnormal",
+    "signbit",
+    "fpclassify",
+    "frexp",
+    "ldexp",
+    "modf",
+    "nan",
+    "ilogb",
+    "lrint",
+    "llrint",
+    "lround",
+    "llround",
+    "scalbn",
+    "scalbln",
+    "remquo",
+    "nexttoward",
+    // stdint.h
+    "int8_t",
+    "int16_t",
+    "int32_t",
+    "uint8_t",
+    "uint16_t",
+    "uint32_t",
+    "uint64_t",
+    "intptr_t",
+    "uintptr_t",
+];
+
+impl C {
+    /// Whether a host function can be given the name of an extern procedure in C
+    fn is_valid_extern(name: &str, args: usize, rets: usize) -> bool {
+        let math = C_MATH_FUNCTIONS
+            .iter()
+            .find(|(function, _)| *function == name);
+        let is_math_variant = C_MATH_FUNCTIONS
+            .iter()
+            .any(|(function, _)| matches!(name.strip_prefix(function), Some("f") | Some("l")));
+        !name.starts_with("lasm_")
+            && !C_RESERVED.contains(&name)
+            && !C_HEADER_NAMES.contains(&name)
+            && !is_math_variant
+            && math.map_or(true, |(_, arity)| *arity == args && rets == 1)
+    }
+
+    /// Get the name of the C function an outlined procedure is assembled to
+    fn proc_name(name: &str) -> String {
+        format!("lasm_proc_{}", name)
+    }
+
+    /// Get the C expression for a cell's value. Infinities and NaN have no literals,
+    /// and integers too large for a C integer constant are written as doubles.
+    fn literal(value: f64) -> String {
+        if value.is_nan() {
+            String::from("NAN")
+        } else if value.is_infinite() {
+            String::from(if value > 0.0 { "INFINITY" } else { "-INFINITY" })
+        } else if value.abs() >= 1e15 {
+            format!("{:e}", value)
+        } else {
+            format!("{}", value)
+        }
+    }
+
+    /// Get the C prototype of the host function that implements an extern procedure.
+    /// The arguments are passed as doubles, and a single result is returned. More than
+    /// one result is written to an array passed after the arguments.
+    fn extern_prototype(name: &str, args: usize, rets: usize) -> String {
+        let mut params = vec!["double"; args];
+        if rets > 1 {
+            params.push("double rets[]");
+        }
+        format!(
+            "{} {}({});",
+            if rets == 1 { "double" } else { "void" },
+            name,
+            if params.is_empty() {
+                String::from("void")
+            } else {
+                params.join(", ")
+            }
+        )
+    }
+
+    /// Get the C statement that calls the host function of an extern procedure
+    fn extern_call(name: &str, args: usize, rets: usize) -> String {
+        let mut result = String::from("{ ");
+        let mut params = (0..args)
+            .map(|i| format!("args[{}]", i))
+            .collect::<Vec<_>>();
+        if args > 0 {
+            result += &format!("double args[{n}]; pop_args(tape, args, {n}); ", n = args);
+        }
+        if rets > 1 {
+            result += &format!("double rets[{}]; ", rets);
+            params.push(String::from("rets"));
+        }
+        let call = format!("{}({})", name, params.join(", "));
+        result += &match rets {
+            0 => format!("{};", call),
+            1 => format!("push_cell(tape, {});", call),
+            n => format!("{}; push_rets(tape, rets, {});", call, n),
+        };
+        result + " }"
+    }
+
+    /// Get the number of the loop that a `break` or `continue` with a depth of `n`
+    /// refers to, out of the open loops. A program that was not assembled from
+    /// assembly code can refer to a loop that is not open.
+    fn enclosing_loop(loops: &[usize], n: usize) -> Result<usize> {
+        match loops.len().checked_sub(n + 1) {
+            Some(i) => Ok(loops[i]),
+            None => Err(Error::UnmatchedLoop),
+        }
+    }
+
+    /// Find the loops that are left or continued from inside of another loop.
+    /// C's `break` and `continue` only apply to the innermost loop, so these loops
+    /// need labels to jump to. Loops are numbered in the order they start.
+    fn labeled_loops(code: &[Instruct]) -> Result<(BTreeSet<usize>, BTreeSet<usize>)> {
+        let mut breaks = BTreeSet::new();
+        let mut continues = BTreeSet::new();
+        let mut loops = Vec::new();
+        let mut count = 0;
+        for line in code {
+            match line {
+                Instruct::WhileNotZero | Instruct::While => {
+                    loops.push(count);
+                    count += 1;
+                }
+                Instruct::EndWhile | Instruct::EndDo => {
+                    loops.pop();
+                }
+                Instruct::Break(n) => {
+                    let id = Self::enclosing_loop(&loops, *n)?;
+                    if *n > 0 {
+                        breaks.insert(id);
+                    }
+                }
+                Instruct::Continue(n) => {
+                    let id = Self::enclosing_loop(&loops, *n)?;
+                    if *n > 0 {
+                        continues.insert(id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok((breaks, continues))
+    }
+
+    /// Assemble a list of instructions from a program into C statements
+    fn assemble_code(&self, code: &[Instruct], program: &Program) -> Result<String> {
+        let (breaks, continues) = Self::labeled_loops(code)?;
+        let mut loops = Vec::new();
+        let mut count = 0;
+
+        let mut result = String::new();
+        for line in code {
+            result += &(String::from("    ")
+                + &match line {
+                    Instruct::Refer(r) => format!("push_cell(tape, {});", r.get_addr()),
+                    Instruct::DerefLoad => String::from("deref_load(tape);"),
+                    Instruct::DerefStore => String::from("deref_store(tape);"),
+                    Instruct::DerefLoadN(n) => format!("deref_load_n(tape, {});", n),
+                    Instruct::DerefStoreN(n) => format!("deref_store_n(tape, {});", n),
+                    Instruct::MemCopy => String::from("lasm_memcpy(tape);"),
+                    Instruct::MemSet => String::from("lasm_memset(tape);"),
+                    Instruct::Alloc(r) => {
+                        format!("lasm_alloc(tape, alloc_tape, {});", r.get_addr())
+                    }
+                    Instruct::Free(r) => format!("lasm_free(tape, alloc_tape, {});", r.get_addr()),
+                    Instruct::Load(r) => format!("load(tape, {}, {});", r.get_addr(), r.get_size()),
+                    Instruct::Store(r) => {
+                        format!("store(tape, {}, {});", r.get_addr(), r.get_size())
+                    }
+                    Instruct::LoadIndex(r) => {
+                        format!("load_idx(tape, {}, {});", r.get_addr(), r.get_size())
+                    }
+                    Instruct::StoreIndex(r) => {
+                        format!("store_idx(tape, {}, {});", r.get_addr(), r.get_size())
+                    }
+                    Instruct::Push(l) => format!("push_cell(tape, {});", Self::literal(l.get())),
+                    Instruct::Pop => String::from("pop_cell(tape, ACC);"),
+                    Instruct::Duplicate => String::from("dup(tape);"),
+                    Instruct::Swap => String::from("swap(tape);"),
+                    Instruct::Over => String::from("over(tape);"),
+                    Instruct::Rotate => String::from("rot(tape);"),
+                    Instruct::Pick(n) => format!("pick(tape, {});", n),
+                    Instruct::Drop(n) => format!("drop(tape, {});", n),
+                    Instruct::Add => String::from("add(tape);"),
+                    Instruct::Subtract => String::from("sub(tape);"),
+                    Instruct::Multiply => String::from("mul(tape);"),
+                    Instruct::Divide => String::from("lasm_div(tape);"),
+                    Instruct::Modulo => String::from("lasm_mod(tape);"),
+                    Instruct::Negate => String::from("neg(tape);"),
+                    Instruct::Floor => String::from("lasm_floor(tape);"),
+                    Instruct::Ceil => String::from("lasm_ceil(tape);"),
+                    Instruct::Truncate => String::from("lasm_trunc(tape);"),
+                    Instruct::Absolute => String::from("lasm_abs(tape);"),
+                    Instruct::Power => String::from("lasm_pow(tape);"),
+                    Instruct::SquareRoot => String::from("lasm_sqrt(tape);"),
+                    Instruct::BitAnd => String::from("band(tape);"),
+                    Instruct::BitOr => String::from("bor(tape);"),
+                    Instruct::BitXor => String::from("bxor(tape);"),
+                    Instruct::BitNot => String::from("bnot(tape);"),
+                    Instruct::ShiftLeft => String::from("shl(tape);"),
+                    Instruct::ShiftRight => String::from("shr(tape);"),
+                    Instruct::And => String::from("lasm_and(tape);"),
+                    Instruct::Or => String::from("lasm_or(tape);"),
+                    Instruct::Not => String::from("lasm_not(tape);"),
+                    Instruct::InputChar => String::from("inc(tape);"),
+                    Instruct::InputNumber => String::from("inn(tape);"),
+                    Instruct::OutputChar => String::from("outc(tape);"),
+                    Instruct::OutputNumber => String::from("outn(tape);"),
+                    Instruct::Compare => String::from("cmp(tape);"),
+                    Instruct::Less => String::from("lt(tape);"),
+                    Instruct::LessEqual => String::from("le(tape);"),
+                    Instruct::Greater => String::from("gt(tape);"),
+                    Instruct::GreaterEqual => String::from("ge(tape);"),
+                    Instruct::Equal => String::from("eq(tape);"),
+                    Instruct::NotEqual => String::from("ne(tape);"),
+                    Instruct::WhileNotZero => {
+                        loops.push(count);
+                        count += 1;
+                        String::from("while (pop_bool(tape)) {")
+                    }
+                    Instruct::While => {
+                        loops.push(count);
+                        count += 1;
+                        String::from("while (true) {")
+                    }
+                    Instruct::Do => String::from("if (!pop_bool(tape)) break;"),
+                    Instruct::EndWhile | Instruct::EndDo => {
+                        let id = loops.pop().unwrap_or_default();
+                        let mut end = String::new();
+                        if continues.contains(&id) {
+                            end += &format!("lasm_continue_{}:;\n    ", id);
+                        }
+                        end += "}";
+                        if breaks.contains(&id) {
+                            end += &format!("\n    lasm_break_{}:;", id);
+                        }
+                        end
+                    }
+                    Instruct::Break(0) => String::from("break;"),
+                    Instruct::Break(n) => {
+                        format!("goto lasm_break_{};", Self::enclosing_loop(&loops, *n)?)
+                    }
+                    Instruct::Continue(0) => String::from("continue;"),
+                    Instruct::Continue(n) => {
+                        format!("goto lasm_continue_{};", Self::enclosing_loop(&loops, *n)?)
+                    }
+                    Instruct::If => String::from("if (pop_bool(tape)) {"),
+                    Instruct::Else => String::from("} else {"),
+                    Instruct::EndIf => String::from("}"),
+                    Instruct::Call(name) => format!("{}(tape, alloc_tape);", Self::proc_name(name)),
+                    Instruct::ReferProc(name) => format!(
+                        "push_cell(tape, {});",
+                        // A program that was not assembled from assembly code can
+                        // refer to a procedure that is not outlined
+                        program
+                            .proc_id(name)
+                            .ok_or_else(|| Error::ProcedureNotDefined(name.clone()))?
+                    ),
+                    Instruct::CallIndirect => String::from("lasm_call_ind(tape, alloc_tape);"),
+                    Instruct::CallExtern { name, args, rets } => {
+                        Self::extern_call(name, *args, *rets)
+                    }
+                    Instruct::Raw { target, code } if target == self.name() => {
+                        String::from(code.trim())
+                    }
+                    Instruct::Raw { target, .. } => {
+                        return Err(Error::RawTargetMismatch {
+                            raw: target.clone(),
+                            target: String::from(self.name()),
+                        })
+                    }
+                }
+                + "\n");
+        }
+        Ok(result)
+    }
+}
+
 impl Target for C {
-    fn assemble(&self, initial_stack_ptr: usize, stack_size: usize, code: Vec<Instruct>) -> String {
-        let total_mem_size = initial_stack_ptr + stack_size;
+    fn name(&self) -> &str {
+        "c"
+    }
+
+    fn assemble(&self, program: Program) -> Result<String> {
+        let total_mem_size = program.initial_stack_ptr + program.stack_size;
 
         let mut result = format!(
             "#include <stdio.h>
+#include <stdlib.h>
 #include <stdbool.h>
+#include <math.h>
+#include <stdint.h>
 
 const int INIT_STACK_PTR = {reg_size};
-const int MEMORY_SIZE = {mem_size};",
-            reg_size = initial_stack_ptr,
-            mem_size = total_mem_size
+const int MEMORY_SIZE = {mem_size};
+const bool BOUNDS_CHECKS = {bounds_checks};",
+            reg_size = program.initial_stack_ptr,
+            mem_size = total_mem_size,
+            bounds_checks = program.bounds_checks
         );
 
         result += r#"
@@ -68,7 +652,7 @@ void pop_cell(double tape[], int addr) {
 void deref_load(double tape[]) {
     pop_cell(tape, ACC);
     int addr = tape[ACC];
-    push_cell(tape, (int)tape[addr]);
+    push_cell(tape, tape[addr]);
 }
 
 void deref_store(double tape[]) {
@@ -77,6 +661,52 @@ void deref_store(double tape[]) {
     pop_cell(tape, addr);
 }
 
+void deref_load_n(double tape[], int size) {
+    pop_cell(tape, ACC);
+    int addr = tape[ACC];
+    for (int i=0; i<size; i++) {
+        push_cell(tape, tape[addr + i]);
+    }
+}
+
+void deref_store_n(double tape[], int size) {
+    pop_cell(tape, ACC);
+    int addr = tape[ACC];
+    for (int i=0; i<size; i++) {
+        pop_cell(tape, addr + size - i - 1);
+    }
+}
+
+void lasm_memcpy(double tape[]) {
+    pop_cell(tape, ACC);
+    int dst = tape[ACC];
+    pop_cell(tape, ACC);
+    int src = tape[ACC];
+    pop_cell(tape, ACC);
+    int len = tape[ACC];
+    if (dst < src) {
+        for (int i=0; i<len; i++) {
+            tape[dst + i] = tape[src + i];
+        }
+    } else {
+        for (int i=len-1; i>=0; i--) {
+            tape[dst + i] = tape[src + i];
+        }
+    }
+}
+
+void lasm_memset(double tape[]) {
+    pop_cell(tape, ACC);
+    int dst = tape[ACC];
+    pop_cell(tape, ACC);
+    double value = tape[ACC];
+    pop_cell(tape, ACC);
+    int len = tape[ACC];
+    for (int i=0; i<len; i++) {
+        tape[dst + i] = value;
+    }
+}
+
 
 void store(double tape[], int addr, int size) {
     for (int i=0; i<size; i++) {
@@ -107,7 +737,7 @@ void sub(double tape[]) {
     push_cell(tape, a - b);
 }
 
-void div(double tape[]) {
+void lasm_div(double tape[]) {
     pop_cell(tape, ACC);
     double a = tape[ACC];
     pop_cell(tape, ACC);
@@ -123,12 +753,196 @@ void mul(double tape[]) {
     push_cell(tape, a * b);
 }
 
+void lasm_mod(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, fmod(a, b));
+}
+
+void lasm_pow(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, pow(a, b));
+}
+
+void neg(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, -tape[ACC]);
+}
+
+void lasm_floor(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, floor(tape[ACC]));
+}
+
+void lasm_ceil(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, ceil(tape[ACC]));
+}
+
+void lasm_trunc(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, trunc(tape[ACC]));
+}
+
+void lasm_abs(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, fabs(tape[ACC]));
+}
+
+void lasm_sqrt(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, sqrt(tape[ACC]));
+}
+
+int64_t to_int(double value) {
+    if (isnan(value)) {
+        return 0;
+    } else if (value >= 9223372036854775807.0) {
+        return INT64_MAX;
+    } else if (value <= -9223372036854775808.0) {
+        return INT64_MIN;
+    }
+    return value;
+}
+
+int64_t pop_index(double tape[], int size) {
+    pop_cell(tape, ACC);
+    int64_t index = to_int(tape[ACC]);
+    if (BOUNDS_CHECKS && (index < 0 || index >= size)) {
+        fprintf(stderr, "index %lld out of bounds for register of size %d\n", (long long)index, size);
+        exit(1);
+    }
+    return index;
+}
+
+void load_idx(double tape[], int addr, int size) {
+    int64_t index = pop_index(tape, size);
+    push_cell(tape, tape[addr + index]);
+}
+
+void store_idx(double tape[], int addr, int size) {
+    int64_t index = pop_index(tape, size);
+    pop_cell(tape, addr + index);
+}
+
+void band(double tape[]) {
+    pop_cell(tape, ACC);
+    int64_t a = to_int(tape[ACC]);
+    pop_cell(tape, ACC);
+    int64_t b = to_int(tape[ACC]);
+    push_cell(tape, a & b);
+}
+
+void bor(double tape[]) {
+    pop_cell(tape, ACC);
+    int64_t a = to_int(tape[ACC]);
+    pop_cell(tape, ACC);
+    int64_t b = to_int(tape[ACC]);
+    push_cell(tape, a | b);
+}
+
+void bxor(double tape[]) {
+    pop_cell(tape, ACC);
+    int64_t a = to_int(tape[ACC]);
+    pop_cell(tape, ACC);
+    int64_t b = to_int(tape[ACC]);
+    push_cell(tape, a ^ b);
+}
+
+void bnot(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, ~to_int(tape[ACC]));
+}
+
+void shl(double tape[]) {
+    pop_cell(tape, ACC);
+    int64_t a = to_int(tape[ACC]);
+    pop_cell(tape, ACC);
+    int64_t b = to_int(tape[ACC]) & 63;
+    push_cell(tape, (int64_t)((uint64_t)a << b));
+}
+
+void shr(double tape[]) {
+    pop_cell(tape, ACC);
+    int64_t a = to_int(tape[ACC]);
+    pop_cell(tape, ACC);
+    int64_t b = to_int(tape[ACC]) & 63;
+    push_cell(tape, a >> b);
+}
+
+void lasm_and(double tape[]) {
+    pop_cell(tape, ACC);
+    bool a = to_int(tape[ACC]) != 0;
+    pop_cell(tape, ACC);
+    bool b = to_int(tape[ACC]) != 0;
+    push_cell(tape, a && b);
+}
+
+void lasm_or(double tape[]) {
+    pop_cell(tape, ACC);
+    bool a = to_int(tape[ACC]) != 0;
+    pop_cell(tape, ACC);
+    bool b = to_int(tape[ACC]) != 0;
+    push_cell(tape, a || b);
+}
+
+void lasm_not(double tape[]) {
+    pop_cell(tape, ACC);
+    push_cell(tape, to_int(tape[ACC]) == 0);
+}
+
 void dup(double tape[]) {
     pop_cell(tape, ACC);
     push_cell(tape, tape[ACC]);
     push_cell(tape, tape[ACC]);
 }
 
+void swap(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a);
+    push_cell(tape, b);
+}
+
+void over(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, b);
+    push_cell(tape, a);
+    push_cell(tape, b);
+}
+
+void rot(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    pop_cell(tape, ACC);
+    double c = tape[ACC];
+    push_cell(tape, b);
+    push_cell(tape, a);
+    push_cell(tape, c);
+}
+
+void pick(double tape[], int n) {
+    push_cell(tape, tape[(int)tape[SPR] - n - 1]);
+}
+
+void drop(double tape[], int n) {
+    for (int i=0; i<n; i++) {
+        pop_cell(tape, ACC);
+    }
+}
+
 void cmp(double tape[]) {
     pop_cell(tape, ACC);
     double a = tape[ACC];
@@ -140,9 +954,59 @@ void cmp(double tape[]) {
         push_cell(tape, 0);
     } else if (a > b) {
         push_cell(tape, 1);
+    } else {
+        push_cell(tape, NAN);
     }
 }
 
+void lt(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a < b);
+}
+
+void le(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a <= b);
+}
+
+void gt(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a > b);
+}
+
+void ge(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a >= b);
+}
+
+void eq(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a == b);
+}
+
+void ne(double tape[]) {
+    pop_cell(tape, ACC);
+    double a = tape[ACC];
+    pop_cell(tape, ACC);
+    double b = tape[ACC];
+    push_cell(tape, a != b);
+}
+
 
 void outc(double tape[]) {
     pop_cell(tape, ACC);
@@ -151,7 +1015,9 @@ void outc(double tape[]) {
 
 void outn(double tape[]) {
     pop_cell(tape, ACC);
-    printf("%lG", tape[ACC]);
+    // The sign of NaN depends on the machine, so it is not printed
+    if (isnan(tape[ACC])) printf("NAN");
+    else printf("%lG", tape[ACC]);
 }
 
 void inc(double tape[]) {
@@ -174,8 +1040,7 @@ void inn(double tape[]) {
 
 bool pop_bool(double tape[]) {
     pop_cell(tape, ACC);
-    int a = tape[ACC];
-    return a;
+    return to_int(tape[ACC]) != 0;
 }
 
 
@@ -214,6 +1079,75 @@ void lasm_free(double tape[], bool alloc_tape[], int ptr_addr) {
     }
 }
 
+void pop_args(double tape[], double args[], int n) {
+    for (int i=n-1; i>=0; i--) {
+        pop_cell(tape, ACC);
+        args[i] = tape[ACC];
+    }
+}
+
+void push_rets(double tape[], double rets[], int n) {
+    for (int i=0; i<n; i++) {
+        push_cell(tape, rets[i]);
+    }
+}
+
+"#;
+
+        // Declare the host functions that implement the extern procedures
+        let mut externs = BTreeSet::new();
+        for line in program.code.iter().chain(program.procs.values().flatten()) {
+            if let Instruct::CallExtern { name, args, rets } = line {
+                if !Self::is_valid_extern(name, *args, *rets) {
+                    return Err(Error::InvalidExtern(name.clone()));
+                }
+                externs.insert(Self::extern_prototype(name, *args, *rets));
+            }
+        }
+        for prototype in externs {
+            result += &prototype;
+            result += "\n";
+        }
+
+        // Declare every outlined procedure before defining them,
+        // so that they can call each other in any order
+        for name in program.procs.keys() {
+            result += &format!(
+                "void {}(double tape[], bool alloc_tape[]);\n",
+                Self::proc_name(name)
+            );
+        }
+
+        // The identifier of an outlined procedure is its index in the dispatch table,
+        // which starts with NULL so that zero is not the identifier of a procedure
+        result += "\nvoid (*const lasm_dispatch[])(double tape[], bool alloc_tape[]) = {\n";
+        result += "    NULL,\n";
+        for name in program.procs.keys() {
+            result += &format!("    {},\n", Self::proc_name(name));
+        }
+        result += r#"};
+
+void lasm_call_ind(double tape[], bool alloc_tape[]) {
+    pop_cell(tape, ACC);
+    int64_t id = to_int(tape[ACC]);
+    if (id < 1 || id >= (int64_t)(sizeof(lasm_dispatch) / sizeof(lasm_dispatch[0]))) {
+        fprintf(stderr, "no procedure has the identifier %lld\n", (long long)id);
+        exit(1);
+    }
+    lasm_dispatch[id](tape, alloc_tape);
+}
+"#;
+
+        for (name, body) in &program.procs {
+            result += &format!(
+                "\nvoid {}(double tape[], bool alloc_tape[]) {{\n",
+                Self::proc_name(name)
+            );
+            result += &self.assemble_code(body, &program)?;
+            result += "}\n";
+        }
+
+        result += r#"
 int main() {
     double tape[MEMORY_SIZE];
     bool alloc_tape[MEMORY_SIZE];
@@ -222,38 +1156,14 @@ int main() {
     init(tape, alloc_tape);
 "#;
 
-        for line in &code {
-            result += &(String::from("    ")
-                + &match line {
-                    Instruct::Refer(r) => format!("push_cell(tape, {});", r.get_addr()),
-                    Instruct::DerefLoad => String::from("deref_load(tape);"),
-                    Instruct::DerefStore => String::from("deref_store(tape);"),
-                    Instruct::Alloc(r) => {
-                        format!("lasm_alloc(tape, alloc_tape, {});", r.get_addr())
-                    }
-                    Instruct::Free(r) => format!("lasm_free(tape, alloc_tape, {});", r.get_addr()),
-                    Instruct::Load(r) => format!("load(tape, {}, {});", r.get_addr(), r.get_size()),
-                    Instruct::Store(r) => {
-                        format!("store(tape, {}, {});", r.get_addr(), r.get_size())
-                    }
-                    Instruct::Push(l) => format!("push_cell(tape, {});", l.get()),
-                    Instruct::Pop => String::from("pop_cell(tape, ACC);"),
-                    Instruct::Duplicate => String::from("dup(tape);"),
-                    Instruct::Add => String::from("add(tape);"),
-                    Instruct::Subtract => String::from("sub(tape);"),
-                    Instruct::Multiply => String::from("mul(tape);"),
-                    Instruct::Divide => String::from("div(tape);"),
-                    Instruct::InputChar => String::from("inc(tape);"),
-                    Instruct::InputNumber => String::from("inn(tape);"),
-                    Instruct::OutputChar => String::from("outc(tape);"),
-                    Instruct::OutputNumber => String::from("outn(tape);"),
-                    Instruct::Compare => String::from("cmp(tape);"),
-                    Instruct::WhileNotZero => String::from("while (pop_bool(tape)) {"),
-                    Instruct::EndWhile => String::from("}"),
-                }
-                + "\n");
+        for (addr, cells) in &program.data {
+            for (i, cell) in cells.iter().enumerate() {
+                result += &format!("    tape[{}] = {};\n", addr + i, Self::literal(*cell));
+            }
         }
 
+        result += &self.assemble_code(&program.code, &program)?;
+
         result += r#"
         
     // printf("...\n\n");
@@ -279,6 +1189,6 @@ int main() {
 
     return 0;
 }"#;
-        result
+        Ok(result)
     }
 }
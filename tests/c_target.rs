@@ -0,0 +1,42 @@
+//! Tests for the behaviour that the interpreter used by the tests shares with the
+//! runtime of the C target. Each program is also compiled and run as C when a C
+//! compiler is available, and must print the same output.
+
+mod common;
+
+use common::output;
+
+#[test]
+fn dereferencing_loads_the_whole_value() {
+    let source = "proc start
+            define x, 1
+            push 2.75 st x
+            refer x deref_ld outn
+            refer x deref_ld_n 1 outn
+        endproc";
+    assert_eq!(output(source), "2.752.75");
+}
+
+#[test]
+fn characters_are_printed_modulo_256() {
+    let source = "proc start\n push 321 outc push -191 outc push 65.9 outc\nendproc";
+    assert_eq!(output(source), "AAA");
+}
+
+#[test]
+fn numbers_are_printed_with_six_significant_digits() {
+    let source = "proc start
+            push 0.5 outn push 10 outc
+            push 123456 outn push 10 outc
+            push 1234567 outn push 10 outc
+            push 3.14159265 outn push 10 outc
+            push 0.0001 outn push 10 outc
+            push 0.00001234 outn push 10 outc
+            push -2500000 outn push 10 outc
+            push 999999.5 outn
+        endproc";
+    assert_eq!(
+        output(source),
+        "0.5\n123456\n1.23457E+06\n3.14159\n0.0001\n1.234E-05\n-2.5E+06\n1E+06"
+    );
+}
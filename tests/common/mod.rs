@@ -0,0 +1,679 @@
+//! Helpers shared by the tests. Programs are assembled for an interpreter that runs
+//! them, so that their behaviour can be checked without compiling the output of a target.
+//! When a C compiler is available, every program that is run is also assembled to C,
+//! compiled, and run, and its output must be the same as the interpreter's.
+
+// Each test file uses a different subset of the helpers
+#![allow(dead_code)]
+
+use lasm::{target::C, Error, Instruct, Options, Program, Result, Target, Warning};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+/// The assembler keeps its state in global variables while assembling,
+/// so only one program can be assembled at a time
+static ASSEMBLER: Mutex<()> = Mutex::new(());
+
+/// The registers every program starts with
+const ACC: usize = 0;
+const SPR: usize = 1;
+
+/// A target that runs a program instead of translating it. The output of
+/// the program is returned in place of the output code.
+pub struct Interpreter {
+    /// The input given to the program
+    pub input: &'static str,
+    /// The number of cells left on the stack when the program finishes
+    pub depth: RefCell<usize>,
+}
+
+/// The state of a running program
+struct Machine<'a> {
+    tape: Vec<f64>,
+    allocated: Vec<bool>,
+    procs: &'a BTreeMap<String, Vec<Instruct>>,
+    input: std::str::Chars<'a>,
+    output: String,
+}
+
+impl Machine<'_> {
+    fn push(&mut self, value: f64) {
+        let spr = self.tape[SPR] as usize;
+        self.tape[spr] = value;
+        self.tape[SPR] += 1.0;
+    }
+
+    fn pop(&mut self) -> f64 {
+        self.tape[SPR] -= 1.0;
+        let spr = self.tape[SPR] as usize;
+        let value = self.tape[spr];
+        self.tape[spr] = 0.0;
+        self.tape[ACC] = value;
+        value
+    }
+
+    fn pop_int(&mut self) -> i64 {
+        self.pop() as i64
+    }
+
+    fn pop_addr(&mut self) -> usize {
+        self.pop() as usize
+    }
+
+    fn binary(&mut self, op: impl Fn(f64, f64) -> f64) {
+        let a = self.pop();
+        let b = self.pop();
+        self.push(op(a, b));
+    }
+
+    fn int_binary(&mut self, op: impl Fn(i64, i64) -> i64) {
+        let a = self.pop_int();
+        let b = self.pop_int();
+        self.push(op(a, b) as f64);
+    }
+
+    fn compare(&mut self, op: impl Fn(f64, f64) -> bool) {
+        self.binary(|a, b| op(a, b) as i64 as f64);
+    }
+
+    /// Find where each structured control instruction jumps to
+    fn jumps(code: &[Instruct]) -> Vec<usize> {
+        let mut jumps = vec![0; code.len()];
+        let mut loops = Vec::new();
+        let mut ifs: Vec<Vec<usize>> = Vec::new();
+        let mut exits: Vec<Vec<usize>> = Vec::new();
+        for (i, instruct) in code.iter().enumerate() {
+            match instruct {
+                Instruct::WhileNotZero | Instruct::While => {
+                    loops.push(i);
+                    exits.push(vec![i]);
+                }
+                Instruct::Do => exits.last_mut().unwrap().push(i),
+                Instruct::EndWhile | Instruct::EndDo => {
+                    let start = loops.pop().unwrap();
+                    jumps[i] = start;
+                    for exit in exits.pop().unwrap() {
+                        jumps[exit] = i + 1;
+                    }
+                }
+                Instruct::Break(n) => {
+                    let depth = exits.len() - n - 1;
+                    exits[depth].push(i);
+                }
+                Instruct::Continue(n) => jumps[i] = loops[loops.len() - n - 1],
+                Instruct::If => ifs.push(vec![i]),
+                Instruct::Else => ifs.last_mut().unwrap().push(i),
+                Instruct::EndIf => {
+                    let branches = ifs.pop().unwrap();
+                    jumps[branches[0]] = branches.get(1).map_or(i + 1, |e| e + 1);
+                    if let Some(e) = branches.get(1) {
+                        jumps[*e] = i + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        jumps
+    }
+
+    fn run(&mut self, code: &[Instruct]) {
+        let jumps = Self::jumps(code);
+        let mut pc = 0;
+        while pc < code.len() {
+            let mut next = pc + 1;
+            match &code[pc] {
+                Instruct::Refer(r) => self.push(r.get_addr() as f64),
+                Instruct::DerefLoad => {
+                    let addr = self.pop_addr();
+                    self.push(self.tape[addr]);
+                }
+                Instruct::DerefStore => {
+                    let addr = self.pop_addr();
+                    self.tape[addr] = self.pop();
+                }
+                Instruct::DerefLoadN(n) => {
+                    let addr = self.pop_addr();
+                    for i in 0..*n {
+                        self.push(self.tape[addr + i]);
+                    }
+                }
+                Instruct::DerefStoreN(n) => {
+                    let addr = self.pop_addr();
+                    for i in (0..*n).rev() {
+                        self.tape[addr + i] = self.pop();
+                    }
+                }
+                Instruct::MemCopy => {
+                    let (dst, src, len) = (self.pop_addr(), self.pop_addr(), self.pop_addr());
+                    self.tape.copy_within(src..src + len, dst);
+                }
+                Instruct::MemSet => {
+                    let (dst, value, len) = (self.pop_addr(), self.pop(), self.pop_addr());
+                    self.tape[dst..dst + len]
+                        .iter_mut()
+                        .for_each(|cell| *cell = value);
+                }
+                Instruct::Alloc(r) => {
+                    let size = self.pop_addr();
+                    let start = (1..self.tape.len())
+                        .rev()
+                        .find(|i| {
+                            *i + size <= self.tape.len()
+                                && self.allocated[*i..*i + size].iter().all(|a| !a)
+                        })
+                        .expect("out of memory");
+                    self.allocated[start..start + size]
+                        .iter_mut()
+                        .for_each(|a| *a = true);
+                    self.tape[r.get_addr()] = start as f64;
+                }
+                Instruct::Free(r) => {
+                    let size = self.pop_addr();
+                    let addr = self.tape[r.get_addr()] as usize;
+                    for i in addr..addr + size {
+                        self.tape[i] = 0.0;
+                        self.allocated[i] = false;
+                    }
+                }
+                Instruct::Load(r) => {
+                    for i in 0..r.get_size() {
+                        self.push(self.tape[r.get_addr() + i]);
+                    }
+                }
+                Instruct::Store(r) => {
+                    for i in (0..r.get_size()).rev() {
+                        self.tape[r.get_addr() + i] = self.pop();
+                    }
+                }
+                Instruct::LoadIndex(r) => {
+                    let index = self.pop_addr();
+                    self.push(self.tape[r.get_addr() + index]);
+                }
+                Instruct::StoreIndex(r) => {
+                    let index = self.pop_addr();
+                    self.tape[r.get_addr() + index] = self.pop();
+                }
+                Instruct::Push(l) => self.push(l.get()),
+                Instruct::Pop => {
+                    self.pop();
+                }
+                Instruct::Duplicate => {
+                    let a = self.pop();
+                    self.push(a);
+                    self.push(a);
+                }
+                Instruct::Swap => {
+                    let (a, b) = (self.pop(), self.pop());
+                    self.push(a);
+                    self.push(b);
+                }
+                Instruct::Over => {
+                    let (a, b) = (self.pop(), self.pop());
+                    self.push(b);
+                    self.push(a);
+                    self.push(b);
+                }
+                Instruct::Rotate => {
+                    let (a, b, c) = (self.pop(), self.pop(), self.pop());
+                    self.push(b);
+                    self.push(a);
+                    self.push(c);
+                }
+                Instruct::Pick(n) => {
+                    let spr = self.tape[SPR] as usize;
+                    self.push(self.tape[spr - n - 1]);
+                }
+                Instruct::Drop(n) => {
+                    for _ in 0..*n {
+                        self.pop();
+                    }
+                }
+                Instruct::Add => self.binary(|a, b| a + b),
+                Instruct::Subtract => self.binary(|a, b| a - b),
+                Instruct::Multiply => self.binary(|a, b| a * b),
+                Instruct::Divide => self.binary(|a, b| a / b),
+                Instruct::Modulo => self.binary(|a, b| a % b),
+                Instruct::Power => self.binary(f64::powf),
+                Instruct::Negate => {
+                    let a = self.pop();
+                    self.push(-a);
+                }
+                Instruct::Floor => {
+                    let a = self.pop();
+                    self.push(a.floor());
+                }
+                Instruct::Ceil => {
+                    let a = self.pop();
+                    self.push(a.ceil());
+                }
+                Instruct::Truncate => {
+                    let a = self.pop();
+                    self.push(a.trunc());
+                }
+                Instruct::Absolute => {
+                    let a = self.pop();
+                    self.push(a.abs());
+                }
+                Instruct::SquareRoot => {
+                    let a = self.pop();
+                    self.push(a.sqrt());
+                }
+                Instruct::BitAnd => self.int_binary(|a, b| a & b),
+                Instruct::BitOr => self.int_binary(|a, b| a | b),
+                Instruct::BitXor => self.int_binary(|a, b| a ^ b),
+                Instruct::BitNot => {
+                    let a = self.pop_int();
+                    self.push(!a as f64);
+                }
+                Instruct::ShiftLeft => self.int_binary(|a, b| a << (b & 63)),
+                Instruct::ShiftRight => self.int_binary(|a, b| a >> (b & 63)),
+                Instruct::And => self.int_binary(|a, b| (a != 0 && b != 0) as i64),
+                Instruct::Or => self.int_binary(|a, b| (a != 0 || b != 0) as i64),
+                Instruct::Not => {
+                    let a = self.pop_int();
+                    self.push((a == 0) as i64 as f64);
+                }
+                Instruct::Compare => self.binary(|a, b| match a.partial_cmp(&b) {
+                    Some(ordering) => ordering as i64 as f64,
+                    None => f64::NAN,
+                }),
+                Instruct::Less => self.compare(|a, b| a < b),
+                Instruct::LessEqual => self.compare(|a, b| a <= b),
+                Instruct::Greater => self.compare(|a, b| a > b),
+                Instruct::GreaterEqual => self.compare(|a, b| a >= b),
+                Instruct::Equal => self.compare(|a, b| a == b),
+                Instruct::NotEqual => self.compare(|a, b| a != b),
+                Instruct::InputChar => {
+                    let ch = self.input.next().map_or(0.0, |ch| ch as u32 as f64);
+                    self.push(ch);
+                }
+                Instruct::InputNumber => {
+                    let rest = self.input.as_str().trim_start();
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    self.push(rest[..end].parse().unwrap_or(0.0));
+                    self.input = rest[end..].chars();
+                }
+                Instruct::OutputChar => {
+                    // The C runtime prints the number modulo 256 as a byte
+                    let ch = self.pop() as i32 as u8 as char;
+                    self.output.push(ch);
+                }
+                Instruct::OutputNumber => {
+                    let n = self.pop();
+                    self.output += &format_g(n);
+                }
+                Instruct::WhileNotZero | Instruct::If | Instruct::Do => {
+                    if self.pop_int() == 0 {
+                        next = jumps[pc];
+                    }
+                }
+                Instruct::While | Instruct::EndIf => {}
+                Instruct::EndWhile
+                | Instruct::EndDo
+                | Instruct::Break(_)
+                | Instruct::Continue(_)
+                | Instruct::Else => next = jumps[pc],
+                Instruct::Call(name) => {
+                    let procs = self.procs;
+                    self.run(&procs[name]);
+                }
+                Instruct::ReferProc(name) => {
+                    let id = self.procs.keys().position(|n| n == name).unwrap() + 1;
+                    self.push(id as f64);
+                }
+                Instruct::CallIndirect => {
+                    let id = self.pop_addr();
+                    let procs = self.procs;
+                    let (_, body) = procs
+                        .iter()
+                        .nth(id - 1)
+                        .expect("invalid procedure identifier");
+                    self.run(body);
+                }
+                Instruct::CallExtern { name, args, .. } => {
+                    let mut values = (0..*args).map(|_| self.pop()).collect::<Vec<_>>();
+                    values.reverse();
+                    for value in host(name, &values) {
+                        self.push(value);
+                    }
+                }
+                Instruct::Raw { .. } => unreachable!("raw code is rejected before running"),
+            }
+            pc = next;
+        }
+    }
+}
+
+/// Format a number the way C's `printf("%lG", n)` does, which is how the C runtime
+/// prints numbers: with six significant digits and no trailing zeros, in scientific
+/// notation if the exponent is less than -4 or at least 6. NaN is printed without a sign.
+fn format_g(n: f64) -> String {
+    const PRECISION: i32 = 6;
+    let sign = if n.is_sign_negative() { "-" } else { "" };
+    if n.is_nan() {
+        return String::from("NAN");
+    } else if n.is_infinite() {
+        return format!("{}INF", sign);
+    } else if n == 0.0 {
+        return format!("{}0", sign);
+    }
+
+    // The exponent is the one the number has after rounding to the precision
+    let scientific = format!("{:.*E}", PRECISION as usize - 1, n);
+    let (mantissa, exponent) = scientific.split_once('E').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+    let strip = |digits: &str| {
+        if digits.contains('.') {
+            digits
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_string()
+        } else {
+            digits.to_string()
+        }
+    };
+    if !(-4..PRECISION).contains(&exponent) {
+        format!(
+            "{}E{}{:02}",
+            strip(mantissa),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    } else {
+        strip(&format!("{:.*}", (PRECISION - 1 - exponent) as usize, n))
+    }
+}
+
+/// The host functions that implement extern procedures in the interpreter
+fn host(name: &str, args: &[f64]) -> Vec<f64> {
+    match (name, args) {
+        ("sqrt", [x]) => vec![x.sqrt()],
+        ("hypot", [x, y]) => vec![x.hypot(*y)],
+        ("pow", [x, y]) => vec![x.powf(*y)],
+        ("fma", [x, y, z]) => vec![x.mul_add(*y, *z)],
+        _ => panic!("no host function for extern procedure '{}'", name),
+    }
+}
+
+impl Target for Interpreter {
+    fn name(&self) -> &str {
+        "interpreter"
+    }
+
+    fn assemble(&self, program: Program) -> Result<String> {
+        // The interpreter has no language of its own to write raw code in
+        let raw = program
+            .code
+            .iter()
+            .chain(program.procs.values().flatten())
+            .find_map(|i| match i {
+                Instruct::Raw { target, .. } => Some(target.clone()),
+                _ => None,
+            });
+        if let Some(raw) = raw {
+            return Err(Error::RawTargetMismatch {
+                raw,
+                target: String::from(self.name()),
+            });
+        }
+
+        let size = program.initial_stack_ptr + program.stack_size;
+        let mut machine = Machine {
+            tape: vec![0.0; size],
+            allocated: vec![false; size],
+            procs: &program.procs,
+            input: self.input.chars(),
+            output: String::new(),
+        };
+        machine.tape[SPR] = program.initial_stack_ptr as f64;
+        machine.allocated[..program.initial_stack_ptr]
+            .iter_mut()
+            .for_each(|a| *a = true);
+        for (addr, cells) in &program.data {
+            machine.tape[*addr..*addr + cells.len()].copy_from_slice(cells);
+        }
+
+        machine.run(&program.code);
+        *self.depth.borrow_mut() = machine.tape[SPR] as usize - program.initial_stack_ptr;
+        Ok(machine.output)
+    }
+}
+
+impl Target for &Interpreter {
+    fn name(&self) -> &str {
+        (*self).name()
+    }
+
+    fn assemble(&self, program: Program) -> Result<String> {
+        (*self).assemble(program)
+    }
+}
+
+/// A target that assembles programs to C, but has the name of the interpreter,
+/// so that `#if target` chooses the same code as it does for the interpreter
+struct CAsInterpreter;
+
+impl Target for CAsInterpreter {
+    fn name(&self) -> &str {
+        "interpreter"
+    }
+
+    fn assemble(&self, program: Program) -> Result<String> {
+        C.assemble(program)
+    }
+}
+
+/// Is there a C compiler to compile the output of the C target with?
+fn has_cc() -> bool {
+    static HAS_CC: Mutex<Option<bool>> = Mutex::new(None);
+    let mut has_cc = HAS_CC.lock().unwrap_or_else(|e| e.into_inner());
+    *has_cc.get_or_insert_with(|| {
+        Command::new("cc")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    })
+}
+
+/// Compile C code with `cc`, and run it with the given input. The output is
+/// returned with each byte as a character, as the interpreter writes it.
+fn run_c(code: &str, input: &str) -> String {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "lasm-test-{}-{}",
+        std::process::id(),
+        BUILDS.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let (source, exe): (PathBuf, PathBuf) = (dir.join("main.c"), dir.join("main"));
+    fs::write(&source, code).unwrap();
+
+    let build = Command::new("cc")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&source)
+        .arg("-lm")
+        .output()
+        .unwrap();
+    assert!(
+        build.status.success(),
+        "the C output does not compile:\n{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+
+    let mut child = Command::new(&exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    output.stdout.iter().map(|b| *b as char).collect()
+}
+
+/// Check that the C target gives the same output as the interpreter for a program,
+/// if there is a C compiler
+fn check_c(assemble: impl FnOnce() -> Result<(String, Vec<Warning>)>, input: &str, expected: &str) {
+    if !has_cc() {
+        return;
+    }
+    let code = {
+        let _lock = lock();
+        assemble().map(|(code, _)| code)
+    };
+    let code = code.unwrap_or_else(|e| panic!("the C target rejects the program: {}", e));
+    assert_eq!(
+        run_c(&code, input),
+        expected,
+        "the C target and the interpreter disagree"
+    );
+}
+
+/// A target that keeps the program it is given instead of translating it
+struct Capture(RefCell<Option<Program>>);
+
+impl Target for &Capture {
+    fn name(&self) -> &str {
+        "capture"
+    }
+
+    fn assemble(&self, program: Program) -> Result<String> {
+        *self.0.borrow_mut() = Some(program);
+        Ok(String::new())
+    }
+}
+
+/// Take the lock on the assembler for the rest of a test
+pub fn lock() -> MutexGuard<'static, ()> {
+    ASSEMBLER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Assemble and run a program, and return its output along with its warnings
+/// and the number of cells it leaves on the stack
+pub fn run_with(
+    source: &str,
+    input: &'static str,
+    options: &Options,
+) -> Result<(String, Vec<Warning>, usize)> {
+    let _lock = lock();
+    let interpreter = Interpreter {
+        input,
+        depth: RefCell::new(0),
+    };
+    let (output, warnings) = lasm::assemble_with(&interpreter, source, options)?;
+    let depth = *interpreter.depth.borrow();
+    drop(_lock);
+
+    check_c(
+        || lasm::assemble_with(CAsInterpreter, source, options),
+        input,
+        &output,
+    );
+    Ok((output, warnings, depth))
+}
+
+/// Assemble and run a program split across files, starting with the file named `main`,
+/// and return its output along with its warnings
+pub fn run_file(main: &str, files: &[(&str, &str)]) -> Result<(String, Vec<Warning>)> {
+    let _lock = lock();
+    let files = files
+        .iter()
+        .map(|(name, source)| (name.to_string(), source.to_string()))
+        .collect::<BTreeMap<_, _>>();
+    let interpreter = Interpreter {
+        input: "",
+        depth: RefCell::new(0),
+    };
+    let (output, warnings) = lasm::assemble_file(
+        &interpreter,
+        main,
+        &files[main],
+        &files,
+        &Options::default(),
+    )?;
+    drop(_lock);
+
+    check_c(
+        || {
+            lasm::assemble_file(
+                CAsInterpreter,
+                main,
+                &files[main],
+                &files,
+                &Options::default(),
+            )
+        },
+        "",
+        &output,
+    );
+    Ok((output, warnings))
+}
+
+/// Assemble and run a program that must not give any errors or warnings, and return
+/// its output along with the number of cells it leaves on the stack
+pub fn run(source: &str, input: &'static str) -> (String, usize) {
+    let (output, warnings, depth) =
+        run_with(source, input, &Options::default()).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(warnings, vec![]);
+    (output, depth)
+}
+
+/// Assemble and run a program that reads no input, and return its output
+pub fn output(source: &str) -> String {
+    run(source, "").0
+}
+
+/// Assemble a program for the interpreter, and return the error it gives
+pub fn error(source: &str) -> Error {
+    match run_with(source, "", &Options::default()) {
+        Ok(_) => panic!("no error for {:?}", source),
+        Err(e) => e,
+    }
+}
+
+/// Check a program with the given options
+pub fn check(source: &str, options: &Options) -> Result<Vec<Warning>> {
+    let _lock = lock();
+    lasm::check(source, options)
+}
+
+/// Assemble a program to C with the given options
+pub fn assemble_c(source: &str, options: &Options) -> Result<(String, Vec<Warning>)> {
+    let _lock = lock();
+    lasm::assemble_with(C, source, options)
+}
+
+/// Assemble a program with the given options, and return the program
+/// given to the target along with its warnings
+pub fn program(source: &str, options: &Options) -> Result<(Program, Vec<Warning>)> {
+    let _lock = lock();
+    let capture = Capture(RefCell::new(None));
+    let (_, warnings) = lasm::assemble_with(&capture, source, options)?;
+    let program = capture.0.into_inner().expect("the target was not called");
+    Ok((program, warnings))
+}
+
+/// Assemble a program with the given options, and return the names of the
+/// procedures that were outlined, separated by spaces
+pub fn outlined(source: &str, options: &Options) -> Result<String> {
+    let (program, _) = program(source, options)?;
+    Ok(program.procs.keys().cloned().collect::<Vec<_>>().join(" "))
+}
@@ -0,0 +1,229 @@
+//! Tests for conditional assembly with `#if`
+
+mod common;
+
+use common::{error, lock, output, run_with};
+use lasm::{Error, Options, Program, Result, Target};
+
+/// Run a program that must not give any errors or warnings with the given
+/// constants and features, and return its output
+fn output_with(source: &str, defines: &[(&str, f64)], features: &[&str]) -> String {
+    let mut options = Options::default();
+    for (name, value) in defines {
+        options.defines.insert(name.to_string(), *value);
+    }
+    for feature in features {
+        options.features.insert(feature.to_string());
+    }
+    let (output, warnings, _) = run_with(source, "", &options).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(warnings, vec![]);
+    output
+}
+
+#[test]
+fn target() {
+    let source = "proc start
+            #if target == \"interpreter\"
+                push 1 outn
+            #else
+                push 2 outn
+            #endif
+            #if target != \"c\"
+                push 3 outn
+            #endif
+            #if target == \"c\"
+                push 4 outn
+            #endif
+        endproc";
+    assert_eq!(output(source), "13");
+}
+
+#[test]
+fn targets_are_named_after_their_type_by_default() {
+    /// A target that only keeps the number of instructions in the program
+    struct Counter;
+
+    impl Target for Counter {
+        fn assemble(&self, program: Program) -> Result<String> {
+            Ok(program.code.len().to_string())
+        }
+    }
+
+    assert_eq!(Counter.name(), "Counter");
+    let _lock = lock();
+    let source = "proc start
+            #if target == \"Counter\"
+                push 1 push 2 add outn
+            #endif
+        endproc";
+    assert_eq!(lasm::assemble(Counter, source), Ok(String::from("4")));
+}
+
+#[test]
+fn features() {
+    let source = "proc start
+            #if feature(fast)
+                push 1 outn
+            #else
+                push 2 outn
+            #endif
+            #if !feature(fast)
+                push 3 outn
+            #endif
+        endproc";
+    assert_eq!(output_with(source, &[], &[]), "23");
+    assert_eq!(output_with(source, &[], &["fast"]), "1");
+    assert_eq!(output_with(source, &[], &["slow"]), "23");
+}
+
+#[test]
+fn defined() {
+    let source = "proc start
+            #if defined(SIZE)
+                push SIZE outn
+            #else
+                push 0 outn
+            #endif
+        endproc";
+    assert_eq!(output_with(source, &[], &[]), "0");
+    assert_eq!(output_with(source, &[("SIZE", 5.0)], &[]), "5");
+
+    // Constants in the program are defined too
+    let source = "const SIZE = 7
+        proc start
+            #if defined(SIZE)
+                push SIZE outn
+            #endif
+        endproc";
+    assert_eq!(output(source), "7");
+}
+
+#[test]
+fn combined_conditions() {
+    let source = "proc start
+            #if feature(a) && feature(b)
+                push 1 outn
+            #endif
+            #if feature(a) || feature(b)
+                push 2 outn
+            #endif
+            #if feature(a) && feature(b) || target == \"interpreter\"
+                push 3 outn
+            #endif
+        endproc";
+    assert_eq!(output_with(source, &[], &[]), "3");
+    assert_eq!(output_with(source, &[], &["a"]), "23");
+    assert_eq!(output_with(source, &[], &["b"]), "23");
+    assert_eq!(output_with(source, &[], &["a", "b"]), "123");
+}
+
+#[test]
+fn names_that_are_not_constants_are_zero() {
+    for (condition, holds) in [
+        ("UNDEF", false),
+        ("!UNDEF", true),
+        ("UNDEF == 0", true),
+        ("UNDEF < 3", true),
+        ("0 == UNDEF", true),
+        ("1 == UNDEF", false),
+        ("UNDEF + 2 == 2", true),
+    ] {
+        let source = format!(
+            "proc start
+                #if {}
+                    push 1 outn
+                #else
+                    push 0 outn
+                #endif
+            endproc",
+            condition
+        );
+        assert_eq!(
+            output(&source),
+            if holds { "1" } else { "0" },
+            "{}",
+            condition
+        );
+    }
+
+    let source = "proc start
+            #if LEVEL > 1
+                push 1 outn
+            #endif
+        endproc";
+    assert_eq!(output_with(source, &[], &[]), "");
+    assert_eq!(output_with(source, &[("LEVEL", 2.0)], &[]), "1");
+}
+
+#[test]
+fn conditions_end_at_the_end_of_the_line() {
+    for condition in ["UNDEF UNDEF", "feature(a) push 1", "1 ==", "defined(X", ""] {
+        let source = format!(
+            "proc start\n #if {}\n push 1 outn\n #endif\nendproc",
+            condition
+        );
+        assert!(
+            matches!(error(&source), Error::InvalidCondition(_)),
+            "{}",
+            condition
+        );
+    }
+}
+
+#[test]
+fn skipped_branches_are_not_parsed() {
+    // The branch that is not assembled can use names that are not defined
+    let source = "proc start
+            #if feature(missing)
+                ld nothere call nowhere
+                #if target == \"c\"
+                    bogus
+                #else
+                    more bogus
+                #endif
+            #else
+                push 1 outn
+            #endif
+        endproc";
+    assert_eq!(output(source), "1");
+
+    // Procedures can be skipped as well
+    let source = "#if feature(missing)
+        proc start\n push 1 outn\nendproc
+        #else
+        proc start\n push 2 outn\nendproc
+        #endif";
+    assert_eq!(output(source), "2");
+}
+
+#[test]
+fn directives_in_literals_are_skipped() {
+    let source = "proc start
+            #if feature(missing)
+                push \"#endif\" push '#' raw c \"\"\"#else\"\"\"
+            #else
+                push 1 outn
+            #endif
+        endproc";
+    assert_eq!(output(source), "1");
+}
+
+#[test]
+fn unmatched_directives() {
+    assert_eq!(
+        error("proc start\n push 1 outn\nendproc\n#endif"),
+        Error::UnmatchedDirective(String::from("#endif"))
+    );
+    assert_eq!(
+        error("proc start\n push 1 outn\nendproc\n#else"),
+        Error::UnmatchedDirective(String::from("#else"))
+    );
+    assert!(matches!(
+        error("proc start\n #if feature(missing)\n push 1 outn\nendproc"),
+        Error::UnmatchedDirective(_)
+    ));
+    assert!(matches!(
+        error("#if feature(missing)\nproc start\n push 1 outn\nendproc"),
+        Error::UnmatchedDirective(_)
+    ));
+}
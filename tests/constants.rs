@@ -0,0 +1,98 @@
+//! Tests for constants and compile-time expressions
+
+mod common;
+
+use common::{assemble_c, error, output, program};
+use lasm::{Error, Options};
+
+#[test]
+fn constants() {
+    let source = "const A = 4\nconst B = A * 2 + 1
+        proc start\n push A outn push B outn\nendproc";
+    assert_eq!(output(source), "49");
+}
+
+#[test]
+fn operator_precedence() {
+    let source = "proc start
+        push 1 + 2 * 3 outn
+        push (1 + 2) * 3 outn
+        push 7 - 2 - 1 outn
+        push 7 % 4 outn
+        push 9 / 2 outn
+        push -(2 + 3) outn
+    endproc";
+    assert_eq!(output(source), "79434.5-5");
+}
+
+#[test]
+fn characters_and_sizes() {
+    let source = "const LEN = 3
+        proc start
+            define buf, LEN * 2
+            push 'a' + 1 outc
+            push sizeof(buf) st buf[LEN] ld buf[LEN] outn
+        endproc";
+    assert_eq!(output(source), "b6");
+}
+
+#[test]
+fn stack_size_expression() {
+    let source = "const HEAP = 32\nstack_size HEAP + 64\nproc start\n push 1 outn\nendproc";
+    let (program, _) = program(source, &Options::default()).unwrap();
+    assert_eq!(program.stack_size, 96);
+}
+
+#[test]
+fn constants_must_be_defined_first() {
+    let source = "proc start\n push LATER outn\nendproc\nconst LATER = 1";
+    assert!(matches!(error(source), Error::InvalidPushArg(_)));
+
+    let source = "const A = \nproc start\nendproc";
+    assert!(matches!(error(source), Error::InvalidConstant(_)));
+}
+
+#[test]
+fn non_finite_constants_in_c() {
+    let source = "const BIG = 100000000000000000000
+        proc start
+            define d, 3
+            data table 1/0, -1/0, 0/0
+            push 1/0 push 0/0 push BIG outn outn outn
+        endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    assert!(c.contains("push_cell(tape, INFINITY);"));
+    assert!(c.contains("push_cell(tape, NAN);"));
+    assert!(c.contains("push_cell(tape, 1e20);"));
+    assert!(c.contains("] = INFINITY;\n"));
+    assert!(c.contains("] = -INFINITY;\n"));
+    assert!(c.contains("] = NAN;\n"));
+    assert!(!c.contains("tape, inf") && !c.contains("= inf"));
+}
+
+#[test]
+fn invalid_sizes() {
+    let size = |s: &str| Error::InvalidSize(s.to_string());
+    assert_eq!(
+        error("const X = 1/0\nproc start\n define b, X\nendproc"),
+        size("X")
+    );
+    assert_eq!(error("proc start\n define b, -3\nendproc"), size("-3"));
+    assert_eq!(error("proc start\n define b, 1.5\nendproc"), size("1.5"));
+    assert_eq!(
+        error("const X = 0/0\nproc start\n define b, X\nendproc"),
+        size("X")
+    );
+    assert_eq!(error("proc start\n deref_ld_n 1e12\nendproc"), size("1e12"));
+    assert_eq!(error("proc start\n drop 1e30\nendproc"), size("1e30"));
+
+    // The registers together must fit in memory as well
+    assert_eq!(
+        error("proc start\n define a, 2147483000\n define b, 1000\nendproc"),
+        size("1000")
+    );
+    assert_eq!(
+        error("struct Big { x: 2147483000, y: 1000 }\nproc start\nendproc"),
+        size("y")
+    );
+}
@@ -0,0 +1,224 @@
+//! Tests for conditionals and loops
+
+mod common;
+
+use common::{error, output, run};
+use lasm::{target::C, Error, Instruct, Program, Target};
+use std::collections::BTreeMap;
+
+#[test]
+fn if_else() {
+    let source = |n| {
+        format!(
+            "proc start\n push {} if push 1 outn else push 2 outn endif push 3 outn\nendproc",
+            n
+        )
+    };
+    assert_eq!(output(&source(1)), "13");
+    assert_eq!(output(&source(0)), "23");
+    assert_eq!(output(&source(-5)), "13");
+}
+
+#[test]
+fn if_without_else() {
+    let source = "proc start\n push 0 if push 1 outn endif push 1 if push 2 outn endif\nendproc";
+    assert_eq!(output(source), "2");
+}
+
+#[test]
+fn nested_conditionals() {
+    // Print the sign of each number: 1 for positive, 0 for zero, and 2 for negative
+    let sign = "proc sign\n dup if push 0 lt if push 1 outn else push 2 outn endif else pop push 0 outn endif\nendproc";
+    let source = format!(
+        "{}\nproc start\n push 4 call sign push 0 call sign push -3 call sign\nendproc",
+        sign
+    );
+    assert_eq!(run(&source, ""), (String::from("102"), 0));
+}
+
+#[test]
+fn conditionals_in_loops() {
+    // Print the odd numbers below 6
+    let source = "proc start
+        push 6 dup loop
+            push 1 swap sub
+            dup push 2 swap mod if dup outn endif
+            dup
+        endloop pop
+    endproc";
+    assert_eq!(output(source), "531");
+}
+
+#[test]
+fn unmatched_conditionals() {
+    assert_eq!(
+        error("proc start\n push 1 if push 1 outn\nendproc"),
+        Error::UnmatchedIf
+    );
+    assert_eq!(
+        error("proc start\n push 1 outn endif\nendproc"),
+        Error::UnmatchedIf
+    );
+    assert_eq!(
+        error("proc start\n push 1 loop push 0 if endloop endif\nendproc"),
+        Error::UnmatchedLoop
+    );
+}
+
+#[test]
+fn break_and_continue() {
+    // Count down from 9, skipping 7 and stopping at 3
+    let source = "proc start
+        push 9 dup loop
+            dup push 7 eq if push 1 swap sub dup continue endif
+            dup push 3 eq if break endif
+            dup outn
+            push 1 swap sub dup
+        endloop pop
+    endproc";
+    assert_eq!(output(source), "98654");
+}
+
+#[test]
+fn labeled_loops() {
+    // Print pairs of digits below 3, until the digits add up to 5
+    let source = "proc start
+        define i, 1
+        define j, 1
+        push 1 st i
+        push 1 loop outer
+            push 1 st j
+            push 1 loop inner
+                ld i ld j add push 5 eq if break outer endif
+                ld j push 3 eq if ld i push 1 add st i push 1 continue outer endif
+                ld i outn ld j outn
+                ld j push 1 add st j
+                push 1
+            endloop
+            push 1
+        endloop
+    endproc";
+    assert_eq!(run(source, ""), (String::from("11122122"), 0));
+
+    let source = "proc start
+        define i, 1
+        push 0 st i
+        push 1 loop outer
+            ld i push 1 add st i
+            push 1 loop
+                ld i push 3 eq if break outer endif
+                ld i outn push 1 continue outer
+            endloop
+            push 0
+        endloop
+    endproc";
+    assert_eq!(output(source), "12");
+}
+
+#[test]
+fn break_outside_of_loops() {
+    assert_eq!(
+        error("proc start\n break\nendproc"),
+        Error::BreakOutsideLoop(String::from("break"))
+    );
+    assert_eq!(
+        error("proc start\n push 0 if continue endif\nendproc"),
+        Error::BreakOutsideLoop(String::from("continue"))
+    );
+    assert_eq!(
+        error("proc start\n push 1 loop break nothere endloop\nendproc"),
+        Error::UnknownLabel(String::from("nothere"))
+    );
+
+    // Labels are only visible inside of the procedure that defines them
+    let source = "proc a\n break outer\nendproc
+        proc start\n push 1 loop outer call a endloop\nendproc";
+    assert_eq!(error(source), Error::UnknownLabel(String::from("outer")));
+}
+
+#[test]
+fn break_deeper_than_open_loops() {
+    // A program built by hand can refer to loops that are not open
+    let cases = vec![
+        vec![
+            Instruct::WhileNotZero,
+            Instruct::Break(1),
+            Instruct::EndWhile,
+        ],
+        vec![
+            Instruct::WhileNotZero,
+            Instruct::Continue(1),
+            Instruct::EndWhile,
+        ],
+        vec![Instruct::Break(0)],
+    ];
+    for code in cases {
+        let program = Program {
+            initial_stack_ptr: 2,
+            stack_size: 8,
+            code,
+            procs: BTreeMap::new(),
+            data: BTreeMap::new(),
+            bounds_checks: false,
+        };
+        assert_eq!(C.assemble(program), Err(Error::UnmatchedLoop));
+    }
+}
+
+#[test]
+fn while_loops() {
+    // The condition runs before every iteration, including the first
+    let source = "proc start
+        define i, 1
+        push 0 st i
+        while push 3 ld i lt do
+            ld i outn
+            ld i push 1 add st i
+        endwhile
+    endproc";
+    assert_eq!(output(source), "012");
+
+    let source = "proc start
+        define i, 1
+        push 5 st i
+        while push 3 ld i lt do push 1 outn ld i push 1 add st i endwhile
+        push 2 outn
+    endproc";
+    assert_eq!(output(source), "2");
+}
+
+#[test]
+fn break_and_continue_in_while_loops() {
+    // `continue` jumps back to the condition of a `while` loop
+    let source = "proc start
+        define i, 1
+        push 0 st i
+        while outer push 1 do
+            ld i push 1 add st i
+            ld i push 2 eq if continue endif
+            while push 1 do
+                ld i push 4 eq if break outer endif
+                break
+            endwhile
+            ld i outn
+        endwhile
+        push 9 outn
+    endproc";
+    assert_eq!(run(source, ""), (String::from("139"), 0));
+}
+
+#[test]
+fn unmatched_while_loops() {
+    assert_eq!(
+        error("proc start\n while push 1 do endloop\nendproc"),
+        Error::UnmatchedLoop
+    );
+    assert_eq!(
+        error("proc start\n while push 1 endwhile\nendproc"),
+        Error::UnmatchedLoop
+    );
+    assert_eq!(
+        error("proc start\n push 1 do endwhile\nendproc"),
+        Error::UnmatchedLoop
+    );
+}
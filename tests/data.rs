@@ -0,0 +1,80 @@
+//! Tests for string literals and registers defined with initial contents
+
+mod common;
+
+use common::{error, output, program, run};
+use lasm::{Error, Options};
+
+/// Print the zero-terminated string on top of the stack. It pops a different
+/// number of cells for each string, so it needs a signature.
+const PRINT: &str = "proc print (1 -> 0)\n dup loop outc dup endloop pop\nendproc";
+
+#[test]
+fn push_string() {
+    let source = format!(
+        "{}\nproc start\n push \"hello\\n\" call print\nendproc",
+        PRINT
+    );
+    assert_eq!(run(&source, ""), (String::from("hello\n"), 0));
+}
+
+#[test]
+fn push_string_order() {
+    // The zero is pushed first, and the first character ends up on top
+    let source = "proc start\n push \"ab\" outc outc outn\nendproc";
+    assert_eq!(output(source), "ab0");
+}
+
+#[test]
+fn strings_keep_their_whitespace() {
+    let source = format!(
+        "{}\nproc start\n push \"a  b\\t\\\"c\\\"  \" call print\nendproc",
+        PRINT
+    );
+    assert_eq!(output(&source), "a  b\t\"c\"  ");
+}
+
+#[test]
+fn data_strings() {
+    let source = "proc start
+        data greeting \"hi!\\n\"
+        refer greeting
+        while dup deref_ld do
+            dup deref_ld outc
+            push 1 add
+        endwhile
+        pop
+    endproc";
+    assert_eq!(run(source, ""), (String::from("hi!\n"), 0));
+}
+
+#[test]
+fn data_cells() {
+    let source = "const TWO = 2
+        proc start
+            data table 1, TWO, 'c', \"ab\", -4.5
+            push sizeof(table) outn
+            ld table outn outn outn outn outn outn outn
+        endproc";
+    assert_eq!(output(source), "7-4.5098979921");
+}
+
+#[test]
+fn data_is_given_to_the_target() {
+    let source = "proc start\n data table 1, 2\n ld table add outn\nendproc";
+    let (program, _) = program(source, &Options::default()).unwrap();
+    let cells = program.data.values().cloned().collect::<Vec<_>>();
+    assert_eq!(cells, vec![vec![1.0, 2.0]]);
+}
+
+#[test]
+fn invalid_data() {
+    assert!(matches!(
+        error("proc start\n data table\nendproc"),
+        Error::InvalidData(_)
+    ));
+    assert!(matches!(
+        error("proc start\n data table \"open\nendproc"),
+        Error::InvalidData(_)
+    ));
+}
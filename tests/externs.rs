@@ -0,0 +1,134 @@
+//! Tests for extern procedures implemented by the host of a target
+
+mod common;
+
+use common::{assemble_c, error, output};
+use lasm::{Error, Options};
+
+#[test]
+fn host_functions_are_called() {
+    let source = "extern proc sqrt (1 -> 1)
+        extern proc hypot (2 -> 1)
+        proc start
+            push 16 call sqrt outn
+            push 3 push 4 call hypot outn
+        endproc";
+    assert_eq!(output(source), "45");
+}
+
+#[test]
+fn externs_can_be_declared_after_they_are_called() {
+    let source = "proc start
+            push 16 call sqrt outn
+            call show
+        endproc
+        extern proc sqrt (1 -> 1)
+        proc show\n push 3 push 4 call hypot outn\nendproc
+        extern proc hypot (2 -> 1)";
+    assert_eq!(output(source), "45");
+}
+
+#[test]
+fn c_declares_and_calls_host_functions() {
+    let source = "extern proc beep (0 -> 0)
+        extern proc hypot (2 -> 1)
+        extern proc divmod (2 -> 2)
+        proc start
+            call beep
+            push 3 push 4 call hypot outn
+            push 7 push 2 call divmod outn outn
+        endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+
+    assert!(c.contains("void beep(void);"));
+    assert!(c.contains("{ beep(); }"));
+
+    assert!(c.contains("double hypot(double, double);"));
+    assert!(c.contains(
+        "{ double args[2]; pop_args(tape, args, 2); push_cell(tape, hypot(args[0], args[1])); }"
+    ));
+
+    assert!(c.contains("void divmod(double, double, double rets[]);"));
+    assert!(c.contains(
+        "{ double args[2]; pop_args(tape, args, 2); double rets[2]; \
+         divmod(args[0], args[1], rets); push_rets(tape, rets, 2); }"
+    ));
+}
+
+#[test]
+fn declarations_can_be_repeated() {
+    let source = "extern proc sqrt (1 -> 1)
+        extern proc sqrt (1 -> 1)
+        proc start\n push 9 call sqrt outn\nendproc";
+    assert_eq!(output(source), "3");
+
+    let source = "extern proc sqrt (1 -> 1)
+        extern proc sqrt (2 -> 1)
+        proc start\n push 9 call sqrt outn\nendproc";
+    assert!(matches!(error(source), Error::InvalidExtern(_)));
+
+    let source = "extern proc sqrt (1 -> 1)
+        proc sqrt\n outn\nendproc
+        proc start\n push 9 call sqrt\nendproc";
+    assert_eq!(
+        error(source),
+        Error::DuplicateProcedure(String::from("sqrt"))
+    );
+}
+
+#[test]
+fn c_rejects_reserved_names() {
+    for name in [
+        "inc",
+        "add",
+        "and",
+        "load",
+        "push_cell",
+        "main",
+        "int",
+        "lasm_dispatch",
+        "abs",
+        "rand",
+        "printf",
+        "exit",
+        "isnan",
+        "ldexp",
+        "sqrtf",
+        "powl",
+    ] {
+        let source = format!(
+            "extern proc {} (1 -> 1)\nproc start\n push 1 call {} outn\nendproc",
+            name, name
+        );
+        assert_eq!(
+            assemble_c(&source, &Options::default()),
+            Err(Error::InvalidExtern(name.to_string())),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn c_allows_math_functions_with_the_same_prototype() {
+    let source = "extern proc pow (2 -> 1)
+        extern proc fma (3 -> 1)
+        proc start\n push 2 push 10 call pow push 3 push 4 push 5 call fma outn outn\nendproc";
+    assert_eq!(output(source), "171024");
+
+    for declaration in ["pow (1 -> 1)", "pow (2 -> 2)", "sqrt (1 -> 0)"] {
+        let source = format!(
+            "extern proc {}\nproc start\n push 1 push 1 call {}\nendproc",
+            declaration,
+            &declaration[..declaration.find(' ').unwrap()]
+        );
+        assert!(
+            matches!(
+                assemble_c(&source, &Options::default()),
+                Err(Error::InvalidExtern(_))
+            ),
+            "{}",
+            declaration
+        );
+    }
+}
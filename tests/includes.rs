@@ -0,0 +1,124 @@
+//! Tests for programs split across several files
+
+mod common;
+
+use common::run_file;
+use lasm::{include::relative, Error};
+
+/// Run a program split across files, and return its output
+fn output(files: &[(&str, &str)]) -> Result<String, Error> {
+    run_file(files[0].0, files).map(|(output, _)| output)
+}
+
+#[test]
+fn include() {
+    let files = [
+        (
+            "main.lasm",
+            "include \"util.lasm\"\nproc start\n push LEN call show\nendproc",
+        ),
+        ("util.lasm", "const LEN = 3\nproc show\n outn\nendproc"),
+    ];
+    assert_eq!(output(&files), Ok(String::from("3")));
+}
+
+#[test]
+fn paths_are_relative_to_the_including_file() {
+    let files = [
+        (
+            "src/main.lasm",
+            "include \"lib/a.lasm\"\nproc start\n call a\nendproc",
+        ),
+        (
+            "src/lib/a.lasm",
+            "include \"../b.lasm\"\nproc a\n push 1 outn call b\nendproc",
+        ),
+        ("src/b.lasm", "proc b\n push 2 outn\nendproc"),
+    ];
+    assert_eq!(output(&files), Ok(String::from("12")));
+
+    assert_eq!(relative("src/main.lasm", "lib/a.lasm"), "src/lib/a.lasm");
+    assert_eq!(relative("src/lib/a.lasm", "../b.lasm"), "src/b.lasm");
+    assert_eq!(relative("src/main.lasm", "./c.lasm"), "src/c.lasm");
+    assert_eq!(relative("main.lasm", "/abs/d.lasm"), "/abs/d.lasm");
+}
+
+#[test]
+fn files_are_included_once() {
+    // Both files include the same file, but its procedures are only defined once
+    let files = [
+        (
+            "main.lasm",
+            "include \"a.lasm\"\ninclude \"b.lasm\"\nproc start\n call a call b\nendproc",
+        ),
+        (
+            "a.lasm",
+            "include \"common.lasm\"\nproc a\n call c\nendproc",
+        ),
+        (
+            "b.lasm",
+            "include \"common.lasm\"\nproc b\n call c\nendproc",
+        ),
+        ("common.lasm", "proc c\n push 1 outn\nendproc"),
+    ];
+    assert_eq!(output(&files), Ok(String::from("11")));
+}
+
+#[test]
+fn include_not_found() {
+    let files = [("main.lasm", "include \"nothere.lasm\"\nproc start\nendproc")];
+    assert_eq!(
+        output(&files),
+        Err(Error::IncludeNotFound(String::from("nothere.lasm")))
+    );
+}
+
+#[test]
+fn include_cycle() {
+    let files = [
+        ("main.lasm", "include \"a.lasm\"\nproc start\nendproc"),
+        ("a.lasm", "include \"b.lasm\"\nproc a\nendproc"),
+        ("b.lasm", "include \"a.lasm\"\nproc b\nendproc"),
+    ];
+    assert_eq!(
+        output(&files),
+        Err(Error::IncludeCycle(vec![
+            String::from("a.lasm"),
+            String::from("b.lasm"),
+            String::from("a.lasm"),
+        ]))
+    );
+
+    let files = [("main.lasm", "include \"main.lasm\"\nproc start\nendproc")];
+    assert!(matches!(output(&files), Err(Error::IncludeCycle(_))));
+}
+
+#[test]
+fn duplicate_procedures() {
+    let files = [
+        (
+            "main.lasm",
+            "include \"a.lasm\"\nproc show\n push 1 outn\nendproc\nproc start\n call show\nendproc",
+        ),
+        ("a.lasm", "proc show\n push 2 outn\nendproc"),
+    ];
+    assert!(matches!(
+        output(&files),
+        Err(Error::DuplicateProcedure(ref name)) if name == "show"
+    ));
+}
+
+#[test]
+fn errors_name_their_file() {
+    let files = [
+        ("main.lasm", "include \"bad.lasm\"\nproc start\nendproc"),
+        ("bad.lasm", "proc bad\n ld nothere\nendproc"),
+    ];
+    assert_eq!(
+        output(&files),
+        Err(Error::InFile(
+            String::from("bad.lasm"),
+            Box::new(Error::InvalidLoadArg(String::from("nothere")))
+        ))
+    );
+}
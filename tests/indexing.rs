@@ -0,0 +1,91 @@
+//! Tests for constant indices and the indexed load and store instructions
+
+mod common;
+
+use common::{assemble_c, error, output};
+use lasm::{Error, Options};
+
+#[test]
+fn constant_indices() {
+    let source = "const LAST = 3
+        proc start
+            define buf, 4
+            push 1 push 2 push 3 push 4 st buf
+            ld buf[0] outn ld buf[LAST] outn
+            push 9 st buf[1 + 1] ld buf outn outn outn outn
+        endproc";
+    assert_eq!(output(source), "144921");
+}
+
+#[test]
+fn indices_of_fields() {
+    let source = "struct Line { start: 2, end: 2 }
+        proc start
+            define l: Line
+            push 5 st l.end[1] push 6 st l.start[0]
+            ld l.end[1] outn ld l[0] outn refer l refer l.end[1] sub outn
+        endproc";
+    assert_eq!(output(source), "563");
+}
+
+#[test]
+fn constant_index_out_of_bounds() {
+    let source = |index| {
+        format!(
+            "proc start\n define buf, 4\n ld buf[{}] outn\nendproc",
+            index
+        )
+    };
+    assert_eq!(
+        error(&source("9")),
+        Error::IndexOutOfBounds(String::from("buf[9]"))
+    );
+    assert_eq!(
+        error(&source("4")),
+        Error::IndexOutOfBounds(String::from("buf[4]"))
+    );
+    assert_eq!(
+        error(&source("-1")),
+        Error::IndexOutOfBounds(String::from("buf[-1]"))
+    );
+    assert_eq!(
+        error(&source("0.5")),
+        Error::IndexOutOfBounds(String::from("buf[0.5]"))
+    );
+
+    // Every operand that takes a register reports the index
+    assert_eq!(
+        error("proc start\n define buf, 4\n push 1 st buf[4]\nendproc"),
+        Error::IndexOutOfBounds(String::from("buf[4]"))
+    );
+    assert_eq!(
+        error("proc start\n define buf, 4\n refer buf[5] outn\nendproc"),
+        Error::IndexOutOfBounds(String::from("buf[5]"))
+    );
+}
+
+#[test]
+fn runtime_indices() {
+    let source = "proc start
+        define buf, 4
+        push 7 push 2 st_idx buf
+        push 8 push 0 st_idx buf
+        push 2 ld_idx buf outn
+        push 0 ld_idx buf outn
+    endproc";
+    assert_eq!(output(source), "78");
+}
+
+#[test]
+fn bounds_checks() {
+    let source = "proc start\n define buf, 4\n push 1 push 2 st_idx buf\nendproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    assert!(c.contains("const bool BOUNDS_CHECKS = false;"));
+
+    let options = Options {
+        bounds_checks: true,
+        ..Options::default()
+    };
+    let (c, _) = assemble_c(source, &options).unwrap();
+    assert!(c.contains("const bool BOUNDS_CHECKS = true;"));
+}
@@ -0,0 +1,233 @@
+//! Tests for the semantics of individual instructions. Operands are pushed in
+//! reverse, because the first cell popped is the first operand.
+
+mod common;
+
+use common::{assemble_c, check, error, output, program};
+use lasm::{Error, Options, Warning};
+
+/// Push the cells, run the instructions, and print the number on top of the stack
+fn eval(cells: &str, instructions: &str) -> String {
+    output(&format!(
+        "proc start\n {} {} outn\nendproc",
+        cells, instructions
+    ))
+}
+
+/// Cells that push infinity and NaN at runtime
+const INF: &str = "push 0 push 1 div";
+const NAN: &str = "push 0 push 0 div";
+
+#[test]
+fn modulo() {
+    assert_eq!(eval("push 3 push 7", "mod"), "1");
+    assert_eq!(eval("push 3 push -7", "mod"), "-1");
+    assert_eq!(eval("push -3 push 7", "mod"), "1");
+    assert_eq!(eval("push 2 push 5.5", "mod"), "1.5");
+    assert_eq!(eval("push 0 push 7", "mod"), "NAN");
+    assert_eq!(eval(&format!("push 3 {}", INF), "mod"), "NAN");
+}
+
+#[test]
+fn power_and_square_root() {
+    assert_eq!(eval("push 10 push 2", "pow"), "1024");
+    assert_eq!(eval("push -1 push 2", "pow"), "0.5");
+    assert_eq!(eval("push 3 push -2", "pow"), "-8");
+    assert_eq!(eval("push 0.5 push -4", "pow"), "NAN");
+    assert_eq!(eval("push 16", "sqrt"), "4");
+    assert_eq!(eval("push -1", "sqrt"), "NAN");
+}
+
+#[test]
+fn rounding() {
+    assert_eq!(eval("push 2.5", "floor"), "2");
+    assert_eq!(eval("push -2.5", "floor"), "-3");
+    assert_eq!(eval("push 2.5", "ceil"), "3");
+    assert_eq!(eval("push -2.5", "ceil"), "-2");
+    assert_eq!(eval("push 2.5", "trunc"), "2");
+    assert_eq!(eval("push -2.5", "trunc"), "-2");
+
+    // Infinities and NaN are pushed unchanged
+    for op in &["floor", "ceil", "trunc"] {
+        assert_eq!(eval(INF, op), "INF");
+        assert_eq!(eval(NAN, op), "NAN");
+    }
+}
+
+#[test]
+fn sign() {
+    assert_eq!(eval("push 3", "neg"), "-3");
+    assert_eq!(eval("push -3", "neg"), "3");
+    assert_eq!(eval("push -3", "abs"), "3");
+    assert_eq!(eval("push 3", "abs"), "3");
+    assert_eq!(eval(INF, "neg abs"), "INF");
+}
+
+#[test]
+fn math_in_c() {
+    let source = "proc start
+        push 3 push 7 mod push 2 pow neg abs floor ceil trunc sqrt outn
+    endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    assert!(c.contains("#include <math.h>"));
+    for function in &[
+        "lasm_mod",
+        "lasm_pow",
+        "neg",
+        "lasm_abs",
+        "lasm_floor",
+        "lasm_ceil",
+        "lasm_trunc",
+        "lasm_sqrt",
+    ] {
+        assert!(c.contains(&format!("{}(tape);", function)), "{}", function);
+    }
+}
+
+#[test]
+fn bitwise() {
+    assert_eq!(eval("push 10 push 12", "band"), "8");
+    assert_eq!(eval("push 10 push 12", "bor"), "14");
+    assert_eq!(eval("push 10 push 12", "bxor"), "6");
+    assert_eq!(eval("push 0", "bnot"), "-1");
+    assert_eq!(eval("push 5", "bnot"), "-6");
+    assert_eq!(eval("push 4 push 3", "shl"), "48");
+    assert_eq!(eval("push 2 push -8", "shr"), "-2");
+
+    // Only the lowest 6 bits of the shift amount are used
+    assert_eq!(eval("push 65 push 1", "shl"), "2");
+}
+
+#[test]
+fn integer_conversion() {
+    // Cells are rounded toward zero
+    assert_eq!(eval("push 3.9 push -2.9", "bor"), "-1");
+    assert_eq!(eval("push 1 push 2.5", "band"), "0");
+    // NaN is converted to 0
+    assert_eq!(eval(&format!("push 7 {}", NAN), "bor"), "7");
+    // Cells out of range are converted to the largest or smallest integer
+    assert_eq!(eval(INF, "bnot"), "-9.22337E+18");
+}
+
+#[test]
+fn logical() {
+    assert_eq!(eval("push 2 push 3", "and"), "1");
+    assert_eq!(eval("push 0 push 3", "and"), "0");
+    assert_eq!(eval("push 0 push 3", "or"), "1");
+    assert_eq!(eval("push 0 push 0", "or"), "0");
+    assert_eq!(eval("push 0", "not"), "1");
+    assert_eq!(eval("push -4", "not"), "0");
+
+    // A cell that rounds to zero is false
+    assert_eq!(eval("push 0.5", "not"), "1");
+    assert_eq!(eval(NAN, "not"), "1");
+}
+
+#[test]
+fn bitwise_in_c() {
+    let source = "proc start
+        push 1 push 2 band push 3 bor push 4 bxor bnot push 1 shl push 1 shr
+        push 1 and push 0 or not outn
+    endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    for function in &[
+        "band", "bor", "bxor", "bnot", "shl", "shr", "lasm_and", "lasm_or", "lasm_not",
+    ] {
+        assert!(
+            c.contains(&format!("\n    {}(tape);", function)),
+            "{}",
+            function
+        );
+    }
+}
+
+#[test]
+fn comparisons() {
+    // Each comparison with the first cell less than, equal to, and greater than the second
+    let cases = [
+        ("lt", ["1", "0", "0"]),
+        ("le", ["1", "1", "0"]),
+        ("gt", ["0", "0", "1"]),
+        ("ge", ["0", "1", "1"]),
+        ("eq", ["0", "1", "0"]),
+        ("ne", ["1", "0", "1"]),
+        ("cmp", ["-1", "0", "1"]),
+    ];
+    for (op, results) in cases.iter() {
+        assert_eq!(eval("push 2 push 1", op), results[0], "{}", op);
+        assert_eq!(eval("push 2 push 2", op), results[1], "{}", op);
+        assert_eq!(eval("push 2 push 3", op), results[2], "{}", op);
+    }
+}
+
+#[test]
+fn comparisons_with_nan() {
+    for op in &["lt", "le", "gt", "ge", "eq"] {
+        assert_eq!(eval(&format!("push 1 {}", NAN), op), "0", "{}", op);
+        assert_eq!(eval(&format!("{} push 1", NAN), op), "0", "{}", op);
+    }
+    assert_eq!(eval(&format!("push 1 {}", NAN), "ne"), "1");
+    assert_eq!(eval(&format!("{} {}", NAN, NAN), "ne"), "1");
+    assert_eq!(eval(&format!("push 1 {}", NAN), "cmp"), "NAN");
+}
+
+#[test]
+fn comparisons_in_c() {
+    let source = "proc start
+        push 1 push 2 lt push 1 le push 1 gt push 1 ge push 1 eq push 1 ne push 1 cmp outn
+    endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    for function in &["lt", "le", "gt", "ge", "eq", "ne", "cmp"] {
+        assert!(
+            c.contains(&format!("\n    {}(tape);", function)),
+            "{}",
+            function
+        );
+    }
+}
+
+/// Push the cells, run the instructions, and print every cell left on the stack from the top
+fn stack(cells: &str, instructions: &str, depth: usize) -> String {
+    output(&format!(
+        "proc start\n {} {} {}\nendproc",
+        cells,
+        instructions,
+        "outn ".repeat(depth)
+    ))
+}
+
+#[test]
+fn stack_shuffling() {
+    assert_eq!(stack("push 1 push 2", "swap", 2), "12");
+    assert_eq!(stack("push 1 push 2", "over", 3), "121");
+    assert_eq!(stack("push 1 push 2 push 3", "rot", 3), "132");
+    assert_eq!(stack("push 1 push 2 push 3", "pick 0", 4), "3321");
+    assert_eq!(stack("push 1 push 2 push 3", "pick 2", 4), "1321");
+    assert_eq!(stack("push 1 push 2 push 3", "drop 2", 1), "1");
+    assert_eq!(stack("push 1 push 2 push 3", "drop 0", 3), "321");
+
+    // The last cell dropped is left in the ACC register
+    assert_eq!(stack("push 1 push 2 push 3", "drop 2 ld ACC", 2), "21");
+}
+
+#[test]
+fn stack_shuffling_depth() {
+    // `pick N` needs N + 1 cells on the stack
+    let source = "stack_size auto\nproc start\n push 1 push 2 pick 1 outn outn outn\nendproc";
+    let (program, _) = program(source, &Options::default()).unwrap();
+    assert_eq!(program.stack_size, 3);
+
+    let source = "proc start\n push 1 pick 1 outn outn\nendproc";
+    assert_eq!(
+        check(source, &Options::default()),
+        Ok(vec![Warning::StackUnderflow(1)])
+    );
+}
+
+#[test]
+fn invalid_stack_shuffling() {
+    assert_eq!(
+        error("proc start\n push 1 pick x\nendproc"),
+        Error::InvalidSize(String::from("x"))
+    );
+}
@@ -0,0 +1,166 @@
+//! Tests for the lints and their levels
+
+mod common;
+
+use common::check;
+use lasm::{
+    lint::{Level, Lint},
+    Error, Options, Warning,
+};
+
+/// Check a program with the default lint levels
+fn warnings(source: &str) -> Vec<Warning> {
+    check(source, &Options::default()).unwrap_or_else(|e| panic!("{}", e))
+}
+
+#[test]
+fn clean_program() {
+    let source = "proc start\n define a, 1\n push 1 st a ld a outn\nendproc";
+    assert_eq!(warnings(source), vec![]);
+}
+
+#[test]
+fn unbalanced_loop_and_branch() {
+    let source = "proc start\n push 1 loop push 1 push 1 endloop pop\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::UnbalancedLoop(String::from("start"))]
+    );
+
+    let source = "proc start\n push 1 if push 1 endif pop\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::UnbalancedBranch(String::from("start"))]
+    );
+}
+
+#[test]
+fn stack_underflow() {
+    assert_eq!(
+        warnings("proc start\n outn outn\nendproc"),
+        vec![Warning::StackUnderflow(2)]
+    );
+}
+
+#[test]
+fn unreachable_procedure() {
+    let source = "proc unused\n push 1 outn\nendproc\nproc start\n push 1 outn\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::UnreachableProcedure(String::from("unused"))]
+    );
+}
+
+#[test]
+fn register_lints() {
+    let source = "proc start\n define a, 1\n push 1 st a\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::UnusedRegister(String::from("a"))]
+    );
+
+    let source = "proc start\n define a, 1\n ld a outn\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::UninitializedRegister(String::from("a"))]
+    );
+
+    let source = "proc start\n define p, 1\n push 4 alloc p ld p outn\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::UnfreedAlloc(String::from("p"))]
+    );
+}
+
+#[test]
+fn push_pop_and_constant_loop() {
+    assert_eq!(
+        warnings("proc start\n push 1 pop\nendproc"),
+        vec![Warning::PushPop(String::from("start"))]
+    );
+
+    let source = "proc start\n push 1 loop push 1 endloop\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::ConstantLoop(String::from("start"))]
+    );
+    // A loop that can be left is not constant
+    let source = "proc start\n push 1 loop push 1 if break endif push 1 endloop\nendproc";
+    assert_eq!(warnings(source), vec![]);
+}
+
+#[test]
+fn constant_while_loop() {
+    let source = "proc start\n while push 1 do push 2 outn endwhile\nendproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::ConstantLoop(String::from("start"))]
+    );
+
+    // The condition reads a register that the body changes
+    let source = "proc start
+            define i, 1
+            push 0 st i
+            while push 3 ld i lt do ld i push 1 add st i endwhile
+        endproc";
+    assert_eq!(warnings(source), vec![]);
+
+    // The condition reads the stack, or the loop is left with break
+    let source = "proc start\n push 3 while dup do push 1 swap sub endwhile pop\nendproc";
+    assert_eq!(warnings(source), vec![]);
+    let source = "proc start
+            while outer push 1 do
+                push 1 loop break outer endloop
+            endwhile
+        endproc";
+    assert_eq!(warnings(source), vec![]);
+    let source = "proc start
+            while push 1 do
+                push 1 loop break endloop
+            endwhile
+        endproc";
+    assert_eq!(
+        warnings(source),
+        vec![Warning::ConstantLoop(String::from("start"))]
+    );
+}
+
+#[test]
+fn lint_levels() {
+    let source = "proc start\n push 1 pop\nendproc\nproc unused\nendproc";
+    let with = |lint, level| {
+        let mut options = Options::default();
+        options.lints.set(lint, level);
+        check(source, &options)
+    };
+
+    assert_eq!(
+        with(Lint::PushPop, Level::Allow),
+        Ok(vec![Warning::UnreachableProcedure(String::from("unused"))])
+    );
+    assert_eq!(
+        with(Lint::PushPop, Level::Deny),
+        Err(Error::DeniedWarnings(vec![Warning::PushPop(String::from(
+            "start"
+        ))]))
+    );
+
+    let mut options = Options::default();
+    options.lints.deny_warnings();
+    options.lints.set(Lint::UnreachableProcedure, Level::Allow);
+    assert_eq!(
+        check(source, &options),
+        Err(Error::DeniedWarnings(vec![Warning::PushPop(String::from(
+            "start"
+        ))]))
+    );
+}
+
+#[test]
+fn lint_names() {
+    for lint in Lint::ALL.iter() {
+        assert_eq!(Lint::named(lint.get_name()), Some(*lint));
+    }
+    assert_eq!(Lint::named("push-pop"), Some(Lint::PushPop));
+    assert_eq!(Lint::named("nothing"), None);
+}
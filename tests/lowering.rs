@@ -0,0 +1,144 @@
+//! Tests for lowering procedures into the instructions of a program
+
+mod common;
+
+use common::{assemble_c, error, outlined, output, run, run_with};
+use lasm::{Error, Options};
+
+#[test]
+fn calls_are_inlined() {
+    let source = "proc two\n push 2 outn\nendproc\nproc start\n call two call two\nendproc";
+    assert_eq!(output(source), "22");
+}
+
+#[test]
+fn procedures_can_be_defined_in_any_order() {
+    let source =
+        "proc start\n call a\nendproc\nproc a\n call b\nendproc\nproc b\n push 1 outn\nendproc";
+    assert_eq!(output(source), "1");
+}
+
+#[test]
+fn undefined_procedure() {
+    assert_eq!(
+        error("proc start\n call nothere\nendproc"),
+        Error::ProcedureNotDefined(String::from("nothere"))
+    );
+}
+
+#[test]
+fn procedures_called_many_times_are_lowered_once() {
+    // Inlining every call would give 2^40 copies of the innermost procedure
+    let mut source = String::from("proc p0\n push 1 pop\nendproc\n");
+    for i in 1..=40 {
+        source += &format!("proc p{}\n call p{} call p{}\nendproc\n", i, i - 1, i - 1);
+    }
+    source += "proc start\n call p40\nendproc";
+    assert!(assemble_c(&source, &Options::default()).is_ok());
+}
+
+#[test]
+fn code_size_limit() {
+    let source = "proc start\n push 1 push 2 push 3 pop pop pop\nendproc";
+    let options = Options {
+        code_size_limit: 5,
+        ..Options::default()
+    };
+    assert_eq!(
+        run_with(source, "", &options).map(|_| ()),
+        Err(Error::CodeSizeLimitExceeded(5))
+    );
+
+    let options = Options {
+        code_size_limit: 6,
+        ..Options::default()
+    };
+    assert!(run_with(source, "", &options).is_ok());
+}
+
+#[test]
+fn code_size_limit_applies_to_inlined_code() {
+    let source = "proc three\n push 1 push 2 push 3 pop pop pop\nendproc
+        inline proc start\n call three call three\nendproc";
+    let options = Options {
+        code_size_limit: 10,
+        ..Options::default()
+    };
+    assert_eq!(
+        run_with(source, "", &options).map(|_| ()),
+        Err(Error::CodeSizeLimitExceeded(10))
+    );
+}
+
+#[test]
+fn annotations_decide_outlining() {
+    let source = "noinline proc a\n push 1 outn\nendproc
+        inline proc b\n push 2 outn\nendproc
+        proc start\n call a call b\nendproc";
+    assert_eq!(outlined(source, &Options::default()), Ok(String::from("a")));
+    assert_eq!(output(source), "12");
+
+    // `inline` keeps a procedure inlined however many times it is called
+    let source = "inline proc a\n push 1 push 2 push 3 pop pop pop\nendproc
+        proc start\n call a call a call a call a\nendproc";
+    let options = Options {
+        outline_threshold: 0,
+        ..Options::default()
+    };
+    assert_eq!(outlined(source, &options), Ok(String::new()));
+}
+
+#[test]
+fn outline_threshold() {
+    let source = "proc a\n push 1 push 2 add outn\nendproc
+        proc start\n call a call a\nendproc";
+    let options = |outline_threshold| Options {
+        outline_threshold,
+        ..Options::default()
+    };
+    // The body of `a` is 4 instructions, and it is called twice
+    assert_eq!(outlined(source, &options(8)), Ok(String::new()));
+    assert_eq!(outlined(source, &options(7)), Ok(String::from("a")));
+
+    // A procedure called once is never outlined automatically
+    let source = "proc a\n push 1 push 2 add outn\nendproc
+        proc start\n call a\nendproc";
+    assert_eq!(outlined(source, &options(0)), Ok(String::new()));
+}
+
+#[test]
+fn calls_from_unreachable_procedures_are_not_counted() {
+    let source = "proc a\n push 1 push 2 add outn\nendproc
+        proc unused\n call a call a call a\nendproc
+        proc start\n call a\nendproc";
+    let options = Options {
+        outline_threshold: 0,
+        ..Options::default()
+    };
+    assert_eq!(outlined(source, &options), Ok(String::new()));
+}
+
+#[test]
+fn recursive_procedures_are_outlined() {
+    // Count down from the top of the stack, printing each number
+    let source = "proc count\n dup outn dup if push 1 swap sub call count else pop endif\nendproc
+        proc start\n push 3 call count\nendproc";
+    assert_eq!(
+        outlined(source, &Options::default()),
+        Ok(String::from("count"))
+    );
+    assert_eq!(run(source, ""), (String::from("3210"), 0));
+
+    let source = "inline proc a\n call b\nendproc
+        proc b\n call a\nendproc
+        proc start\n call a\nendproc";
+    assert_eq!(error(source), Error::RecursiveProcedure(String::from("a")));
+}
+
+#[test]
+fn recursive_calls_share_registers() {
+    // The recursive call overwrites `n`, so the outer call prints the innermost value
+    let source = "proc count (n)\n ld n if ld n push 1 swap sub call count endif ld n outn\nendproc
+        proc start\n push 2 call count\nendproc";
+    assert_eq!(output(source), "000");
+}
@@ -0,0 +1,92 @@
+//! Tests for declaring and expanding macros
+
+mod common;
+
+use common::{error, output, run};
+use lasm::Error;
+
+const INCR: &str = "macro incr r\n ld $r push 1 add st $r\nendmacro";
+
+#[test]
+fn arguments_are_substituted() {
+    let source = format!(
+        "{}\nproc start
+            define counter, 1
+            push 0 st counter
+            incr counter incr counter
+            ld counter outn
+        endproc",
+        INCR
+    );
+    assert_eq!(output(&source), "2");
+
+    // Arguments can be literals and the names of procedures
+    let source = "macro twice f x\n push $x call $f push $x call $f\nendmacro
+        proc show\n outn\nendproc
+        proc start\n twice show 7\nendproc";
+    assert_eq!(output(source), "77");
+}
+
+#[test]
+fn macros_can_use_macros() {
+    let source = format!(
+        "{}\nmacro incr2 r\n incr $r incr $r\nendmacro
+        proc start
+            define n, 1
+            push 5 st n incr2 n ld n outn
+        endproc",
+        INCR
+    );
+    assert_eq!(output(&source), "7");
+}
+
+#[test]
+fn dollars_that_are_not_parameters_are_kept() {
+    let source = "include std:io
+        macro label x\n push \"$ cost\" call print push $x outn\nendmacro
+        proc start\n label 3\nendproc";
+    assert_eq!(run(source, ""), (String::from("$ cost3"), 0));
+
+    // Only whole parameter names are substituted
+    let source = "include std:io
+        macro label x\n push \"$xy $x\" call print\nendmacro
+        proc start\n label 5\nendproc";
+    assert_eq!(run(source, ""), (String::from("$xy 5"), 0));
+}
+
+#[test]
+fn errors_in_the_body_are_reported() {
+    let source = format!("{}\nproc start\n incr nothere\nendproc", INCR);
+    assert_eq!(
+        error(&source),
+        Error::InvalidLoadArg(String::from("nothere"))
+    );
+
+    // The error is the one in the innermost macro
+    let source = format!(
+        "{}\nmacro incr2 r\n incr $r\nendmacro\nproc start\n incr2 nothere\nendproc",
+        INCR
+    );
+    assert_eq!(
+        error(&source),
+        Error::InvalidLoadArg(String::from("nothere"))
+    );
+}
+
+#[test]
+fn missing_arguments() {
+    let source = format!("{}\nproc start\n incr\nendproc", INCR);
+    assert!(matches!(error(&source), Error::InvalidMacroExpansion(_)));
+
+    let source = format!(
+        "{}\nproc start\n define n, 1\n incr\n incr n\nendproc",
+        INCR
+    );
+    assert!(matches!(error(&source), Error::InvalidMacroExpansion(_)));
+}
+
+#[test]
+fn recursive_macros() {
+    let source = "macro forever\n forever\nendmacro\nproc start\n forever\nendproc";
+    assert!(matches!(error(source), Error::RecursiveMacro(_)));
+}
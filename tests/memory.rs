@@ -0,0 +1,103 @@
+//! Tests for the block memory instructions
+
+mod common;
+
+use common::{assemble_c, output, program};
+use lasm::Options;
+
+#[test]
+fn deref_load_n() {
+    let source = "proc start
+        define v, 3
+        push 1 push 2 push 3 st v
+        refer v deref_ld_n 3 outn outn outn
+        refer v[1] deref_ld_n 2 outn outn
+    endproc";
+    assert_eq!(output(source), "32132");
+}
+
+#[test]
+fn deref_store_n() {
+    // The top cell is stored last, like `st`
+    let source = "proc start
+        define v, 3
+        push 4 push 5 push 6 refer v deref_st_n 3
+        ld v[0] outn ld v[2] outn
+        push 7 refer v[1] deref_st_n 1
+        ld v outn outn outn
+    endproc";
+    assert_eq!(output(source), "46674");
+}
+
+#[test]
+fn memcpy() {
+    let source = "proc start
+        define a, 3
+        define b, 3
+        push 1 push 2 push 3 st a
+        push 3 refer a refer b memcpy
+        ld b outn outn outn
+    endproc";
+    assert_eq!(output(source), "321");
+}
+
+#[test]
+fn overlapping_memcpy() {
+    // Copying forward and backward inside of one register
+    let source = |src, dst| {
+        format!(
+            "proc start
+                define a, 4
+                push 1 push 2 push 3 push 4 st a
+                push 3 refer a[{}] refer a[{}] memcpy
+                ld a outn outn outn outn
+            endproc",
+            src, dst
+        )
+    };
+    assert_eq!(output(&source(0, 1)), "3211");
+    assert_eq!(output(&source(1, 0)), "4432");
+}
+
+#[test]
+fn memset() {
+    let source = "proc start
+        define a, 4
+        push 0 push 0 push 0 push 0 st a
+        push 2 push 9 refer a[1] memset
+        ld a outn outn outn outn
+    endproc";
+    assert_eq!(output(source), "0990");
+}
+
+#[test]
+fn block_stack_usage() {
+    let source = "stack_size auto
+        proc start
+            define v, 4
+            push 0 push 0 push 0 push 0 st v
+            refer v deref_ld_n 4 refer v deref_st_n 4
+        endproc";
+    let (program, _) = program(source, &Options::default()).unwrap();
+    assert_eq!(program.stack_size, 5);
+}
+
+#[test]
+fn block_memory_in_c() {
+    let source = "proc start
+        define v, 2
+        push 1 push 2 st v
+        refer v deref_ld_n 2 refer v deref_st_n 2
+        push 2 refer v refer v memcpy
+        push 2 push 0 refer v memset
+    endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    for call in &[
+        "deref_load_n(tape, 2);",
+        "deref_store_n(tape, 2);",
+        "lasm_memcpy(tape);",
+        "lasm_memset(tape);",
+    ] {
+        assert!(c.contains(call), "{}", call);
+    }
+}
@@ -0,0 +1,62 @@
+//! Tests for named procedure parameters and results
+
+mod common;
+
+use common::{error, output, run};
+use lasm::Error;
+
+#[test]
+fn parameters_get_arguments_in_order() {
+    // The first parameter gets the deepest argument
+    let source = "proc sub_pair (a, b) -> (r)\n ld b ld a sub st r\nendproc
+        proc start\n push 3 push 1 call sub_pair outn\nendproc";
+    assert_eq!(run(source, ""), (String::from("2"), 0));
+}
+
+#[test]
+fn results_are_pushed_in_order() {
+    let source = "proc divmod (a, b) -> (q, r)
+            ld b ld a mod st r
+            ld b ld a div trunc st q
+        endproc
+        proc start\n push 17 push 5 call divmod outn outn\nendproc";
+    assert_eq!(run(source, ""), (String::from("23"), 0));
+}
+
+#[test]
+fn parameters_without_results() {
+    let source = "proc show (a, b)\n ld a outn ld b outn\nendproc
+        proc start\n push 1 push 2 call show\nendproc";
+    assert_eq!(run(source, ""), (String::from("12"), 0));
+
+    // A name can be both a parameter and a result
+    let source = "proc twice (n) -> (n)\n ld n push 2 mul st n\nendproc
+        proc start\n push 4 call twice outn\nendproc";
+    assert_eq!(output(source), "8");
+}
+
+#[test]
+fn parameters_are_scoped_to_their_procedure() {
+    let source = "proc show (a)\n ld a outn\nendproc
+        proc start\n push 1 call show ld a outn\nendproc";
+    assert_eq!(error(source), Error::InvalidLoadArg(String::from("a")));
+}
+
+#[test]
+fn parameters_shadow_registers() {
+    // The register `a` is visible again after the procedure that shadows it
+    let source = "proc start
+            define a, 1
+            push 5 st a
+            push 1 call show
+            ld a outn
+        endproc
+        proc show (a)\n ld a outn\nendproc";
+    assert_eq!(output(source), "15");
+}
+
+#[test]
+fn invalid_parameters() {
+    let source = "proc f (a) -> \n ld a outn\nendproc\nproc start\n push 1 call f\nendproc";
+    assert!(matches!(error(source), Error::InvalidParameters(_)));
+}
@@ -0,0 +1,80 @@
+//! Tests for calling procedures through their identifiers
+
+mod common;
+
+use common::{assemble_c, error, output};
+use lasm::{target::C, Error, Instruct, Options, Program, Target};
+use std::collections::BTreeMap;
+
+const PROCS: &str = "proc double (1 -> 1)\n push 2 mul\nendproc
+    proc square (1 -> 1)\n dup mul\nendproc
+    proc apply (2 -> 1)\n call_ind\nendproc";
+
+#[test]
+fn procedures_are_called_through_identifiers() {
+    let source = format!(
+        "{}\nproc start
+            push 5 refer_proc double call apply outn
+            push 3 refer_proc square call apply outn
+        endproc",
+        PROCS
+    );
+    assert_eq!(output(&source), "109");
+
+    // An identifier can be stored in a register and called later
+    let source = "proc square (1 -> 1)\n dup mul\nendproc
+        proc start
+            define f, 1
+            refer_proc square st f
+            push 4 ld f call_ind outn
+        endproc";
+    assert_eq!(output(source), "16");
+}
+
+#[test]
+fn identifiers_are_positions_in_the_dispatch_table() {
+    let source = "proc b\nendproc\nproc a\nendproc
+        proc start\n refer_proc a outn refer_proc b outn\nendproc";
+    assert_eq!(output(source), "12");
+
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    assert!(c.contains("lasm_dispatch[])(double tape[], bool alloc_tape[]) = {\n    NULL,\n    lasm_proc_a,\n    lasm_proc_b,\n};"));
+    assert!(c.contains("push_cell(tape, 1);"));
+    assert!(c.contains("push_cell(tape, 2);"));
+}
+
+#[test]
+fn referred_procedures_must_be_outlined() {
+    let source = "inline proc double (1 -> 1)\n push 2 mul\nendproc
+        proc start\n push 5 refer_proc double call_ind outn\nendproc";
+    assert_eq!(
+        error(source),
+        Error::ReferredInlineProcedure(String::from("double"))
+    );
+
+    let source = "proc start\n refer_proc nothere call_ind\nendproc";
+    assert_eq!(
+        error(source),
+        Error::ProcedureNotDefined(String::from("nothere"))
+    );
+}
+
+#[test]
+fn referring_to_a_procedure_that_is_not_outlined() {
+    // A program that was not assembled from assembly code can refer to any name
+    let program = Program {
+        initial_stack_ptr: 2,
+        stack_size: 8,
+        code: vec![
+            Instruct::ReferProc(String::from("missing")),
+            Instruct::CallIndirect,
+        ],
+        procs: BTreeMap::new(),
+        data: BTreeMap::new(),
+        bounds_checks: false,
+    };
+    assert_eq!(
+        C.assemble(program),
+        Err(Error::ProcedureNotDefined(String::from("missing")))
+    );
+}
@@ -0,0 +1,55 @@
+//! Tests for raw code written in the language of a target
+
+mod common;
+
+use common::{assemble_c, error, output};
+use lasm::{Error, Options};
+
+#[test]
+fn raw_code_is_kept_exactly() {
+    let source = "proc start
+            // a comment
+            raw c \"\"\"printf(\"// not a comment /* */\");\"\"\" /* another comment */
+        endproc";
+    let (c, _) = assemble_c(source, &Options::default()).unwrap();
+    assert!(c.contains("printf(\"// not a comment /* */\");"));
+    assert!(!c.contains("a comment\n"));
+    assert!(!c.contains("another comment"));
+}
+
+#[test]
+fn raw_in_comments_and_strings_is_not_code() {
+    let source = "// raw c \"\"\" x \"\"\" and another \"\"\"
+        proc start\n push 1 outn\nendproc";
+    assert_eq!(output(source), "1");
+
+    let source = "/* raw c \"\"\" */
+        proc start\n push 2 outn\nendproc";
+    assert_eq!(output(source), "2");
+
+    // The comment after a string that mentions raw code is still removed
+    let source = "include std:io
+        proc start\n push \"raw c \" call print // \"\"\"\nendproc";
+    assert_eq!(output(source), "raw c ");
+}
+
+#[test]
+fn raw_code_for_another_target() {
+    assert_eq!(
+        error("proc start\n raw c \"\"\"puts(\"hi\");\"\"\"\nendproc"),
+        Error::RawTargetMismatch {
+            raw: String::from("c"),
+            target: String::from("interpreter"),
+        }
+    );
+    assert_eq!(
+        assemble_c(
+            "proc start\n raw python \"\"\"print('hi')\"\"\"\nendproc",
+            &Options::default()
+        ),
+        Err(Error::RawTargetMismatch {
+            raw: String::from("python"),
+            target: String::from("c"),
+        })
+    );
+}
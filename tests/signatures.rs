@@ -0,0 +1,95 @@
+//! Tests for checking procedures against their declared signatures
+
+mod common;
+
+use common::{error, output, program};
+use lasm::{Error, Options, StackEffect, Warning};
+
+/// The error for a call of `extra`, which is declared as (1 -> 0) but pushes a cell
+fn extra(caller: Option<&str>) -> Error {
+    Error::SignatureMismatch {
+        name: String::from("extra"),
+        declared: StackEffect::new(1, 0),
+        actual: StackEffect::new(1, 1),
+        caller: caller.map(String::from),
+    }
+}
+
+#[test]
+fn matching_signatures() {
+    let source = "proc less (2 -> 1)\n lt\nendproc
+        proc print_num (1 -> 0)\n outn\nendproc
+        proc start\n push 2 push 1 call less call print_num\nendproc";
+    assert_eq!(output(source), "1");
+}
+
+#[test]
+fn mismatch_is_reported_at_the_call() {
+    let source = "proc extra (1 -> 0)\n dup outn\nendproc
+        proc start\n push 1 call extra pop\nendproc";
+    assert_eq!(error(source), extra(Some("start")));
+    assert_eq!(
+        error(source).to_string(),
+        "procedure 'extra' is declared as (1 -> 0), but its body has the stack effect (1 -> 1)
+  called from procedure 'start'"
+    );
+}
+
+#[test]
+fn mismatch_is_reported_at_every_call() {
+    let source = "proc extra (1 -> 0)\n dup outn\nendproc
+        proc other\n push 1 call extra pop\nendproc
+        proc start\n push 1 call extra push 2 call extra pop pop call other\nendproc";
+    assert_eq!(
+        error(source),
+        Error::Multiple(vec![
+            extra(Some("other")),
+            extra(Some("start")),
+            extra(Some("start")),
+        ])
+    );
+}
+
+#[test]
+fn mismatch_without_calls() {
+    let source = "proc extra (1 -> 0)\n dup outn\nendproc
+        proc start\n push 1 outn\nendproc";
+    assert_eq!(error(source), extra(None));
+}
+
+#[test]
+fn popping_more_than_declared() {
+    let source = "proc eat (1 -> 1)\n pop pop push 1\nendproc
+        proc start\n push 1 push 2 call eat pop\nendproc";
+    assert_eq!(
+        error(source),
+        Error::SignatureMismatch {
+            name: String::from("eat"),
+            declared: StackEffect::new(1, 1),
+            actual: StackEffect::new(2, 1),
+            caller: Some(String::from("start")),
+        }
+    );
+}
+
+#[test]
+fn callers_use_the_signature() {
+    // The loop pops a cell on every iteration, so the stack effect of the body
+    // cannot be computed, and callers use the signature instead
+    let body = "dup loop swap pop push 1 swap sub dup endloop pop";
+    let source = |signature| {
+        format!(
+            "proc drop_n {}\n {}\nendproc
+            proc start\n push 5 push 1 call drop_n\nendproc",
+            signature, body
+        )
+    };
+    let (_, warnings) = program(&source("(2 -> 0)"), &Options::default()).unwrap();
+    assert_eq!(warnings, vec![]);
+
+    let (_, warnings) = program(&source(""), &Options::default()).unwrap();
+    assert_eq!(
+        warnings,
+        vec![Warning::UnbalancedLoop(String::from("drop_n"))]
+    );
+}
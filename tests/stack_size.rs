@@ -0,0 +1,113 @@
+//! Tests for computing the stack size of a program with `stack_size auto`
+
+mod common;
+
+use common::{program, run};
+use lasm::{
+    analysis::Unknown,
+    asm::{DEFAULT_HEAP_SIZE, DEFAULT_STACK_SIZE},
+    Error, Options, Warning,
+};
+
+/// Get the stack size of a program assembled with the default options
+fn stack_size(source: &str) -> Result<usize, Error> {
+    program(source, &Options::default()).map(|(program, _)| program.stack_size)
+}
+
+#[test]
+fn default_stack_size() {
+    assert_eq!(
+        stack_size("proc start\n push 1 pop\nendproc"),
+        Ok(DEFAULT_STACK_SIZE)
+    );
+    assert_eq!(
+        stack_size("stack_size 16\nproc start\n push 1 pop\nendproc"),
+        Ok(16)
+    );
+}
+
+#[test]
+fn stack_size_is_inferred() {
+    assert_eq!(
+        stack_size("stack_size auto\nproc start\n push 1 push 2 push 3 add add pop\nendproc"),
+        Ok(3)
+    );
+
+    // The deepest point of a called procedure counts from the depth at the call
+    let source = "stack_size auto
+        proc two\n push 1 push 2 add outn\nendproc
+        proc start\n push 1 call two pop\nendproc";
+    assert_eq!(stack_size(source), Ok(3));
+}
+
+#[test]
+fn stack_size_of_loops_and_conditionals() {
+    let source = "stack_size auto
+        proc start\n push 3 dup loop push 1 swap sub dup endloop pop\nendproc";
+    assert_eq!(stack_size(source), Ok(2));
+
+    let source = "stack_size auto
+        proc start\n push 1 if push 1 push 2 add outn else push 3 outn endif\nendproc";
+    assert_eq!(stack_size(source), Ok(2));
+}
+
+#[test]
+fn unknown_stack_size() {
+    let source = "stack_size auto
+        proc count\n dup if push 1 swap sub call count endif\nendproc
+        proc start\n push 3 call count pop\nendproc";
+    assert_eq!(
+        stack_size(source),
+        Err(Error::UnknownStackSize(Unknown::Recursive(String::from(
+            "count"
+        ))))
+    );
+
+    let source = "stack_size auto\nproc start\n push 1 loop push 1 push 1 endloop pop\nendproc";
+    assert_eq!(
+        stack_size(source),
+        Err(Error::UnknownStackSize(Unknown::UnbalancedLoop(
+            String::from("start")
+        )))
+    );
+
+    // A declared stack size is used when the stack size cannot be computed
+    let source = "stack_size 64
+        proc count\n dup if push 1 swap sub call count endif\nendproc
+        proc start\n push 3 call count pop\nendproc";
+    assert_eq!(stack_size(source), Ok(64));
+}
+
+#[test]
+fn declared_stack_size_too_small() {
+    let source = "stack_size 2\nproc start\n push 1 push 2 push 3 add add outn\nendproc";
+    let (_, warnings) = program(source, &Options::default()).unwrap();
+    assert_eq!(
+        warnings,
+        vec![Warning::StackSizeTooSmall {
+            declared: 2,
+            required: 3
+        }]
+    );
+}
+
+#[test]
+fn heap_is_reserved_for_alloc() {
+    let source = "stack_size auto
+        proc start
+            define p, 1
+            push 4 alloc p
+            push 7 ld p deref_st ld p deref_ld outn
+            push 4 free p
+        endproc";
+    let stack_size = |heap_size| {
+        let options = Options {
+            heap_size,
+            ..Options::default()
+        };
+        program(source, &options).map(|(program, _)| program.stack_size)
+    };
+    assert_eq!(stack_size(DEFAULT_HEAP_SIZE), Ok(2 + DEFAULT_HEAP_SIZE));
+    assert_eq!(stack_size(8), Ok(10));
+    assert_eq!(run(source, ""), (String::from("7"), 0));
+}
@@ -0,0 +1,161 @@
+//! Tests for the standard library. Each procedure is assembled into a small program
+//! that is run by the interpreter, so that its stack effect and behaviour can be checked.
+
+mod common;
+
+use common::{check, run};
+use lasm::{target::C, Error, Options, Warning};
+use std::collections::BTreeMap;
+
+#[test]
+fn every_module_checks_without_warnings() {
+    for (name, _) in lasm::stdlib::MODULES {
+        let source = format!("include std:{}\nproc start\nendproc", name);
+        assert_eq!(
+            check(&source, &Options::default()),
+            Ok(vec![]),
+            "std:{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn unknown_module() {
+    assert_eq!(
+        check(
+            "include std:nothing\nproc start\nendproc",
+            &Options::default()
+        ),
+        Err(Error::IncludeNotFound(String::from("std:nothing")))
+    );
+}
+
+#[test]
+fn modules_are_only_included_once() {
+    let source = "include std:io include std:io\nproc start\n call nl\nendproc";
+    assert_eq!(run(source, ""), (String::from("\n"), 0));
+}
+
+#[test]
+fn io() {
+    let source = "include std:io
+        proc start
+            push \"hi\" call print call nl
+        endproc";
+    assert_eq!(run(source, ""), (String::from("hi\n"), 0));
+
+    let source = "include std:io
+        proc start
+            define buf, 8
+            refer buf call cscan
+            refer buf call cprint
+            refer buf call cscan
+            refer buf call cprint
+        endproc";
+    assert_eq!(run(source, "ab\ncd"), (String::from("abcd"), 0));
+    assert_eq!(run(source, ""), (String::new(), 0));
+}
+
+#[test]
+fn cmp() {
+    let cases = [
+        ("less", 1, 2, 1.0),
+        ("less", 2, 2, 0.0),
+        ("less_eq", 2, 2, 1.0),
+        ("less_eq", 3, 2, 0.0),
+        ("greater", 3, 2, 1.0),
+        ("greater", 2, 2, 0.0),
+        ("greater_eq", 2, 2, 1.0),
+        ("greater_eq", 1, 2, 0.0),
+        ("equal", 2, 2, 1.0),
+        ("equal", 1, 2, 0.0),
+        ("nequal", 1, 2, 1.0),
+        ("nequal", 2, 2, 0.0),
+        ("min", 1, 2, 1.0),
+        ("min", 2, 1, 1.0),
+        ("max", 1, 2, 2.0),
+        ("max", 2, 1, 2.0),
+        ("and", 1, 2, 1.0),
+        ("and", 0, 2, 0.0),
+        ("or", 0, 2, 1.0),
+        ("or", 0, 0, 0.0),
+    ];
+    for (name, a, b, expected) in &cases {
+        let source = format!(
+            "include std:cmp\nproc start\n push {} push {} call {} outn\nendproc",
+            a, b, name
+        );
+        assert_eq!(
+            run(&source, ""),
+            (expected.to_string(), 0),
+            "{} {} {}",
+            name,
+            a,
+            b
+        );
+    }
+
+    for (name, a, expected) in &[("not", 0, 1), ("not", 5, 0), ("bool", 5, 1), ("bool", 0, 0)] {
+        let source = format!(
+            "include std:cmp\nproc start\n push {} call {} outn\nendproc",
+            a, name
+        );
+        assert_eq!(
+            run(&source, ""),
+            (expected.to_string(), 0),
+            "{} {}",
+            name,
+            a
+        );
+    }
+}
+
+#[test]
+fn string() {
+    let source = "include std:string
+        proc start
+            data hello \"hello\"
+            data world \"world\"
+            define buf, 16
+            refer hello call strlen outn
+            refer buf refer hello call strcpy
+            refer buf refer world call strcat
+            refer buf call strlen outn
+            refer buf refer hello call streq outn
+            refer hello refer hello call streq outn
+            refer buf push 5 add refer world call streq outn
+        endproc";
+    assert_eq!(run(source, ""), (String::from("510011"), 0));
+}
+
+#[test]
+fn mem() {
+    let source = "include std:mem
+        proc start
+            data a 1, 2, 3
+            data b 1, 2, 4
+            refer a refer b push 2 call memeq outn
+            refer a refer b push 3 call memeq outn
+            refer b push 3 call memzero
+            ld b add add outn
+            push 7 call last outn outn
+            push 7 call last_ptr deref_ld outn pop
+        endproc";
+    assert_eq!(run(source, ""), (String::from("100777"), 0));
+}
+
+#[test]
+fn warnings_are_only_hidden_in_the_standard_library() {
+    let _lock = common::lock();
+    let mut files = BTreeMap::new();
+    files.insert(
+        String::from("util.lasm"),
+        String::from("proc waste\n push 1 pop\nendproc"),
+    );
+    let source = "include std:io include \"util.lasm\"\nproc start\n call waste call nl\nendproc";
+    assert_eq!(
+        lasm::check_file(C, "main.lasm", source, &files, &Options::default()),
+        Ok(vec![Warning::PushPop(String::from("waste"))])
+    );
+}
@@ -0,0 +1,69 @@
+//! Tests for struct layouts and field operands
+
+mod common;
+
+use common::{error, output};
+use lasm::Error;
+
+const SHAPES: &str = "struct Vec2 { x, y }\nstruct Body { pos: Vec2, vel: Vec2, name: 3, mass }";
+
+#[test]
+fn struct_sizes() {
+    let source = format!(
+        "{}\nproc start
+            define v: Vec2
+            define b: Body
+            push sizeof(v) outn push sizeof(b) outn push sizeof(b.pos) outn push sizeof(b.name) outn
+            push 1 push 2 st v ld v add outn
+            push 4 st b.mass ld b.mass outn
+        endproc",
+        SHAPES
+    );
+    assert_eq!(output(&source), "282334");
+}
+
+#[test]
+fn fields_are_parts_of_the_register() {
+    // Storing to the whole register sets its fields in order
+    let source = format!(
+        "{}\nproc start
+            define v: Vec2
+            push 2 push 1 st v
+            ld v.x outn ld v.y outn
+        endproc",
+        SHAPES
+    );
+    assert_eq!(output(&source), "21");
+}
+
+#[test]
+fn nested_fields() {
+    let source = format!(
+        "{}\nproc start
+            define b: Body
+            push 1 st b.pos.x push 2 st b.pos.y push 3 st b.vel.x push 4 st b.vel.y push 5 st b.mass
+            ld b.pos.y outn ld b.vel.x outn ld b.mass outn
+            refer b refer b.mass sub outn
+        endproc",
+        SHAPES
+    );
+    assert_eq!(output(&source), "2357");
+}
+
+#[test]
+fn struct_errors() {
+    assert!(matches!(
+        error("proc start\n define v: Nothing\nendproc"),
+        Error::StructNotDefined(_)
+    ));
+    assert!(matches!(
+        error("struct Vec2 { x, y\nproc start\nendproc"),
+        Error::InvalidStruct(_)
+    ));
+
+    let source = format!(
+        "{}\nproc start\n define v: Vec2\n ld v.z outn\nendproc",
+        SHAPES
+    );
+    assert!(matches!(error(&source), Error::InvalidLoadArg(_)));
+}